@@ -1,3 +1,5 @@
+use std::mem;
+
 use event::GenericEvent;
 use graphics::Context;
 use input::{keyboard, Button};
@@ -5,12 +7,14 @@ use opengl_graphics::GlGraphics;
 use opengl_graphics::glyph_cache::GlyphCache;
 
 use gui::{TextBox, TextButton};
+use login::CareerStats;
 use vec::Vec2f;
 
 use super::ChatMsg;
 
 pub enum ChatGuiAction {
     SendMsg(String),
+    CareerStats,        // Player typed /stats
 }
 
 pub struct ChatGui {
@@ -37,6 +41,13 @@ impl ChatGui {
     pub fn add_message(&mut self, msg: ChatMsg) {
         self.messages.push(msg);
     }
+    
+    pub fn show_career_stats(&mut self, stats: &CareerStats) {
+        self.add_message(ChatMsg {
+            author_name: "Career".to_string(),
+            content: format!("{}", stats),
+        });
+    }
 
     pub fn event<E: GenericEvent>(&mut self, e: &E, mouse_pos: Vec2f) -> Option<ChatGuiAction> {
         use event::*;
@@ -46,8 +57,7 @@ impl ChatGui {
         
         if self.send_button.get_clicked() {
             if self.msg_box.text.len() > 0 {
-                self.action = Some(ChatGuiAction::SendMsg(self.msg_box.text.clone()));
-                self.msg_box.text = "".to_string();
+                self.send();
             }
         }
         
@@ -65,13 +75,23 @@ impl ChatGui {
         match key {
             keyboard::Key::Return => {
                 if self.msg_box.text.len() > 0 && self.msg_box.has_focus {
-                    self.action = Some(ChatGuiAction::SendMsg(self.msg_box.text.clone()));
-                    self.msg_box.text = "".to_string();
+                    self.send();
                 }
             },
             _ => { },
         }
     }
+    
+    fn send(&mut self) {
+        let text = mem::replace(&mut self.msg_box.text, "".to_string());
+        
+        self.action =
+            if text.trim() == "/stats" {
+                Some(ChatGuiAction::CareerStats)
+            } else {
+                Some(ChatGuiAction::SendMsg(text))
+            };
+    }
 
     pub fn draw(&mut self, context: &Context, gl: &mut GlGraphics, glyph_cache: &mut GlyphCache) {
         use graphics::*;
@@ -1,34 +1,85 @@
+use std::collections::HashMap;
 use std::sync::mpsc::{Sender, Receiver};
+use time;
+
+use event_mux::EventMux;
 
 use super::ChatMsg;
 
+/// Mutes or unmutes a player. Their messages go nowhere until the mute ends.
+pub struct ChatMute {
+    pub username: String,
+    pub until: Option<i64>, // Unix time in seconds the mute ends, or None to unmute
+}
+
+// Everything the chat server waits on
+enum ChatEvent {
+    Msg(ChatMsg),
+    Mute(ChatMute),
+}
+
 pub struct ChatServer {
-    msg_receiver: Receiver<ChatMsg>,
+    events: EventMux<ChatEvent>,
     msg_senders: Vec<Sender<ChatMsg>>,
     msg_log: Vec<ChatMsg>,
+    
+    // When each muted player can chat again, by username
+    muted: HashMap<String, i64>,
 }
 
 impl ChatServer {
-    pub fn new(msg_receiver: Receiver<ChatMsg>, msg_senders: Vec<Sender<ChatMsg>>) -> ChatServer {
+    pub fn new(msg_receiver: Receiver<ChatMsg>, mute_receiver: Receiver<ChatMute>, msg_senders: Vec<Sender<ChatMsg>>) -> ChatServer {
+        let events = EventMux::new();
+        events.forward(msg_receiver, ChatEvent::Msg);
+        events.forward(mute_receiver, ChatEvent::Mute);
+        
         ChatServer {
-            msg_receiver: msg_receiver,
+            events: events,
             msg_senders: msg_senders,
             msg_log: vec!(),
+            muted: HashMap::new(),
         }
     }
     
     pub fn run(&mut self) {
-        while let Ok(msg) = self.msg_receiver.recv() {
-            for msg_sender in &self.msg_senders {
-                msg_sender.send(msg.clone());
+        loop {
+            match self.events.recv() {
+                ChatEvent::Msg(msg) => {
+                    if self.is_muted(&msg.author_name) {
+                        continue;
+                    }
+                    
+                    for msg_sender in &self.msg_senders {
+                        msg_sender.send(msg.clone());
+                    }
+                    self.add_msg(msg);
+                },
+                ChatEvent::Mute(ChatMute { username, until: Some(until) }) => {
+                    self.muted.insert(username, until);
+                },
+                ChatEvent::Mute(ChatMute { username, until: None }) => {
+                    self.muted.remove(&username);
+                },
             }
-            self.add_msg(msg);
         }
-        
-        println!("Chat log died");
     }
     
     pub fn add_msg(&mut self, msg: ChatMsg) {
         self.msg_log.push(msg);
     }
+    
+    fn is_muted(&mut self, username: &str) -> bool {
+        let until =
+            match self.muted.get(username) {
+                Some(until) => *until,
+                None => { return false; },
+            };
+        
+        if time::get_time().sec < until {
+            true
+        } else {
+            self.muted.remove(username);
+            false
+        }
+    }
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 #[cfg(feature = "client")]
 pub use self::chat_gui::{ChatGui, ChatGuiAction};
 pub use self::chat_msg::ChatMsg;
-pub use self::chat_server::ChatServer;
+pub use self::chat_server::{ChatMute, ChatServer};
 
 #[cfg(feature = "client")]
 pub mod chat_gui;
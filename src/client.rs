@@ -14,8 +14,12 @@
 #![feature(collections_drain)]
 
 extern crate bincode;
+extern crate crypto;
+extern crate flate2;
 extern crate float;
 extern crate num;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate time;
@@ -49,11 +53,11 @@ use battle_context::BattleContext;
 use battle_type::BattleType;
 use sector_client::ClientBattleState;
 use client_state::run_client_state_manager;
-use login::{LoginPacket, LoginError};
+use login::{LoginPacket, LoginError, MemoryAccountStore};
 use login_screen::{LoginScreen, LoginGuiAction};
 use main_menu::{MainMenu, MainMenuSelection};
 use module::ModelStore;
-use net::{Client, OutPacket};
+use net::{Client, NetResult, OutPacket};
 use star_map::StarMapServer;
 
 // Server stuff
@@ -67,6 +71,7 @@ mod sector_client;
 mod chat;
 mod client_action;
 mod client_state;
+mod event_mux;
 mod gui;
 mod login;
 mod login_screen;
@@ -133,18 +138,23 @@ fn main () {
     let star_map_slot_id = star_map_slot.get_id();
     let (star_map_account_sender, star_map_account_receiver) = channel();
     let (logout_sender, logout_receiver) = channel();
+    let (mod_sender, mod_receiver) = channel();
+    let (chat_mute_sender, chat_mute_receiver) = channel();
+    let (account_save_sender, account_save_receiver) = channel();
+    let (_, sector_close_receiver) = channel(); // Nothing closes sectors on a local server
     
     Builder::new().name("server_master".to_string()).spawn(move || {
         server.listen("localhost:30000");
     });
     
     Builder::new().name("login_server".to_string()).spawn(move || {
-        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender, logout_receiver);
+        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender, logout_receiver, Box::new(MemoryAccountStore),
+                                mod_receiver, chat_mute_sender, account_save_receiver);
     });
     
     Builder::new().name("star_map_server".to_string()).spawn(move || {
-        let mut star_map_server = StarMapServer::new(star_map_slot);
-        star_map_server.run(star_map_account_receiver, logout_sender);
+        let mut star_map_server = StarMapServer::new(star_map_slot, mod_sender, chat_mute_receiver, account_save_sender);
+        star_map_server.run(star_map_account_receiver, logout_sender, sector_close_receiver);
     });
     
     // Create main menu
@@ -155,31 +165,56 @@ fn main () {
                 let mut login_screen = LoginScreen::new();
             
                 loop {
-                    match login_screen.run(&window, gl, &mut glyph_cache, menu_bg) {
-                        LoginGuiAction::Login(username, password, ip_address) => {
-                            // Connect to server
-                            let mut client = Client::new((ip_address+":30000").as_str());
+                    let (login_packet, ip_address) =
+                        match login_screen.run(&window, gl, &mut glyph_cache, menu_bg) {
+                            LoginGuiAction::Login(username, password, ip_address, take_over) => {
+                                (LoginPacket::Login(username, password, take_over), ip_address)
+                            },
+                            LoginGuiAction::Register(username, password, ip_address) => {
+                                (LoginPacket::Register(username, password), ip_address)
+                            },
+                            LoginGuiAction::Back => {
+                                break;
+                            },
+                        };
+                    
+                    // Connect to server
+                    let mut client =
+                        match connect((ip_address+":30000").as_str()) {
+                            Ok(client) => client,
+                            Err(e) => {
+                                login_screen.net_error = Some(e);
+                                continue;
+                            },
+                        };
+                    login_screen.net_error = None;
 
-                            let mut packet = OutPacket::new();
-                            packet.write(&LoginPacket{username: username, password: password});
-                            client.send(&packet);
-                            
-                            let mut login_result_packet = client.receive();
-                            let login_result: Option<LoginError> = login_result_packet.read().unwrap();
-                            
-                            match login_result {
-                                Some(login_error) => {
-                                    login_screen.login_error = Some(login_error);
-                                },
-                                None => {
-                                    run_client_state_manager(&window, gl, &mut glyph_cache, asset_store, model_store, client);
-                                    break;
+                    let mut packet = OutPacket::new();
+                    packet.write(&login_packet);
+                    
+                    let login_result: Option<LoginError> =
+                        match client.send(&packet).and_then(|_| client.receive()) {
+                            Ok(mut login_result_packet) => login_result_packet.read().unwrap(),
+                            Err(e) => {
+                                login_screen.net_error = Some(e);
+                                continue;
+                            },
+                        };
+                    
+                    match login_result {
+                        Some(login_error) => {
+                            login_screen.login_error = Some(login_error);
+                        },
+                        None => {
+                            match run_client_state_manager(&window, gl, &mut glyph_cache, asset_store, model_store, client) {
+                                Ok(()) => { break; },
+                                Err(e) => {
+                                    // Lost the server, go back to the login screen and say why
+                                    println!("Disconnected from server: {}", e);
+                                    login_screen.net_error = Some(e);
                                 },
                             }
                         },
-                        LoginGuiAction::Back => {
-                            break;
-                        },
                     }
                 }
                 
@@ -191,4 +226,20 @@ fn main () {
     
     sdl2_mixer::Music::halt();
     sdl2_mixer::quit();
+}
+
+// Connects over TLS if the environment asks for it. See `TlsClientConfig::from_env`.
+#[cfg(feature = "tls")]
+fn connect(host: &str) -> NetResult<Client> {
+    use net::TlsClientConfig;
+    
+    match TlsClientConfig::from_env() {
+        Some(config) => Client::new_tls(host, &config),
+        None => Client::new(host),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn connect(host: &str) -> NetResult<Client> {
+    Client::new(host)
 }
\ No newline at end of file
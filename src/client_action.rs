@@ -3,4 +3,5 @@ pub enum ClientAction {
     JoinSector,
     JoinStation,
     Logout,
+    ServerShutdown,     // Logged out because the server's going down. It sends the reason before closing.
 }
\ No newline at end of file
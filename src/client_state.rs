@@ -12,7 +12,7 @@ use client_action::ClientAction;
 use module::ModelStore;
 use sector_client::ClientBattleState;
 use star_map::station::StationClient;
-use net::Client;
+use net::{Client, NetResult};
 use sector_data::SectorData;
 use ship::{Ship, ShipStored};
 
@@ -21,23 +21,23 @@ pub fn run_client_state_manager(window: &Rc<RefCell<GlutinWindow>>,
                                 glyph_cache: &mut GlyphCache,
                                 asset_store: &AssetStore,
                                 model_store: &ModelStore,
-                                mut client: Client) {
+                                mut client: Client) -> NetResult<()> {
     use client_action::ClientAction::*;
     
     let ref mut chat_gui = ChatGui::new();
 
     // Receive the star map
-    let mut packet = client.receive();
+    let mut packet = try!(client.receive());
     let sectors: Vec<SectorData> = packet.read().ok().expect("Failed to read star map");
     
     loop {
-        let mut client_action_packet = client.receive();
+        let mut client_action_packet = try!(client.receive());
         let client_action: ClientAction = client_action_packet.read().ok().expect("Failed to read next ClientAction");
     
         match client_action {
             JoinSector => {
                 // Receive the sector join packet
-                let mut packet = client.receive();
+                let mut packet = try!(client.receive());
                 let my_ship: Ship = packet.read().ok().expect("Failed to read my Ship");
                 let server_results_sent = packet.read().ok().expect("Failed to read server_results_sent from server");
                 let ships: Vec<Option<Ship>> = packet.read().ok().expect("Unable to receive ships froms server");
@@ -50,22 +50,30 @@ pub fn run_client_state_manager(window: &Rc<RefCell<GlutinWindow>>,
                 
                 let mut battle = ClientBattleState::new(&mut client, battle_context);
 
-                battle.run(window, gl, glyph_cache, asset_store, chat_gui, sectors.clone(), server_results_sent);
+                try!(battle.run(window, gl, glyph_cache, asset_store, chat_gui, sectors.clone(), server_results_sent));
                 
                 println!("I (client) left a sector");
             },
             JoinStation => {
                 // Receive the station join packet
-                let mut packet = client.receive();
+                let mut packet = try!(client.receive());
                 let my_ship: Option<ShipStored> = packet.read().ok().expect("Failed to read my Ship");
                 
                 let mut station_client = StationClient::new(&mut client, my_ship);
                 
-                station_client.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone());
+                try!(station_client.run(window, gl, glyph_cache, asset_store, model_store, chat_gui, sectors.clone()));
             },
             Logout => {
                 break;
             },
+            ServerShutdown => {
+                // Nothing else is coming but the shutdown notice, which ends up as the error
+                loop {
+                    try!(client.receive());
+                }
+            },
         }
     }
+    
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+/// Merges several channels and timers into a single channel, so a server loop can block on all of
+/// them at once instead of polling each with `try_recv`.
+pub struct EventMux<E: Send + 'static> {
+    sender: Sender<E>,
+    receiver: Receiver<E>,
+    timers: Arc<Timers<E>>,
+}
+
+impl<E: Send + 'static> EventMux<E> {
+    pub fn new() -> EventMux<E> {
+        let (sender, receiver) = channel();
+        let timers = Arc::new(Timers {
+            queue: Mutex::new(TimerQueue {
+                pending: BinaryHeap::new(),
+                next_seq: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        {
+            let timers = timers.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                run_timers(&timers, sender);
+            });
+        }
+
+        EventMux {
+            sender: sender,
+            receiver: receiver,
+            timers: timers,
+        }
+    }
+
+    /// Wraps every message that arrives on `receiver` into an event. The forwarding thread exits
+    /// once either side hangs up.
+    pub fn forward<T, F>(&self, receiver: Receiver<T>, wrap: F)
+        where T: Send + 'static, F: Fn(T) -> E + Send + 'static
+    {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                if sender.send(wrap(msg)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Like `forward`, but delivers `closed` once `receiver` hangs up, so the loop can tell the
+    /// sender is gone
+    pub fn forward_then<T, F>(&self, receiver: Receiver<T>, wrap: F, closed: E)
+        where T: Send + 'static, F: Fn(T) -> E + Send + 'static
+    {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                if sender.send(wrap(msg)).is_err() {
+                    return;
+                }
+            }
+            sender.send(closed);
+        });
+    }
+
+    /// Delivers `event` after `delay_ms` milliseconds. All of a mux's timers share one thread.
+    pub fn schedule(&self, delay_ms: u32, event: E) {
+        let mut queue = self.timers.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.pending.push(Timer {
+            due_ns: time::precise_time_ns() + delay_ms as u64 * 1000000,
+            seq: seq,
+            event: event,
+        });
+        self.timers.changed.notify_one();
+    }
+
+    /// Another way to inject events directly
+    pub fn sender(&self) -> Sender<E> {
+        self.sender.clone()
+    }
+
+    /// Blocks until the next event. Never fails, since the mux holds a sender of its own.
+    pub fn recv(&self) -> E {
+        self.receiver.recv().ok().expect("EventMux holds its own sender, so this can't hang up")
+    }
+}
+
+impl<E: Send + 'static> Drop for EventMux<E> {
+    fn drop(&mut self) {
+        self.timers.queue.lock().unwrap().stopped = true;
+        self.timers.changed.notify_one();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Timers
+
+// Events waiting to be delivered by `schedule`
+struct Timers<E> {
+    queue: Mutex<TimerQueue<E>>,
+    changed: Condvar,   // A timer was added or the mux was dropped
+}
+
+struct TimerQueue<E> {
+    pending: BinaryHeap<Timer<E>>,
+    next_seq: u64,
+    stopped: bool,  // Mux is gone, so the timer thread should exit
+}
+
+struct Timer<E> {
+    due_ns: u64,    // precise_time_ns the event is delivered at
+    seq: u64,       // Keeps timers due at the same time in the order they were scheduled
+    event: E,
+}
+
+// BinaryHeap keeps the largest on top, so timers compare backwards to keep the earliest there
+impl<E> Ord for Timer<E> {
+    fn cmp(&self, other: &Timer<E>) -> Ordering {
+        (other.due_ns, other.seq).cmp(&(self.due_ns, self.seq))
+    }
+}
+
+impl<E> PartialOrd for Timer<E> {
+    fn partial_cmp(&self, other: &Timer<E>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> PartialEq for Timer<E> {
+    fn eq(&self, other: &Timer<E>) -> bool {
+        self.due_ns == other.due_ns && self.seq == other.seq
+    }
+}
+
+impl<E> Eq for Timer<E> { }
+
+// Sleeps until the earliest timer is due and delivers it, until the mux is dropped
+fn run_timers<E: Send + 'static>(timers: &Timers<E>, sender: Sender<E>) {
+    let mut queue = timers.queue.lock().unwrap();
+    while !queue.stopped {
+        let now = time::precise_time_ns();
+        let due_ns = queue.pending.peek().map(|timer| timer.due_ns);
+
+        queue =
+            match due_ns {
+                Some(due_ns) if due_ns <= now => {
+                    let timer = queue.pending.pop().unwrap();
+                    sender.send(timer.event);
+                    queue
+                },
+                Some(due_ns) => {
+                    let wait_ns = due_ns - now;
+                    let wait = Duration::new(wait_ns / 1000000000, (wait_ns % 1000000000) as u32);
+                    timers.changed.wait_timeout(queue, wait).unwrap().0
+                },
+                None => timers.changed.wait(queue).unwrap(),
+            };
+    }
+}
@@ -262,4 +262,22 @@ impl TextBox {
             }
         });
     }
-}
\ No newline at end of file
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Draws the player's balance, if the server has said what it is yet
+pub fn draw_credits(credits: Option<u64>, context: &Context, gl: &mut GlGraphics, glyph_cache: &mut GlyphCache) {
+    use graphics::*;
+    use graphics::text::Text;
+    
+    if let Some(credits) = credits {
+        let context = context.trans(550.0, 170.0);
+        Text::colored([1.0; 4], 20).draw(
+            &format!("{} credits", credits),
+            glyph_cache,
+            &context.draw_state, context.transform,
+            gl,
+        );
+    }
+}
@@ -1,85 +1,439 @@
-use std::collections::HashMap;
+use std::ascii::AsciiExt;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::string::String;
 
+use bincode::{encode_into, decode_from, SizeLimit};
+use time;
+
 use module::ModelIndex;
 use net::ClientId;
-use ship::ShipStored;
+use ship::{new_ship_id, Ship, ShipStored};
 use sector_data::SectorId;
 
+use super::{AccountChange, AccountStore, Ban, CareerStats, Password, Role, Wallet};
+
 pub type AccountBox = Box<Account>;
 
-#[derive(Copy, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+// Limits on new usernames and passwords. Usernames can only have ASCII letters, digits, '_' and '-'.
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 24;
+const MIN_PASSWORD_LENGTH: usize = 6;
+
+// Wrong passwords in a row before an account is locked
+const MAX_FAILED_LOGINS: u32 = 5;
+
+// How long a locked account stays locked
+const LOCKOUT_MS: i64 = 300000;
+
+// Levels of the ships new accounts find in their hangar, besides the one they first fly
+const STARTER_HANGAR_LEVELS: [u8; 2] = [3, 4];
+
+#[derive(Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum LoginError {
     NoSuchAccount,
     WrongPassword,
     AlreadyLoggedIn,
+    Unavailable,        // The account couldn't be loaded from storage
+    LockedOut,          // Too many wrong passwords, try again later
+    UsernameTaken,      // Someone already registered that username
+    InvalidUsername,    // Username has characters other than ASCII letters, digits, '_' and '-'
+    UsernameTooShort,
+    UsernameTooLong,
+    PasswordTooShort,
+    Banned { until: Option<i64>, reason: String },  // Unix time in seconds the ban ends, or None if it never does
+}
+
+/// Checks a new account's username and password against the registration rules
+pub fn validate_registration(username: &str, password: &str) -> Result<(), LoginError> {
+    try!(validate_username(username));
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(LoginError::PasswordTooShort);
+    }
+    Ok(())
+}
+
+/// Checks a username against the registration rules
+pub fn validate_username(username: &str) -> Result<(), LoginError> {
+    if username.len() < MIN_USERNAME_LENGTH {
+        return Err(LoginError::UsernameTooShort);
+    }
+    if username.len() > MAX_USERNAME_LENGTH {
+        return Err(LoginError::UsernameTooLong);
+    }
+    if !username.chars().all(|c| c.is_ascii() && (c.is_alphanumeric() || c == '_' || c == '-')) {
+        return Err(LoginError::InvalidUsername);
+    }
+    Ok(())
 }
 
+#[derive(RustcEncodable, RustcDecodable)]
 pub struct Account {
     pub username: String,
-    pub password: String,
-    pub ship: Option<ShipStored>,
+    pub password: Password,
+    pub ship: Option<ShipStored>,  // Active ship
+    pub hangar: Vec<ShipStored>,    // The account's other ships
     pub client_id: Option<ClientId>,
     pub sector: SectorId,
     
     pub module_inventory: HashMap<ModelIndex, u16>,
+    
+    pub role: Role,
+    pub ban: Option<Ban>,
+    pub muted_until: Option<i64>,   // Unix time in seconds the account can chat again
+    
+    pub career: CareerStats,
+    pub wallet: Wallet,
+}
+
+impl Account {
+    /// Copies the account to save while it's still in use. `ship` stands in for the active ship
+    /// when that's out in a battle. Ships can't be cloned, so the copy goes through the same
+    /// encoding as a save.
+    pub fn snapshot(&self, ship: Option<&Ship>) -> AccountBox {
+        let mut data = vec!();
+        encode_into(self, &mut data, SizeLimit::Infinite).ok().expect("Failed to encode account snapshot");
+        let mut snapshot: AccountBox = Box::new(decode_from(&mut &data[..], SizeLimit::Infinite).ok().expect("Failed to decode account snapshot"));
+        
+        if let Some(ship) = ship {
+            let mut data = vec!();
+            encode_into(ship, &mut data, SizeLimit::Infinite).ok().expect("Failed to encode ship snapshot");
+            let ship: Ship = decode_from(&mut &data[..], SizeLimit::Infinite).ok().expect("Failed to decode ship snapshot");
+            snapshot.ship = Some(ShipStored::from_ship(ship));
+        }
+        
+        snapshot
+    }
 }
 
 pub struct AccountManager {
     accounts: HashMap<String, Option<AccountBox>>,
+    store: Box<AccountStore>,
+    
+    // Accounts that have changed since they were last saved
+    unsaved: HashSet<String>,
+    
+    // Wrong password streaks, by username. Not saved, a restart forgives everyone.
+    failed_logins: HashMap<String, FailedLogins>,
+    
+    // Every logged in account, by username
+    online: HashMap<String, OnlineAccount>,
+    
+    // Changes to logged in accounts, made when they log out
+    pending_changes: HashMap<String, Vec<AccountChange>>,
+}
+
+struct FailedLogins {
+    count: u32,
+    locked_until: Option<time::Timespec>,
+}
+
+// What's still known about an account while it's out in the universe
+struct OnlineAccount {
+    client_id: ClientId,
+    role: Role,
+    password: Password,     // So a new connection can prove it owns the account
 }
 
 impl AccountManager {
-    pub fn new() -> AccountManager {
+    pub fn new(store: Box<AccountStore>) -> AccountManager {
         AccountManager {
             accounts: HashMap::new(),
+            store: store,
+            unsaved: HashSet::new(),
+            failed_logins: HashMap::new(),
+            online: HashMap::new(),
+            pending_changes: HashMap::new(),
+        }
+    }
+    
+    /// Registers a new account if the username is free and the credentials pass
+    /// `validate_registration`
+    pub fn register_account(&mut self, username: String, password: String) -> Result<(), LoginError> {
+        try!(validate_registration(&username, &password));
+        
+        let taken =
+            match self.load(&username) {
+                Ok(exists) => exists,
+                Err(e) => {
+                    println!("WARNING: Failed to check for account {}: {}", username, e);
+                    return Err(LoginError::Unavailable);
+                },
+            };
+        if taken {
+            return Err(LoginError::UsernameTaken);
         }
+        
+        self.create_account(username, password);
+        Ok(())
     }
     
-    /// Creates a new account with no ship and no client ID
-    pub fn create_account(&mut self, username: String, password: String) {
+    // Creates a new account with a starter hangar and no client ID. Its active ship is made when
+    // it first logs in.
+    fn create_account(&mut self, username: String, password: String) {
+        let hangar = STARTER_HANGAR_LEVELS.iter()
+            .map(|level| ShipStored::from_ship(Ship::generate(new_ship_id(), username.clone(), *level)))
+            .collect();
+        
         self.accounts.insert(username.clone(), Some(Box::new(Account {
-            username: username,
-            password: password,
+            username: username.clone(),
+            password: Password::new(&password),
             ship: None,
+            hangar: hangar,
             client_id: None,
             sector: SectorId(0),
             module_inventory: HashMap::new(),
+            role: Role::Player,
+            ban: None,
+            muted_until: None,
+            career: CareerStats::new(),
+            wallet: Wallet::new(),
         })));
+        self.unsaved.insert(username);
+        self.autosave();
     }
     
     /// Attempts to log an account in and returns the AccountBox on success.
     /// If the login fails, the corresponding error is returned.
     pub fn login_account(&mut self, username: String, password: String, client_id: ClientId) -> Result<AccountBox, LoginError> {
-        use std::collections::hash_map::Entry;
-    
-        if let Entry::Occupied(mut account_entry) = self.accounts.entry(username) {
-            // Make sure the account is available to log into
-            if account_entry.get().is_none() {
-                // This account is set to None, which means it's already logged in.
-                Err(LoginError::AlreadyLoggedIn)
-            } else {
-                // Verify password
-                if account_entry.get().as_ref().unwrap().password == password {
-                    // All good, log the account in
-                    
-                    // Set the client ID
-                    account_entry.get_mut().as_mut().unwrap().client_id = Some(client_id);
+        if self.locked_out(&username) {
+            return Err(LoginError::LockedOut);
+        }
+        
+        if let Err(e) = self.load(&username) {
+            println!("WARNING: Failed to load account {}: {}", username, e);
+            return Err(LoginError::Unavailable);
+        }
+        
+        let verified =
+            match self.accounts.get(&username) {
+                Some(&Some(ref account)) => account.password.verify(&password),
+                Some(&None) => return Err(LoginError::AlreadyLoggedIn), // None means it's already logged in
+                None => return Err(LoginError::NoSuchAccount),
+            };
+        
+        if !verified {
+            return Err(self.login_failed(username));
+        }
+        self.failed_logins.remove(&username);
+        
+        // Passwords from before hashing are hashed now that we know them
+        {
+            let account = self.accounts.get_mut(&username).unwrap().as_mut().unwrap();
+            if account.password.is_plain() {
+                account.password = Password::new(&password);
+                if let Err(e) = self.store.save(account) {
+                    println!("WARNING: Failed to save hashed password for account {}: {}", username, e);
+                }
+            }
+        }
+        
+        self.login_verified(username, client_id)
+    }
+    
+    /// Logs in an account whose owner has already proven who they are, like a connection that
+    /// passed `take_over`. Banned accounts are still turned away.
+    pub fn login_verified(&mut self, username: String, client_id: ClientId) -> Result<AccountBox, LoginError> {
+        // Banned accounts only find out once they've proven who they are
+        match self.accounts.get_mut(&username) {
+            Some(&mut Some(ref mut account)) => {
+                if let Some(ban) = account.ban.clone() {
+                    if ban.in_force() {
+                        return Err(LoginError::Banned { until: ban.until, reason: ban.reason });
+                    }
                     
-                    // Remove the account and replace it with None to show the account is logged in.
-                    Ok(account_entry.insert(None).unwrap())
-                } else {
-                    Err(LoginError::WrongPassword)
+                    // Ban's over
+                    account.ban = None;
                 }
+            },
+            Some(&mut None) => return Err(LoginError::AlreadyLoggedIn),
+            None => return Err(LoginError::NoSuchAccount),
+        }
+        
+        // Remove the account and replace it with None to show the account is logged in.
+        let mut account = self.accounts.get_mut(&username).unwrap().take().unwrap();
+        
+        account.client_id = Some(client_id);
+        self.online.insert(username, OnlineAccount {
+            client_id: client_id,
+            role: account.role,
+            password: account.password.clone(),
+        });
+        Ok(account)
+    }
+    
+    /// Checks the password of a logged in account for a new connection that wants it. Returns the
+    /// client ID of the session to kick so the account comes back. Wrong passwords count towards a
+    /// lockout like they do for `login_account`.
+    pub fn take_over(&mut self, username: String, password: &str) -> Result<ClientId, LoginError> {
+        if self.locked_out(&username) {
+            return Err(LoginError::LockedOut);
+        }
+        
+        let (client_id, verified) =
+            match self.online.get(&username) {
+                Some(online) => (online.client_id, online.password.verify(password)),
+                None => return Err(LoginError::NoSuchAccount),
+            };
+        
+        if !verified {
+            return Err(self.login_failed(username));
+        }
+        self.failed_logins.remove(&username);
+        
+        Ok(client_id)
+    }
+    
+    // Loads an account from the store if it isn't loaded yet. Returns whether it exists.
+    fn load(&mut self, username: &str) -> io::Result<bool> {
+        if self.accounts.contains_key(username) {
+            return Ok(true);
+        }
+        
+        match try!(self.store.load(username)) {
+            Some(account) => {
+                self.accounts.insert(username.to_string(), Some(Box::new(account)));
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+    
+    /// Client ID of a logged in account
+    pub fn online_client(&self, username: &str) -> Option<ClientId> {
+        self.online.get(username).map(|online| online.client_id)
+    }
+    
+    /// Role of an account, or None if there's no such account
+    pub fn role(&mut self, username: &str) -> Option<Role> {
+        if let Some(online) = self.online.get(username) {
+            return Some(online.role);
+        }
+        
+        match self.load(username) {
+            Ok(true) => self.accounts[username].as_ref().map(|account| account.role),
+            Ok(false) => None,
+            Err(e) => {
+                println!("WARNING: Failed to load account {}: {}", username, e);
+                None
+            },
+        }
+    }
+    
+    /// Changes an account and saves it. Logged in accounts are changed when they log out.
+    pub fn change_account(&mut self, username: &str, change: AccountChange) -> Result<(), String> {
+        if let Some(online) = self.online.get_mut(username) {
+            if let AccountChange::Role(role) = change {
+                online.role = role;
             }
-        } else {
-            Err(LoginError::NoSuchAccount)
+            self.pending_changes.entry(username.to_string()).or_insert(vec!()).push(change);
+            return Ok(());
+        }
+        
+        match self.load(username) {
+            Ok(true) => { },
+            Ok(false) => { return Err(format!("No account named {}", username)); },
+            Err(e) => { return Err(format!("Failed to load account {}: {}", username, e)); },
         }
+        
+        change.apply(self.accounts.get_mut(username).unwrap().as_mut().unwrap());
+        self.unsaved.insert(username.to_string());
+        self.autosave();
+        Ok(())
+    }
+    
+    // Whether the account is locked after too many wrong passwords
+    fn locked_out(&mut self, username: &str) -> bool {
+        let locked_until =
+            match self.failed_logins.get(username) {
+                Some(failed) => failed.locked_until,
+                None => None,
+            };
+        
+        match locked_until {
+            Some(locked_until) if time::now().to_timespec() < locked_until => true,
+            Some(_) => {
+                // Lockout's over, start counting again
+                self.failed_logins.remove(username);
+                false
+            },
+            None => false,
+        }
+    }
+    
+    // Counts a wrong password, locking the account if there have been too many
+    fn login_failed(&mut self, username: String) -> LoginError {
+        let failed = self.failed_logins.entry(username.clone()).or_insert(FailedLogins { count: 0, locked_until: None });
+        failed.count += 1;
+        
+        if failed.count >= MAX_FAILED_LOGINS {
+            println!("Locking account {} after {} wrong passwords", username, failed.count);
+            failed.locked_until = Some(time::now().to_timespec() + time::Duration::milliseconds(LOCKOUT_MS));
+        }
+        LoginError::WrongPassword
     }
     
     pub fn logout_account(&mut self, mut account: AccountBox) {
         let username = account.username.clone();
         account.client_id = None;
+        
+        self.online.remove(&username);
+        if let Some(changes) = self.pending_changes.remove(&username) {
+            for change in changes {
+                change.apply(&mut account);
+            }
+        }
         *self.accounts.get_mut(&username).expect("This must exist") = Some(account);
+        
+        self.unsaved.insert(username);
+        self.autosave();
+    }
+    
+    /// Saves a snapshot of a logged in account, so a crash doesn't lose everything since it logged
+    /// in. Changes waiting for the account to log out go in the save too. Snapshots from an
+    /// earlier session, or of accounts that are already back, are stale and dropped.
+    pub fn save_snapshot(&mut self, mut snapshot: AccountBox) {
+        let current =
+            match self.online.get(&snapshot.username) {
+                Some(online) => snapshot.client_id == Some(online.client_id),
+                None => false,
+            };
+        if !current {
+            return;
+        }
+        
+        snapshot.client_id = None;
+        if let Some(changes) = self.pending_changes.get(&snapshot.username) {
+            for change in changes.iter() {
+                change.clone().apply(&mut snapshot);
+            }
+        }
+        
+        if let Err(e) = self.store.save(&snapshot) {
+            println!("WARNING: Failed to save snapshot of account {}: {}", snapshot.username, e);
+        }
+    }
+    
+    /// Saves every changed account that isn't logged in. Ones that fail to save are tried again
+    /// next time. Logged in accounts are saved when they log out, and in between from the
+    /// snapshots given to `save_snapshot`.
+    pub fn autosave(&mut self) {
+        let mut failed = HashSet::new();
+        
+        for username in self.unsaved.drain() {
+            if let Some(&Some(ref account)) = self.accounts.get(&username) {
+                if let Err(e) = self.store.save(account) {
+                    println!("WARNING: Failed to save account {}: {}", username, e);
+                    failed.insert(username);
+                }
+            }
+        }
+        
+        self.unsaved = failed;
+    }
+    
+    /// Whether every account has been handed back by `logout_account`
+    pub fn all_logged_out(&self) -> bool {
+        self.accounts.values().all(|account| account.is_some())
     }
 }
\ No newline at end of file
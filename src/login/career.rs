@@ -0,0 +1,27 @@
+use std::fmt;
+
+/// What an account has done over its whole life
+#[derive(Clone, Default, Debug, RustcEncodable, RustcDecodable)]
+pub struct CareerStats {
+    pub battles: u32,       // Times the account's ship entered a sector
+    pub turns: u32,         // Turns simulated with the account's ship in them
+    pub kills: u32,         // Ships destroyed, credited to whoever hit them last
+    pub deaths: u32,
+    pub damage_dealt: u64,  // Hull damage that got past the target's shields
+    pub damage_taken: u64,
+    pub repairs: u64,       // Module HP restored by repair modules
+    pub jumps: u32,
+}
+
+impl CareerStats {
+    pub fn new() -> CareerStats {
+        Default::default()
+    }
+}
+
+impl fmt::Display for CareerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} battles, {} turns, {} kills, {} deaths, {} damage dealt, {} taken, {} repaired, {} jumps",
+               self.battles, self.turns, self.kills, self.deaths, self.damage_dealt, self.damage_taken, self.repairs, self.jumps)
+    }
+}
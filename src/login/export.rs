@@ -0,0 +1,230 @@
+use std::cmp;
+
+use rustc_serialize::json;
+
+use module::{BeamWeaponModule, CommandModule, EngineModule, ModelIndex, ModuleClass, ModuleStored, ProjectileWeaponModule,
+             RepairModule, ShieldModule, SolarModule};
+use sector_data::SectorId;
+use ship::{new_ship_id, ShipId, ShipStored};
+
+use super::{Account, Ban, CareerStats, LoginError, Password, Role, Wallet};
+use super::account::{validate_registration, validate_username};
+
+// Bumped whenever the document's layout changes, so old exports are rejected rather than misread
+const DOCUMENT_FORMAT_VERSION: u32 = 1;
+
+// Largest ship an import accepts, in module blocks. Generated ships can outgrow the station
+// editor's 10 by 8 grid, so this leaves them room.
+const MAX_SHIP_SIZE: u8 = 32;
+
+/// An account laid out for people to read and edit, see `export_account`
+#[derive(RustcEncodable, RustcDecodable)]
+struct AccountDocument {
+    format_version: u32,
+    username: String,
+    password: Option<Password>,     // Left out unless exported with credentials
+    sector: u32,
+    role: Role,
+    ban: Option<Ban>,
+    muted_until: Option<i64>,
+    ship: Option<ShipDocument>,     // Active ship
+    hangar: Vec<ShipDocument>,
+    inventory: Vec<InventoryDocument>,
+    career: CareerStats,
+    wallet: Wallet,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct ShipDocument {
+    id: ShipId,
+    name: String,
+    level: u8,
+    hp: u8,
+    modules: Vec<ModuleDocument>,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct ModuleDocument {
+    class: ModuleClass,
+    x: u8,
+    y: u8,
+    hp: u8,
+    active: bool,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct InventoryDocument {
+    model: u16,
+    count: u16,
+}
+
+/// Writes an account out as pretty printed JSON. Only the layout of ships is kept, anything
+/// derived from it is worked out again on import. The password's salt and hash are only included
+/// if `with_credentials` is set, so the document can be handed around safely.
+pub fn export_account(account: &Account, with_credentials: bool) -> String {
+    let mut inventory: Vec<InventoryDocument> = account.module_inventory.iter()
+        .map(|(model, count)| InventoryDocument { model: model.0, count: *count })
+        .collect();
+    inventory.sort_by(|a, b| a.model.cmp(&b.model));
+
+    let document = AccountDocument {
+        format_version: DOCUMENT_FORMAT_VERSION,
+        username: account.username.clone(),
+        password: if with_credentials { Some(account.password.clone()) } else { None },
+        sector: account.sector.0,
+        role: account.role,
+        ban: account.ban.clone(),
+        muted_until: account.muted_until,
+        ship: account.ship.as_ref().map(export_ship),
+        hangar: account.hangar.iter().map(export_ship).collect(),
+        inventory: inventory,
+        career: account.career.clone(),
+        wallet: account.wallet.clone(),
+    };
+
+    format!("{}", json::as_pretty_json(&document))
+}
+
+/// Reads an account written by `export_account`. Its password is reset to `new_password`, or kept
+/// from the document if that's None, which needs a document exported with credentials. The error
+/// says what was wrong with the document.
+pub fn import_account(text: &str, new_password: Option<&str>) -> Result<Account, String> {
+    let document: AccountDocument = try!(json::decode(text).map_err(|e| format!("Invalid account document: {}", e)));
+    if document.format_version != DOCUMENT_FORMAT_VERSION {
+        return Err(format!("Account document is format v{}, expected v{}", document.format_version, DOCUMENT_FORMAT_VERSION));
+    }
+
+    // Hand edited documents don't go through registration, so they're held to its rules here
+    let password =
+        match (new_password, document.password) {
+            (Some(new_password), _) => {
+                try!(validate_registration(&document.username, new_password).map_err(describe_invalid));
+                Password::new(new_password)
+            },
+            (None, Some(password)) => {
+                try!(validate_username(&document.username).map_err(describe_invalid));
+                password
+            },
+            (None, None) => { return Err("Account document has no credentials, so the account needs a new password".to_string()); },
+        };
+
+    let ship = match document.ship {
+        Some(ship) => Some(try!(import_ship(ship))),
+        None => None,
+    };
+
+    let mut hangar = vec!();
+    for ship in document.hangar {
+        hangar.push(try!(import_ship(ship)));
+    }
+
+    Ok(Account {
+        username: document.username,
+        password: password,
+        ship: ship,
+        hangar: hangar,
+        client_id: None,
+        sector: SectorId(document.sector),
+        module_inventory: document.inventory.iter().map(|item| (ModelIndex(item.model), item.count)).collect(),
+        role: document.role,
+        ban: document.ban,
+        muted_until: document.muted_until,
+        career: document.career,
+        wallet: document.wallet,
+    })
+}
+
+fn export_ship(ship: &ShipStored) -> ShipDocument {
+    ShipDocument {
+        id: ship.id,
+        name: ship.name.clone(),
+        level: ship.level,
+        hp: ship.state.get_hp(),
+        modules: ship.modules.iter()
+            .map(|module| ModuleDocument {
+                class: module.get_class(),
+                x: module.x,
+                y: module.y,
+                hp: module.get_hp(),
+                active: module.active,
+            })
+            .collect(),
+    }
+}
+
+// Builds the ship back up one module at a time, the way the station does, then damages it. The
+// ship gets a new ID, since the one it was exported with may still be in use.
+fn import_ship(document: ShipDocument) -> Result<ShipStored, String> {
+    let mut ship = ShipStored::new(new_ship_id(), document.level);
+    ship.name = document.name;
+
+    for module_document in &document.modules {
+        let mut module = create_module(module_document.class);
+        let fits =
+            module_document.x.checked_add(module.width).map(|right| right <= MAX_SHIP_SIZE).unwrap_or(false) &&
+            module_document.y.checked_add(module.height).map(|bottom| bottom <= MAX_SHIP_SIZE).unwrap_or(false);
+        if !fits {
+            return Err(format!("Ship {} has a module outside the {} by {} grid at {}, {}",
+                               document.id, MAX_SHIP_SIZE, MAX_SHIP_SIZE, module_document.x, module_document.y));
+        }
+        if !ship.is_space_free(module_document.x, module_document.y, module.width, module.height) {
+            return Err(format!("Ship {} has overlapping modules at {}, {}", document.id, module_document.x, module_document.y));
+        }
+
+        // Modules go in at full HP so the ship's max HP comes out right
+        module.x = module_document.x;
+        module.y = module_document.y;
+        ship.add_module(module);
+    }
+
+    // Damage modules, turning off any too damaged to work
+    for (index, (module, module_document)) in ship.modules.iter_mut().zip(document.modules.iter()).enumerate() {
+        let hp = cmp::min(module_document.hp, module.stats.max_hp);
+        module.stats.hp = hp;
+        ship.state.module_stats[index].hp = hp;
+
+        if module.active && module.is_damaged() {
+            ship.state.power_use -= module.get_power();
+            module.active = false;
+            module.inner.borrow_mut().on_deactivated(&mut ship.state);
+        }
+    }
+    ship.state.hp = cmp::min(document.hp, ship.state.hp);
+
+    // Power modules back on once every solar panel is in
+    for (module, module_document) in ship.modules.iter_mut().zip(document.modules.iter()) {
+        let power = module.get_power();
+        if module_document.active && !module.active && !module.is_damaged() && ship.state.available_power() >= power {
+            module.active = true;
+            module.inner.borrow_mut().on_activated(&mut ship.state);
+            ship.state.power_use += power;
+        }
+    }
+
+    Ok(ship)
+}
+
+// Says which registration rule an imported account broke
+fn describe_invalid(e: LoginError) -> String {
+    match e {
+        LoginError::UsernameTooShort => "Username is too short".to_string(),
+        LoginError::UsernameTooLong => "Username is too long".to_string(),
+        LoginError::InvalidUsername => "Username may only have letters, digits, _ and -".to_string(),
+        LoginError::PasswordTooShort => "New password is too short".to_string(),
+        _ => "Account doesn't meet the registration rules".to_string(),
+    }
+}
+
+fn create_module(class: ModuleClass) -> ModuleStored {
+    let module =
+        match class {
+            ModuleClass::ProjectileWeapon => ProjectileWeaponModule::new(),
+            ModuleClass::Shield => ShieldModule::new(),
+            ModuleClass::Engine => EngineModule::new(),
+            ModuleClass::Solar => SolarModule::new(),
+            ModuleClass::Command => CommandModule::new(),
+            ModuleClass::BeamWeapon => BeamWeaponModule::new(),
+            ModuleClass::Repair => RepairModule::new(),
+        };
+    ModuleStored::from_module(module)
+}
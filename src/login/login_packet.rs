@@ -1,5 +1,5 @@
 #[derive(RustcEncodable, RustcDecodable)]
-pub struct LoginPacket {
-    pub username: String,
-    pub password: String,
+pub enum LoginPacket {
+    Login(String, String, bool),    // Log into an existing account (username, password, take over the account if it's already logged in)
+    Register(String, String),       // Create an account and log into it (username, password)
 }
\ No newline at end of file
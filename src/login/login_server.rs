@@ -1,6 +1,10 @@
+use std::collections::HashMap;
 use std::sync::mpsc::{Sender, Receiver};
 
+use chat::ChatMute;
+use event_mux::EventMux;
 use net::{
+    ClientId,
     OutPacket,
     ServerSlot,
     ServerSlotId,
@@ -10,75 +14,304 @@ use net::{
 use super::{
     AccountBox,
     AccountManager,
+    AccountChange,
+    AccountStore,
+    Ban,
     LoginError,
+    ModAction,
+    ModRequest,
+    Role,
 };
+use super::moderation;
 use super::LoginPacket;
-use ship::{Ship, ShipId, ShipStored};
+use ship::{new_ship_id, Ship, ShipStored};
 
-pub fn run_login_server(slot: ServerSlot,
+// Everything the login server waits on
+enum LoginEvent {
+    Slot(SlotInMsg),
+    Logout(AccountBox),
+    Moderation(ModRequest),
+    SaveAccount(AccountBox),    // Snapshot of a logged in account, from wherever it is
+    Autosave,
+    TakeoverTimeout(String, ClientId),
+}
+
+// How often accounts that failed to save are tried again
+const AUTOSAVE_INTERVAL_MS: u32 = 60000;
+
+// How long a new connection waits for a kicked session's account to come back. Covers a sector
+// turn and the trip back through the star map.
+const TAKEOVER_TIMEOUT_MS: u32 = 15000;
+
+pub fn run_login_server(mut slot: ServerSlot,
                         star_map_slot_id: ServerSlotId,
                         star_map_chan: Sender<AccountBox>,
-                        logout_receiver: Receiver<AccountBox>) {
-    let mut account_manager = AccountManager::new();
+                        logout_receiver: Receiver<AccountBox>,
+                        store: Box<AccountStore>,
+                        mod_requests: Receiver<ModRequest>,
+                        chat_mutes: Sender<ChatMute>,
+                        account_saves: Receiver<AccountBox>) {
+    let mut account_manager = AccountManager::new(store);
+    
+    let events = EventMux::new();
+    slot.forward_incoming(&events, LoginEvent::Slot);
+    events.forward(logout_receiver, LoginEvent::Logout);
+    events.forward(mod_requests, LoginEvent::Moderation);
+    events.forward(account_saves, LoginEvent::SaveAccount);
+    events.schedule(AUTOSAVE_INTERVAL_MS, LoginEvent::Autosave);
+    
+    // Once shutting down, no one new gets in and we wait for every account to come back
+    let mut shutting_down = false;
+    
+    // Connections waiting for a kicked session's account, by username
+    let mut takeovers: HashMap<String, ClientId> = HashMap::new();
+    
+    // Why each client still here was last refused, sent again if it resumes
+    let mut login_errors: HashMap<ClientId, LoginError> = HashMap::new();
 
     loop {
-        if let Ok(msg) = slot.try_receive() {
-            match msg {
-                SlotInMsg::Joined(client_id) => {
-                    println!("Client {} logging in...", client_id);
-                },
-                SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                    let LoginPacket{username: username, password: password} = packet.read().ok().expect("Failed to receive login packet");
-                    
-                    match account_manager.login_account(username.clone(), password.clone(), client_id) {
-                        Ok(account) => {
-                            // Login ok
-                            let mut result_packet = OutPacket::new();
-                            let login_result: Option<LoginError> = None;
-                            result_packet.write(&login_result);
-                            slot.send(client_id, result_packet);
-                            
-                            slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
-                            star_map_chan.send(account);
-                        },
-                        Err(ref e) if *e == LoginError::NoSuchAccount => {
-                            // Login ok
-                            let mut result_packet = OutPacket::new();
-                            let login_result: Option<LoginError> = None;
-                            result_packet.write(&login_result);
-                            slot.send(client_id, result_packet);
+        match events.recv() {
+            LoginEvent::Slot(msg) => {
+                match msg {
+                    SlotInMsg::Joined(client_id) => {
+                        println!("Client {} logging in...", client_id);
+                    },
+                    SlotInMsg::ShuttingDown => {
+                        shutting_down = true;
+                        if account_manager.all_logged_out() {
+                            account_manager.autosave();
+                            slot.shutdown_complete();
+                        }
+                    },
+                    SlotInMsg::ReceivedPacket(_, _) if shutting_down => {
+                        // The client is about to be told the server is going away
+                    },
+                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
+                        let login_packet: LoginPacket = packet.read().ok().expect("Failed to receive login packet");
+                        
+                        let result =
+                            match login_packet {
+                                LoginPacket::Login(username, password, take_over) => {
+                                    match account_manager.login_account(username.clone(), password.clone(), client_id) {
+                                        Err(LoginError::AlreadyLoggedIn) if take_over => {
+                                            match account_manager.take_over(username.clone(), &password) {
+                                                Ok(old_client_id) => {
+                                                    println!("Client {} taking over {} from client {}", client_id, username, old_client_id);
+                                                    slot.kick(old_client_id, "Logged in from somewhere else".to_string());
+                                                    
+                                                    // The account comes back through a logout. Until then this client waits.
+                                                    if let Some(waiting) = takeovers.insert(username.clone(), client_id) {
+                                                        refuse_login(&slot, &mut login_errors, waiting, LoginError::AlreadyLoggedIn);
+                                                    }
+                                                    events.schedule(TAKEOVER_TIMEOUT_MS, LoginEvent::TakeoverTimeout(username, client_id));
+                                                    continue;
+                                                },
+                                                Err(e) => Err(e),
+                                            }
+                                        },
+                                        result => result,
+                                    }
+                                },
+                                LoginPacket::Register(username, password) => {
+                                    match account_manager.register_account(username.clone(), password.clone()) {
+                                        Ok(()) => account_manager.login_account(username, password, client_id),
+                                        Err(e) => Err(e),
+                                    }
+                                },
+                            };
+                        
+                        match result {
+                            Ok(account) => {
+                                login_errors.remove(&client_id);
+                                enter_game(&slot, star_map_slot_id, &star_map_chan, &chat_mutes, account);
+                            },
+                            Err(e) => {
+                                refuse_login(&slot, &mut login_errors, client_id, e);
+                            },
+                        }
+                    },
+                    SlotInMsg::Resumed(client_id) => {
+                        // Clients waiting on a takeover hear back once the account does
+                        if let Some(e) = login_errors.get(&client_id) {
+                            println!("Client {} reconnected to the login server, refusing it again", client_id);
+                            send_login_result(&slot, client_id, Some(e.clone()));
+                        }
+                    },
+                    SlotInMsg::Disconnected(client_id) => {
+                        login_errors.remove(&client_id);
                         
-                            // Account doesn't exist yet, make it
-                            account_manager.create_account(username.clone(), password.clone());
-                            
-                            // Log into the new account
-                            if let Ok(mut account) = account_manager.login_account(username.clone(), password.clone(), client_id) {
-                                // Create ships
-                                let player_ship = ShipStored::from_ship(Ship::generate(client_id as ShipId, username.clone(), 5));
-                                
-                                account.ship = Some(player_ship);
-                                
-                                slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
-                                star_map_chan.send(account);
-                                
-                            } else {
-                                panic!("Failed to log into newly created account");
-                            }
-                        },
-                        Err(e) => {
-                            let mut result_packet = OutPacket::new();
-                            result_packet.write(&Some(e));
-                            slot.send(client_id, result_packet);
-                        },
+                        // Gave up waiting for a takeover. The account just logs out when it's back.
+                        let gone: Vec<String> = takeovers.iter().filter(|&(_, c)| *c == client_id).map(|(username, _)| username.clone()).collect();
+                        for username in gone {
+                            takeovers.remove(&username);
+                        }
+                    },
+                    SlotInMsg::RateLimited(client_id, penalty) => {
+                        println!("Client {} flooding the login server: {:?}", client_id, penalty);
+                    },
+                    _ => {},
+                }
+            },
+            LoginEvent::Logout(account) => {
+                println!("Client {} logging out", account.client_id.expect("This must have a client ID"));
+                let username = account.username.clone();
+                account_manager.logout_account(account);
+                
+                // Hand the account to the connection that took it over. When shutting down, that
+                // client is about to be told the server is going away instead.
+                if let Some(client_id) = takeovers.remove(&username) {
+                    if !shutting_down {
+                        match account_manager.login_verified(username, client_id) {
+                            Ok(account) => {
+                                login_errors.remove(&client_id);
+                                enter_game(&slot, star_map_slot_id, &star_map_chan, &chat_mutes, account);
+                            },
+                            Err(e) => refuse_login(&slot, &mut login_errors, client_id, e),
+                        }
                     }
-                },
-                _ => {},
-            }
+                }
+                
+                if shutting_down && account_manager.all_logged_out() {
+                    account_manager.autosave();
+                    slot.shutdown_complete();
+                }
+            },
+            LoginEvent::TakeoverTimeout(username, client_id) => {
+                if takeovers.get(&username) == Some(&client_id) {
+                    println!("Client {} timed out taking over {}", client_id, username);
+                    takeovers.remove(&username);
+                    refuse_login(&slot, &mut login_errors, client_id, LoginError::AlreadyLoggedIn);
+                }
+            },
+            LoginEvent::Moderation(request) => {
+                moderate(&mut account_manager, &slot, &chat_mutes, request);
+            },
+            LoginEvent::SaveAccount(snapshot) => {
+                account_manager.save_snapshot(snapshot);
+            },
+            LoginEvent::Autosave => {
+                account_manager.autosave();
+                events.schedule(AUTOSAVE_INTERVAL_MS, LoginEvent::Autosave);
+            },
+        }
+    }
+}
+
+fn send_login_result(slot: &ServerSlot, client_id: ClientId, login_result: Option<LoginError>) {
+    let mut result_packet = OutPacket::new();
+    result_packet.write(&login_result);
+    slot.send(client_id, result_packet);
+}
+
+fn refuse_login(slot: &ServerSlot, login_errors: &mut HashMap<ClientId, LoginError>, client_id: ClientId, e: LoginError) {
+    send_login_result(slot, client_id, Some(e.clone()));
+    login_errors.insert(client_id, e);
+}
+
+// Tells the client it's logged in and sends the account off to the star map
+fn enter_game(slot: &ServerSlot,
+              star_map_slot_id: ServerSlotId,
+              star_map_chan: &Sender<AccountBox>,
+              chat_mutes: &Sender<ChatMute>,
+              mut account: AccountBox) {
+    let client_id = account.client_id.expect("This must have a client ID");
+    
+    // Login ok
+    send_login_result(slot, client_id, None);
+    
+    // New accounts get their first ship
+    if account.ship.is_none() {
+        let player_ship = ShipStored::from_ship(Ship::generate(new_ship_id(), account.username.clone(), 5));
+        account.ship = Some(player_ship);
+    }
+    
+    // Mutes outlast logging out
+    if let Some(until) = account.muted_until {
+        if until > moderation::now() {
+            chat_mutes.send(ChatMute { username: account.username.clone(), until: Some(until) });
+        }
+    }
+    
+    slot.transfer_client(client_id, star_map_slot_id);
+    star_map_chan.send(account);
+}
+
+// Carries out a moderator's request, if they're allowed to
+fn moderate(account_manager: &mut AccountManager, slot: &ServerSlot, chat_mutes: &Sender<ChatMute>, request: ModRequest) {
+    let target = request.action.target().to_string();
+    
+    // Roles can change while a moderator's logged in, so theirs is looked up now
+    let role =
+        match request.role {
+            Some(role) => role,
+            None => account_manager.role(&request.moderator).unwrap_or(Role::Player),
+        };
+    
+    if role < request.action.required_role() {
+        println!("Refused moderator command from {}: not allowed", request);
+        return;
+    }
+    
+    // Only admins can act on staff
+    match account_manager.role(&target) {
+        Some(target_role) if target_role >= Role::Moderator && role < Role::Admin => {
+            println!("Refused moderator command from {}: {} is staff", request, target);
+            return;
+        },
+        Some(_) => { },
+        None => {
+            println!("Refused moderator command from {}: no account named {}", request, target);
+            return;
+        },
+    }
+    
+    println!("Moderator command from {}", request);
+    
+    let kick_reason =
+        match request.action {
+            ModAction::Kick(_, ref reason) => Some(format!("Kicked by {}: {}", request.moderator, reason)),
+            ModAction::Ban(_, _, ref reason) => Some(format!("Banned by {}: {}", request.moderator, reason)),
+            _ => None,
+        };
+    
+    let change =
+        match request.action {
+            ModAction::Kick(_, _) => None,
+            ModAction::Mute(_, minutes) => {
+                let until = moderation::now() + minutes as i64 * 60;
+                chat_mutes.send(ChatMute { username: target.clone(), until: Some(until) });
+                Some(AccountChange::Mute(Some(until)))
+            },
+            ModAction::Unmute(_) => {
+                chat_mutes.send(ChatMute { username: target.clone(), until: None });
+                Some(AccountChange::Mute(None))
+            },
+            ModAction::Ban(_, minutes, ref reason) => {
+                Some(AccountChange::Ban(Some(Ban {
+                    until: minutes.map(|minutes| moderation::now() + minutes as i64 * 60),
+                    reason: reason.clone(),
+                    by: request.moderator.clone(),
+                })))
+            },
+            ModAction::Unban(_) => Some(AccountChange::Ban(None)),
+            ModAction::SetRole(_, role) => Some(AccountChange::Role(role)),
+        };
+    
+    if let Some(change) = change {
+        if let Err(e) = account_manager.change_account(&target, change) {
+            println!("Moderator command from {} failed: {}", request, e);
+            return;
+        }
+    }
+    
+    if let Some(reason) = kick_reason {
+        match account_manager.online_client(&target) {
+            Some(client_id) => slot.kick(client_id, reason),
+            None => {
+                if let ModAction::Kick(_, _) = request.action {
+                    println!("Can't kick {}, they aren't logged in", target);
+                }
+            },
         }
-        
-        if let Ok(account) = logout_receiver.try_recv() {
-            println!("Client {} logging out", account.client_id.expect("This must have a client ID"));
-            account_manager.logout_account(account);
-        }        
     }
-}
\ No newline at end of file
+}
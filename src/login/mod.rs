@@ -1,8 +1,20 @@
 pub use self::login_packet::*;
 pub use self::login_server::run_login_server;
 pub use self::account::{Account, AccountBox, AccountManager, LoginError};
+pub use self::career::CareerStats;
+pub use self::export::{export_account, import_account};
+pub use self::moderation::{moderator_command, AccountChange, Ban, ModAction, ModRequest, Role};
+pub use self::password::{Password, PasswordHash};
+pub use self::store::{AccountStore, FileAccountStore, MemoryAccountStore};
+pub use self::wallet::{send_balance, InsufficientFunds, Transaction, Wallet};
 
 mod login_packet;
 mod login_server;
 
-mod account;
\ No newline at end of file
+mod account;
+mod career;
+mod export;
+mod moderation;
+mod password;
+mod store;
+mod wallet;
\ No newline at end of file
@@ -0,0 +1,194 @@
+use std::cmp;
+use std::fmt;
+
+use time;
+
+use chat::ChatMsg;
+
+use super::Account;
+
+/// What an account is allowed to do to other accounts
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug, RustcEncodable, RustcDecodable)]
+pub enum Role {
+    Player,
+    Moderator,  // Can kick, mute and ban players
+    Admin,      // Can also act on moderators and admins, and hand out roles
+}
+
+impl Role {
+    fn parse(name: &str) -> Option<Role> {
+        match name {
+            "player" => Some(Role::Player),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps an account from logging in
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Ban {
+    pub until: Option<i64>,     // Unix time in seconds the ban ends, or None if it never does
+    pub reason: String,
+    pub by: String,             // Who banned the account
+}
+
+impl Ban {
+    pub fn in_force(&self) -> bool {
+        match self.until {
+            Some(until) => now() < until,
+            None => true,
+        }
+    }
+}
+
+/// A moderation change to an account
+#[derive(Clone)]
+pub enum AccountChange {
+    Ban(Option<Ban>),
+    Mute(Option<i64>),  // Unix time in seconds the mute ends, or None to unmute
+    Role(Role),
+}
+
+impl AccountChange {
+    pub fn apply(self, account: &mut Account) {
+        match self {
+            AccountChange::Ban(ban) => { account.ban = ban; },
+            AccountChange::Mute(until) => { account.muted_until = until; },
+            AccountChange::Role(role) => { account.role = role; },
+        }
+    }
+}
+
+/// Current unix time in seconds, which bans and mutes are measured in
+pub fn now() -> i64 {
+    time::get_time().sec
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Actions
+
+/// Something a moderator wants done to an account
+#[derive(Clone, Debug)]
+pub enum ModAction {
+    Kick(String, String),           // Disconnect the player (username, reason)
+    Mute(String, u32),              // Drop the player's chat messages (username, minutes)
+    Unmute(String),                 // (username)
+    Ban(String, Option<u32>, String), // Kick the player and keep them out (username, minutes or None for good, reason)
+    Unban(String),                  // (username)
+    SetRole(String, Role),          // (username, role)
+}
+
+impl ModAction {
+    /// Parses a moderator command typed into chat or the server console, without the leading '/'.
+    /// The error says how the command should look.
+    pub fn parse(command: &str) -> Result<ModAction, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let name = words.get(0).map(|name| *name).unwrap_or("");
+
+        let usage =
+            match name {
+                "kick" => "kick <username> [reason]",
+                "mute" => "mute <username> <minutes>",
+                "unmute" => "unmute <username>",
+                "ban" => "ban <username> <minutes|forever> [reason]",
+                "unban" => "unban <username>",
+                "role" => "role <username> <player|moderator|admin>",
+                _ => { return Err("Commands are kick, mute, unmute, ban, unban and role".to_string()); },
+            };
+
+        let target =
+            match words.get(1) {
+                Some(target) => target.to_string(),
+                None => { return Err(format!("Usage: {}", usage)); },
+            };
+        let argument = words.get(2).map(|argument| *argument);
+
+        let action =
+            match name {
+                "kick" => Some(ModAction::Kick(target, reason(&words[2..]))),
+                "mute" => argument.and_then(|minutes| minutes.parse().ok()).map(|minutes| ModAction::Mute(target, minutes)),
+                "unmute" => Some(ModAction::Unmute(target)),
+                "ban" => {
+                    let reason = reason(&words[cmp::min(3, words.len())..]);
+                    match argument {
+                        Some("forever") => Some(ModAction::Ban(target, None, reason)),
+                        Some(minutes) => minutes.parse().ok().map(|minutes| ModAction::Ban(target, Some(minutes), reason)),
+                        None => None,
+                    }
+                },
+                "unban" => Some(ModAction::Unban(target)),
+                "role" => argument.and_then(Role::parse).map(|role| ModAction::SetRole(target, role)),
+                _ => unreachable!(),
+            };
+        action.ok_or(format!("Usage: {}", usage))
+    }
+
+    /// Username of the account being acted on
+    pub fn target(&self) -> &str {
+        match *self {
+            ModAction::Kick(ref username, _) |
+            ModAction::Mute(ref username, _) |
+            ModAction::Unmute(ref username) |
+            ModAction::Ban(ref username, _, _) |
+            ModAction::Unban(ref username) |
+            ModAction::SetRole(ref username, _) => username,
+        }
+    }
+
+    /// Least role that may do this
+    pub fn required_role(&self) -> Role {
+        match *self {
+            ModAction::SetRole(_, _) => Role::Admin,
+            _ => Role::Moderator,
+        }
+    }
+}
+
+// Free text at the end of a command
+fn reason(words: &[&str]) -> String {
+    if words.is_empty() {
+        "No reason given".to_string()
+    } else {
+        words.join(" ")
+    }
+}
+
+/// A moderator action on its way to the login server, which carries them out
+pub struct ModRequest {
+    pub moderator: String,  // Who asked, for the logs
+    
+    // What they're allowed to do, for requests from outside the game like the console. Requests
+    // from accounts go by the account's role when they're carried out.
+    pub role: Option<Role>,
+    
+    pub action: ModAction,
+}
+
+impl fmt::Display for ModRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:?}", self.moderator, self.action)
+    }
+}
+
+/// Turns a chat message into a moderator request for the login server if it's a command from a
+/// moderator. A command that doesn't parse gives a chat reply for only the moderator to see, saying
+/// how it should have looked. Anyone else's messages are just chat.
+pub fn moderator_command(account: &Account, message: &str) -> Option<Result<ModRequest, ChatMsg>> {
+    if account.role < Role::Moderator || !message.starts_with("/") {
+        return None;
+    }
+
+    Some(match ModAction::parse(&message[1..]) {
+        Ok(action) => Ok(ModRequest {
+            moderator: account.username.clone(),
+            role: None,
+            action: action,
+        }),
+        Err(usage) => Err(ChatMsg {
+            author_name: "Moderation".to_string(),
+            content: usage,
+        }),
+    })
+}
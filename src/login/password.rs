@@ -0,0 +1,72 @@
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{OsRng, Rng};
+
+// PBKDF2 rounds for new hashes. Old hashes keep the count they were made with.
+const PBKDF2_ITERATIONS: u32 = 20000;
+
+const SALT_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+
+/// A password as an account keeps it
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum Password {
+    Plain(String),          // Saved before passwords were hashed. Replaced by a hash on next login.
+    Hashed(PasswordHash),
+}
+
+impl Password {
+    pub fn new(password: &str) -> Password {
+        Password::Hashed(PasswordHash::new(password))
+    }
+
+    /// Checks a password in time that doesn't depend on how much of it matched
+    pub fn verify(&self, password: &str) -> bool {
+        match *self {
+            Password::Plain(ref plain) => fixed_time_eq(plain.as_bytes(), password.as_bytes()),
+            Password::Hashed(ref hash) => hash.verify(password),
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        match *self {
+            Password::Plain(_) => true,
+            Password::Hashed(_) => false,
+        }
+    }
+}
+
+/// Salted PBKDF2-HMAC-SHA256 of a password
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct PasswordHash {
+    salt: Vec<u8>,
+    iterations: u32,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng::new().ok().expect("Failed to open OS random number generator").fill_bytes(&mut salt);
+
+        let hash = derive(password, &salt, PBKDF2_ITERATIONS);
+        PasswordHash {
+            salt: salt,
+            iterations: PBKDF2_ITERATIONS,
+            hash: hash,
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        fixed_time_eq(&derive(password, &self.salt, self.iterations), &self.hash)
+    }
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    let mut hash = vec![0u8; HASH_SIZE];
+    pbkdf2(&mut mac, salt, iterations, &mut hash);
+    hash
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use bincode::{encode_into, decode_from, SizeLimit};
+use rustc_serialize::hex::ToHex;
+
+use module::ModelIndex;
+use net::ClientId;
+use sector_data::SectorId;
+use ship::ShipStored;
+
+use super::{Account, Ban, CareerStats, Password, Role, Wallet};
+
+// Bump this whenever `Account` changes in a way that would make older files misparse
+const ACCOUNT_FORMAT_VERSION: u32 = 6;
+
+// Account as format v1 saved it, with a plaintext password
+#[derive(RustcDecodable)]
+struct AccountV1 {
+    username: String,
+    password: String,
+    ship: Option<ShipStored>,
+    client_id: Option<ClientId>,
+    sector: SectorId,
+    module_inventory: HashMap<ModelIndex, u16>,
+}
+
+impl AccountV1 {
+    fn upgrade(self) -> Account {
+        AccountV2 {
+            username: self.username,
+            password: Password::Plain(self.password),
+            ship: self.ship,
+            client_id: self.client_id,
+            sector: self.sector,
+            module_inventory: self.module_inventory,
+        }.upgrade()
+    }
+}
+
+// Account as format v2 saved it, before roles, bans and mutes
+#[derive(RustcDecodable)]
+struct AccountV2 {
+    username: String,
+    password: Password,
+    ship: Option<ShipStored>,
+    client_id: Option<ClientId>,
+    sector: SectorId,
+    module_inventory: HashMap<ModelIndex, u16>,
+}
+
+impl AccountV2 {
+    fn upgrade(self) -> Account {
+        AccountV3 {
+            username: self.username,
+            password: self.password,
+            ship: self.ship,
+            client_id: self.client_id,
+            sector: self.sector,
+            module_inventory: self.module_inventory,
+            role: Role::Player,
+            ban: None,
+            muted_until: None,
+        }.upgrade()
+    }
+}
+
+// Account as format v3 saved it, before career stats
+#[derive(RustcDecodable)]
+struct AccountV3 {
+    username: String,
+    password: Password,
+    ship: Option<ShipStored>,
+    client_id: Option<ClientId>,
+    sector: SectorId,
+    module_inventory: HashMap<ModelIndex, u16>,
+    role: Role,
+    ban: Option<Ban>,
+    muted_until: Option<i64>,
+}
+
+impl AccountV3 {
+    fn upgrade(self) -> Account {
+        AccountV4 {
+            username: self.username,
+            password: self.password,
+            ship: self.ship,
+            client_id: self.client_id,
+            sector: self.sector,
+            module_inventory: self.module_inventory,
+            role: self.role,
+            ban: self.ban,
+            muted_until: self.muted_until,
+            career: CareerStats::new(),
+        }.upgrade()
+    }
+}
+
+// Account as format v4 saved it, before credits
+#[derive(RustcDecodable)]
+struct AccountV4 {
+    username: String,
+    password: Password,
+    ship: Option<ShipStored>,
+    client_id: Option<ClientId>,
+    sector: SectorId,
+    module_inventory: HashMap<ModelIndex, u16>,
+    role: Role,
+    ban: Option<Ban>,
+    muted_until: Option<i64>,
+    career: CareerStats,
+}
+
+impl AccountV4 {
+    fn upgrade(self) -> Account {
+        AccountV5 {
+            username: self.username,
+            password: self.password,
+            ship: self.ship,
+            client_id: self.client_id,
+            sector: self.sector,
+            module_inventory: self.module_inventory,
+            role: self.role,
+            ban: self.ban,
+            muted_until: self.muted_until,
+            career: self.career,
+            wallet: Wallet::new(),
+        }.upgrade()
+    }
+}
+
+// Account as format v5 saved it, before the hangar
+#[derive(RustcDecodable)]
+struct AccountV5 {
+    username: String,
+    password: Password,
+    ship: Option<ShipStored>,
+    client_id: Option<ClientId>,
+    sector: SectorId,
+    module_inventory: HashMap<ModelIndex, u16>,
+    role: Role,
+    ban: Option<Ban>,
+    muted_until: Option<i64>,
+    career: CareerStats,
+    wallet: Wallet,
+}
+
+impl AccountV5 {
+    fn upgrade(self) -> Account {
+        Account {
+            username: self.username,
+            password: self.password,
+            ship: self.ship,
+            hangar: vec!(),
+            client_id: self.client_id,
+            sector: self.sector,
+            module_inventory: self.module_inventory,
+            role: self.role,
+            ban: self.ban,
+            muted_until: self.muted_until,
+            career: self.career,
+            wallet: self.wallet,
+        }
+    }
+}
+
+/// Somewhere accounts outlive the server process
+pub trait AccountStore: Send {
+    /// Loads an account, or returns None if it was never saved
+    fn load(&mut self, username: &str) -> io::Result<Option<Account>>;
+
+    /// Saves an account, replacing any earlier save of it
+    fn save(&mut self, account: &Account) -> io::Result<()>;
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Memory
+
+/// Keeps nothing. Accounts only last as long as the server's `AccountManager`.
+pub struct MemoryAccountStore;
+
+impl AccountStore for MemoryAccountStore {
+    fn load(&mut self, _username: &str) -> io::Result<Option<Account>> {
+        Ok(None)
+    }
+
+    fn save(&mut self, _account: &Account) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Files
+
+/// Keeps each account in its own file in a directory. A save is written to a temporary file and
+/// renamed over the old one, so a crash mid-save leaves the previous save intact.
+pub struct FileAccountStore {
+    directory: PathBuf,
+}
+
+impl FileAccountStore {
+    /// Opens the store, creating the directory if it isn't there yet
+    pub fn open(directory: &str) -> io::Result<FileAccountStore> {
+        try!(fs::create_dir_all(directory));
+        Ok(FileAccountStore {
+            directory: PathBuf::from(directory),
+        })
+    }
+
+    // Usernames can be anything, so they're hex encoded to keep them from escaping the directory
+    fn path(&self, username: &str, extension: &str) -> PathBuf {
+        self.directory.join(format!("{}.{}", username.as_bytes().to_hex(), extension))
+    }
+}
+
+impl AccountStore for FileAccountStore {
+    fn load(&mut self, username: &str) -> io::Result<Option<Account>> {
+        use std::io::{Error, ErrorKind};
+
+        let file =
+            match File::open(self.path(username, "account")) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+        let mut reader = BufReader::new(file);
+
+        let version: u32 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                    .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+        match version {
+            1 => {
+                let account: AccountV1 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                  .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account.upgrade()))
+            },
+            2 => {
+                let account: AccountV2 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                  .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account.upgrade()))
+            },
+            3 => {
+                let account: AccountV3 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                  .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account.upgrade()))
+            },
+            4 => {
+                let account: AccountV4 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                  .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account.upgrade()))
+            },
+            5 => {
+                let account: AccountV5 = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                  .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account.upgrade()))
+            },
+            ACCOUNT_FORMAT_VERSION => {
+                let account: Account = try!(decode_from(&mut reader, SizeLimit::Infinite)
+                                                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e))));
+                Ok(Some(account))
+            },
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Account file is format v{}, expected v{}", version, ACCOUNT_FORMAT_VERSION))),
+        }
+    }
+
+    fn save(&mut self, account: &Account) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        let temp_path = self.path(&account.username, "tmp");
+        {
+            let mut writer = BufWriter::new(try!(File::create(&temp_path)));
+            try!(encode_into(&ACCOUNT_FORMAT_VERSION, &mut writer, SizeLimit::Infinite)
+                     .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e))));
+            try!(encode_into(account, &mut writer, SizeLimit::Infinite)
+                     .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e))));
+            try!(writer.flush());
+
+            // Make sure it's on disk before it replaces the old save
+            try!(writer.get_ref().sync_all());
+        }
+
+        fs::rename(&temp_path, self.path(&account.username, "account"))
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use rustc_serialize::Encodable;
+use time;
+
+use net::{ClientId, OutPacket, ServerSlot};
+
+// How many transactions a wallet remembers
+const LEDGER_LENGTH: usize = 100;
+
+/// One change to a wallet's balance
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Transaction {
+    pub time: i64,          // Unix time in seconds
+    pub change: i64,        // Credits added, or taken away if negative
+    pub balance: u64,       // Balance afterwards
+    pub reason: String,
+}
+
+/// A spend the wallet couldn't cover
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InsufficientFunds {
+    pub balance: u64,
+    pub needed: u64,
+}
+
+impl fmt::Display for InsufficientFunds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Needed {} credits but only had {}", self.needed, self.balance)
+    }
+}
+
+/// An account's credits, and a ledger of the most recent transactions
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Wallet {
+    balance: u64,
+    ledger: VecDeque<Transaction>,  // Oldest first
+}
+
+impl Wallet {
+    pub fn new() -> Wallet {
+        Wallet {
+            balance: 0,
+            ledger: VecDeque::new(),
+        }
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.balance
+    }
+
+    /// Recent transactions, oldest first
+    #[allow(dead_code)] // Nothing in the game reads, spends or transfers credits yet
+    pub fn ledger(&self) -> &VecDeque<Transaction> {
+        &self.ledger
+    }
+
+    pub fn earn(&mut self, amount: u64, reason: &str) {
+        self.balance = self.balance.saturating_add(amount);
+        self.record(amount as i64, reason);
+    }
+
+    /// Takes credits out, or leaves the wallet alone if there aren't enough
+    #[allow(dead_code)]
+    pub fn spend(&mut self, amount: u64, reason: &str) -> Result<(), InsufficientFunds> {
+        if amount > self.balance {
+            return Err(InsufficientFunds { balance: self.balance, needed: amount });
+        }
+
+        self.balance -= amount;
+        self.record(-(amount as i64), reason);
+        Ok(())
+    }
+
+    /// Moves credits between wallets. Nothing moves if `from` can't cover it.
+    #[allow(dead_code)]
+    pub fn transfer(from: &mut Wallet, to: &mut Wallet, amount: u64, reason: &str) -> Result<(), InsufficientFunds> {
+        try!(from.spend(amount, reason));
+        to.earn(amount, reason);
+        Ok(())
+    }
+
+    fn record(&mut self, change: i64, reason: &str) {
+        if self.ledger.len() >= LEDGER_LENGTH {
+            self.ledger.pop_front();
+        }
+
+        self.ledger.push_back(Transaction {
+            time: time::get_time().sec,
+            change: change,
+            balance: self.balance,
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// Tells a client its balance, in whichever packet `wrap` makes for the screen it's on
+pub fn send_balance<P, F>(slot: &ServerSlot, client_id: ClientId, wallet: &Wallet, wrap: F)
+    where P: Encodable, F: Fn(u64) -> P
+{
+    let mut packet = OutPacket::new();
+    packet.write(&wrap(wallet.balance())).unwrap();
+    slot.send(client_id, packet);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InsufficientFunds, Wallet};
+
+    #[test]
+    fn spend_more_than_balance() {
+        let mut wallet = Wallet::new();
+        wallet.earn(50, "Kill");
+
+        assert_eq!(wallet.spend(80, "Repairs"), Err(InsufficientFunds { balance: 50, needed: 80 }));
+        assert_eq!(wallet.balance(), 50);
+        assert_eq!(wallet.ledger().len(), 1);
+
+        assert_eq!(wallet.spend(50, "Repairs"), Ok(()));
+        assert_eq!(wallet.balance(), 0);
+        assert_eq!(wallet.ledger().back().unwrap().change, -50);
+    }
+
+    #[test]
+    fn transfer_moves_all_or_nothing() {
+        let mut from = Wallet::new();
+        let mut to = Wallet::new();
+        from.earn(30, "Kill");
+
+        // Not enough, so neither wallet changes
+        assert_eq!(Wallet::transfer(&mut from, &mut to, 40, "Gift"), Err(InsufficientFunds { balance: 30, needed: 40 }));
+        assert_eq!(from.balance(), 30);
+        assert_eq!(to.balance(), 0);
+        assert!(to.ledger().is_empty());
+
+        assert_eq!(Wallet::transfer(&mut from, &mut to, 20, "Gift"), Ok(()));
+        assert_eq!(from.balance(), 10);
+        assert_eq!(to.balance(), 20);
+        assert_eq!(to.ledger().back().unwrap().reason, "Gift");
+    }
+
+    #[test]
+    fn ledger_keeps_most_recent() {
+        let mut wallet = Wallet::new();
+        for i in 0..super::LEDGER_LENGTH + 5 {
+            wallet.earn(1, &format!("Kill {}", i));
+        }
+
+        assert_eq!(wallet.ledger().len(), super::LEDGER_LENGTH);
+        assert_eq!(wallet.ledger().front().unwrap().reason, "Kill 5");
+        assert_eq!(wallet.balance(), super::LEDGER_LENGTH as u64 + 5);
+    }
+}
@@ -1,5 +1,6 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use time;
 
 use glutin_window::GlutinWindow;
 use event::{Events, GenericEvent};
@@ -10,10 +11,12 @@ use opengl_graphics::glyph_cache::GlyphCache;
 
 use gui::{TextBox, TextButton};
 use login::LoginError;
+use net::NetError;
 
 #[derive(Clone)]
 pub enum LoginGuiAction {
-    Login(String, String, String),
+    Login(String, String, String, bool),    // (username, password, ip_address, take over the account if it's logged in)
+    Register(String, String, String),       // (username, password, ip_address)
     Back,
 }
 
@@ -25,6 +28,9 @@ pub struct LoginScreen {
     
     pub login_error: Option<LoginError>,
     
+    // Set when the connection to the server was refused or lost
+    pub net_error: Option<NetError>,
+    
     // Text boxes
     username_box: TextBox,
     password_box: TextBox,
@@ -33,6 +39,8 @@ pub struct LoginScreen {
     // Buttons
     back_button: TextButton,
     login_button: TextButton,
+    register_button: TextButton,
+    takeover_button: TextButton,    // Only shown when the account is already logged in
 }
 
 impl LoginScreen {
@@ -47,6 +55,7 @@ impl LoginScreen {
             mouse_y: 0.0,
             
             login_error: None,
+            net_error: None,
             
             username_box: TextBox::new("user".to_string(), 24, [600.0, 300.0], [300.0, 40.0]),
             password_box: password_box,
@@ -54,6 +63,8 @@ impl LoginScreen {
             
             back_button: TextButton::new("Back".to_string(), 24, [450.0, 500.0], [150.0, 40.0]),
             login_button: TextButton::new("Login".to_string(), 24, [610.0, 500.0], [150.0, 40.0]),
+            register_button: TextButton::new("Register".to_string(), 24, [770.0, 500.0], [150.0, 40.0]),
+            takeover_button: TextButton::new("Take over".to_string(), 24, [910.0, 345.0], [200.0, 40.0]),
         }
     }
 
@@ -106,6 +117,10 @@ impl LoginScreen {
         // Handle buttons
         self.login_button.event(e, [self.mouse_x, self.mouse_y]);
         self.back_button.event(e, [self.mouse_x, self.mouse_y]);
+        self.register_button.event(e, [self.mouse_x, self.mouse_y]);
+        if self.already_logged_in() {
+            self.takeover_button.event(e, [self.mouse_x, self.mouse_y]);
+        }
         
         if self.back_button.get_clicked() {
             self.action = Some(LoginGuiAction::Back);
@@ -114,7 +129,29 @@ impl LoginScreen {
         if self.login_button.get_clicked() {
             self.action = Some(LoginGuiAction::Login(self.username_box.text.clone(),
                                                      self.password_box.text.clone(),
-                                                     self.ip_box.text.clone()));
+                                                     self.ip_box.text.clone(),
+                                                     false));
+        }
+        
+        // Kicks whoever is logged into the account, like a session left behind by a crash
+        if self.already_logged_in() && self.takeover_button.get_clicked() {
+            self.action = Some(LoginGuiAction::Login(self.username_box.text.clone(),
+                                                     self.password_box.text.clone(),
+                                                     self.ip_box.text.clone(),
+                                                     true));
+        }
+        
+        if self.register_button.get_clicked() {
+            self.action = Some(LoginGuiAction::Register(self.username_box.text.clone(),
+                                                        self.password_box.text.clone(),
+                                                        self.ip_box.text.clone()));
+        }
+    }
+
+    fn already_logged_in(&self) -> bool {
+        match self.login_error {
+            Some(LoginError::AlreadyLoggedIn) => true,
+            _ => false,
         }
     }
 
@@ -179,38 +216,50 @@ impl LoginScreen {
         // Draw the buttons
         self.back_button.draw(context, gl, glyph_cache);
         self.login_button.draw(context, gl, glyph_cache);
+        self.register_button.draw(context, gl, glyph_cache);
+        if self.already_logged_in() {
+            self.takeover_button.draw(context, gl, glyph_cache);
+        }
         
         // Draw error messages
-        if let Some(login_error) = self.login_error {
-            match login_error {
-                LoginError::NoSuchAccount => {
-                    let context = context.trans(910.0, 330.0);
-                    Text::colored([1.0, 0.0, 0.0, 1.0], 30).draw(
-                        "User doesn't exist",
-                        glyph_cache,
-                        &context.draw_state, context.transform,
-                        gl,
-                    );
-                },
-                LoginError::AlreadyLoggedIn => {
-                    let context = context.trans(910.0, 330.0);
-                    Text::colored([1.0, 0.0, 0.0, 1.0], 30).draw(
-                        "User already logged in",
-                        glyph_cache,
-                        &context.draw_state, context.transform,
-                        gl,
-                    );
-                },
-                LoginError::WrongPassword => {
-                    let context = context.trans(910.0, 400.0);
-                    Text::colored([1.0, 0.0, 0.0, 1.0], 30).draw(
-                        "Incorrect password",
-                        glyph_cache,
-                        &context.draw_state, context.transform,
-                        gl,
-                    );
-                },
-            }
+        if let Some(ref net_error) = self.net_error {
+            let context = context.trans(400.0, 590.0);
+            Text::colored([1.0, 0.0, 0.0, 1.0], 30).draw(
+                &format!("{}", net_error),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+        
+        if let Some(ref login_error) = self.login_error {
+            // Shown beside the box the error is about
+            let (message, y) =
+                match *login_error {
+                    LoginError::NoSuchAccount => ("User doesn't exist".to_string(), 330.0),
+                    LoginError::AlreadyLoggedIn => ("User already logged in".to_string(), 330.0),
+                    LoginError::WrongPassword => ("Incorrect password".to_string(), 400.0),
+                    LoginError::Unavailable => ("Account unavailable, try again later".to_string(), 330.0),
+                    LoginError::LockedOut => ("Too many attempts, try again later".to_string(), 400.0),
+                    LoginError::UsernameTaken => ("Username is taken".to_string(), 330.0),
+                    LoginError::InvalidUsername => ("Only letters, digits, _ and - allowed".to_string(), 330.0),
+                    LoginError::UsernameTooShort => ("Username is too short".to_string(), 330.0),
+                    LoginError::UsernameTooLong => ("Username is too long".to_string(), 330.0),
+                    LoginError::PasswordTooShort => ("Password is too short".to_string(), 400.0),
+                    LoginError::Banned { until: Some(until), ref reason } => {
+                        let until = time::at(time::Timespec::new(until, 0));
+                        (format!("Banned until {}: {}", until.strftime("%Y-%m-%d %H:%M").unwrap(), reason), 330.0)
+                    },
+                    LoginError::Banned { until: None, ref reason } => (format!("Banned: {}", reason), 330.0),
+                };
+            
+            let context = context.trans(910.0, y);
+            Text::colored([1.0, 0.0, 0.0, 1.0], 30).draw(
+                &message,
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
         }
     }
 }
\ No newline at end of file
@@ -47,7 +47,7 @@ impl IModule for BeamWeaponModule {
                         events.add(
                             hit_tick,
                             target.ship.index,
-                            Box::new(DamageEvent::new(module.index, 1, 0, false)),
+                            Box::new(DamageEvent::new(context.ship_id, module.index, 1, 0, false)),
                         );
                     }
                 });
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use time;
+
+use super::{ClientId, InPacket, ServerSlot, ServerSlotId, SlotInMsg, SlotOutMsg};
+use super::framing::{read_varint, write_varint, DEFAULT_MAX_MESSAGE_SIZE};
+use super::wire::{read_full, read_u8, write_u8, read_u32, write_u32};
+
+// Capture files start with the magic and version, then the wall clock time the capture started as
+// a varint of seconds since the Unix epoch. After that it's records until the end of the file:
+//
+//     u8 kind, varint milliseconds since the capture started, u32 client ID, u32 slot ID,
+//     varint data length, data
+//
+// Data is the packet for `In` and `Out` records, and empty otherwise.
+
+const CAPTURE_MAGIC: [u8; 4] = [b'R', b'F', b'C', b'P'];
+const CAPTURE_VERSION: u32 = 1;
+
+// Largest record data that's read back. Nothing bigger can cross the wire, so a longer length
+// means the file is damaged.
+const MAX_RECORD_SIZE: u64 = DEFAULT_MAX_MESSAGE_SIZE;
+
+/// What a capture record saw happen
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CaptureKind {
+    Joined,         // Client entered the slot
+    Disconnected,   // Client's connection closed while in the slot
+    In,             // Packet from the client to the slot
+    Out,            // Packet from the slot to the client
+}
+
+impl CaptureKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            CaptureKind::Joined => 0,
+            CaptureKind::Disconnected => 1,
+            CaptureKind::In => 2,
+            CaptureKind::Out => 3,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<CaptureKind> {
+        match kind {
+            0 => Some(CaptureKind::Joined),
+            1 => Some(CaptureKind::Disconnected),
+            2 => Some(CaptureKind::In),
+            3 => Some(CaptureKind::Out),
+            _ => None,
+        }
+    }
+}
+
+pub struct CaptureRecord {
+    pub kind: CaptureKind,
+    pub time_ms: u64,   // Since the capture started
+    pub client_id: ClientId,
+    pub slot_id: ServerSlotId,
+    pub data: Vec<u8>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Recording
+
+/// Writes packets passing through the server master to a capture file. Give one to
+/// `Server::set_capture`.
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+    start: u64, // precise_time_ns when the capture started
+}
+
+impl CaptureWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CaptureWriter> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+
+        try!(writer.write_all(&CAPTURE_MAGIC));
+        try!(write_u32(&mut writer, CAPTURE_VERSION));
+        try!(write_varint(&mut writer, time::get_time().sec as u64));
+        try!(writer.flush());
+
+        Ok(CaptureWriter {
+            writer: writer,
+            start: time::precise_time_ns(),
+        })
+    }
+
+    /// Appends a record. It's flushed straight away so a crash doesn't lose the turn that caused it.
+    pub fn record(&mut self, kind: CaptureKind, client_id: ClientId, slot_id: ServerSlotId, data: &[u8]) -> io::Result<()> {
+        let time_ms = (time::precise_time_ns() - self.start) / 1000000;
+
+        try!(write_u8(&mut self.writer, kind.to_u8()));
+        try!(write_varint(&mut self.writer, time_ms));
+        try!(write_u32(&mut self.writer, client_id));
+        try!(write_u32(&mut self.writer, slot_id));
+        try!(write_varint(&mut self.writer, data.len() as u64));
+        try!(self.writer.write_all(data));
+        self.writer.flush()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Reading
+
+/// Reads a capture file back one record at a time
+pub struct CaptureReader {
+    reader: BufReader<File>,
+    started_at: u64,
+}
+
+impl CaptureReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CaptureReader> {
+        use std::io::{Error, ErrorKind};
+
+        let mut reader = BufReader::new(try!(File::open(path)));
+
+        let mut magic = [0u8; 4];
+        try!(read_full(&mut reader, &mut magic));
+        if magic != CAPTURE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a capture file"));
+        }
+
+        let version = try!(read_u32(&mut reader));
+        if version != CAPTURE_VERSION {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("Capture file is version {}, expected {}", version, CAPTURE_VERSION)));
+        }
+
+        let started_at = try!(read_varint(&mut reader));
+
+        Ok(CaptureReader {
+            reader: reader,
+            started_at: started_at,
+        })
+    }
+
+    /// When the capture started, in seconds since the Unix epoch
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// Returns the next record, or None at the end of the file
+    pub fn next_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        use std::io::{Error, ErrorKind};
+
+        // A clean end of file can only happen between records
+        let mut kind = [0u8; 1];
+        if try!(self.reader.read(&mut kind)) == 0 {
+            return Ok(None);
+        }
+
+        let kind =
+            match CaptureKind::from_u8(kind[0]) {
+                Some(kind) => kind,
+                None => { return Err(Error::new(ErrorKind::InvalidInput, "Unknown capture record kind")); },
+            };
+
+        let time_ms = try!(read_varint(&mut self.reader));
+        let client_id = try!(read_u32(&mut self.reader));
+        let slot_id = try!(read_u32(&mut self.reader));
+        let len = try!(read_varint(&mut self.reader));
+        if len > MAX_RECORD_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("Capture record of {} bytes exceeds limit of {} bytes", len, MAX_RECORD_SIZE)));
+        }
+        let mut data = vec![0u8; len as usize];
+        try!(read_full(&mut self.reader, &mut data));
+
+        Ok(Some(CaptureRecord {
+            kind: kind,
+            time_ms: time_ms,
+            client_id: client_id,
+            slot_id: slot_id,
+            data: data,
+        }))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Replay
+
+/// Makes a slot that plays back what a captured slot received, for reproducing its behaviour
+/// offline. Clients join, send their packets and disconnect as they did in the capture. With
+/// `realtime` set the original timing is kept, otherwise everything is delivered as fast as the
+/// slot takes it.
+///
+/// Whatever the slot sends comes out of the returned receiver, to compare against the capture's
+/// `Out` records. Slots it tries to create are never answered, so don't replay code that does.
+/// Once the capture runs out the slot is told the server is shutting down, so a slot that finishes
+/// up sends `ShutdownComplete`.
+pub fn replay_slot<P: AsRef<Path>>(path: P, slot_id: ServerSlotId, realtime: bool) -> io::Result<(ServerSlot, Receiver<SlotOutMsg>)> {
+    let mut capture = try!(CaptureReader::open(path));
+
+    let (slot_out_t, slot_out_r) = channel();
+    let (slot_in_t, slot_in_r) = channel();
+    let (_, create_slot_r) = channel();
+    let slot = ServerSlot::new(slot_id, slot_out_t, slot_in_r, create_slot_r);
+
+    thread::spawn(move || {
+        let start = time::precise_time_ns();
+
+        loop {
+            let record =
+                match capture.next_record() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => { break; },
+                    Err(e) => {
+                        println!("WARNING: Replay stopped early, capture is damaged: {}", e);
+                        break;
+                    },
+                };
+
+            if record.slot_id != slot_id {
+                continue;
+            }
+
+            if realtime {
+                let elapsed_ms = (time::precise_time_ns() - start) / 1000000;
+                if record.time_ms > elapsed_ms {
+                    thread::sleep_ms((record.time_ms - elapsed_ms) as u32);
+                }
+            }
+
+            let msg =
+                match record.kind {
+                    CaptureKind::Joined => SlotInMsg::Joined(record.client_id),
+                    CaptureKind::Disconnected => SlotInMsg::Disconnected(record.client_id),
+                    CaptureKind::In => SlotInMsg::ReceivedPacket(record.client_id, InPacket::new(record.data)),
+                    CaptureKind::Out => { continue; },
+                };
+
+            if slot_in_t.send(msg).is_err() {
+                return;
+            }
+        }
+
+        println!("Replay of slot {} finished", slot_id);
+        slot_in_t.send(SlotInMsg::ShuttingDown);
+    });
+
+    Ok((slot, slot_out_r))
+}
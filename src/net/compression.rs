@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use super::framing::{FrameKind, write_frame};
+
+// Packets smaller than this aren't worth the CPU, and often come out bigger compressed
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// Running totals for packets sent over connections that negotiated compression
+pub struct CompressionStats {
+    packets_sent: AtomicUsize,
+    packets_compressed: AtomicUsize,
+    bytes_before: AtomicUsize,  // Size of the packets as built
+    bytes_after: AtomicUsize,   // Size of the packets as sent
+}
+
+impl CompressionStats {
+    pub fn new() -> CompressionStats {
+        CompressionStats {
+            packets_sent: AtomicUsize::new(0),
+            packets_compressed: AtomicUsize::new(0),
+            bytes_before: AtomicUsize::new(0),
+            bytes_after: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn packets_sent(&self) -> usize {
+        self.packets_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn packets_compressed(&self) -> usize {
+        self.packets_compressed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_before(&self) -> usize {
+        self.bytes_before.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_after(&self) -> usize {
+        self.bytes_after.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent per byte of packet data, so lower is better. 1.0 if nothing was sent.
+    pub fn ratio(&self) -> f64 {
+        let before = self.bytes_before();
+        if before == 0 {
+            1.0
+        } else {
+            self.bytes_after() as f64 / before as f64
+        }
+    }
+
+    fn count(&self, before: usize, after: usize, compressed: bool) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        if compressed {
+            self.packets_compressed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_before.fetch_add(before, Ordering::Relaxed);
+        self.bytes_after.fetch_add(after, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} packets compressed, {} bytes sent for {} bytes of packets ({:.1}%)",
+               self.packets_compressed(), self.packets_sent(),
+               self.bytes_after(), self.bytes_before(), self.ratio() * 100.0)
+    }
+}
+
+/// Writes a packet frame, compressing it if the connection negotiated compression and the packet
+/// is big enough to be worth it. Falls back to a plain packet frame if compression doesn't help.
+pub fn write_packet_frame<W: Write>(writer: &mut W, data: &[u8], compress: bool, stats: &CompressionStats) -> io::Result<()> {
+    if compress && data.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), Compression::Default);
+        try!(encoder.write_all(data));
+        let compressed = try!(encoder.finish());
+
+        if compressed.len() < data.len() {
+            stats.count(data.len(), compressed.len(), true);
+            return write_frame(writer, FrameKind::CompressedPacket, &compressed);
+        }
+    }
+
+    if compress {
+        stats.count(data.len(), data.len(), false);
+    }
+    write_frame(writer, FrameKind::Packet, data)
+}
+
+/// Inflates a compressed packet frame. Packets that inflate past `max_size` are refused, the same
+/// as uncompressed ones would be.
+pub fn decompress(data: &[u8], max_size: u64) -> io::Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut packet = vec!();
+    try!(DeflateDecoder::new(data).take(max_size + 1).read_to_end(&mut packet));
+    if packet.len() as u64 > max_size {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Compressed message inflates past limit of {} bytes", max_size)));
+    }
+
+    Ok(packet)
+}
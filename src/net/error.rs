@@ -0,0 +1,43 @@
+use std::convert::From;
+use std::fmt;
+use std::io;
+
+use super::HandshakeRejection;
+
+pub type NetResult<T> = Result<T, NetError>;
+
+/// Everything that can go wrong talking to the other end of a connection
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),                  // The socket failed
+    Rejected(HandshakeRejection),   // The server refused us during the handshake
+    Disconnected,                   // The connection is gone and no more packets will arrive
+    ServerShutdown(String),         // The server shut down, with the reason it gave
+    Kicked(String),                 // The server dropped us on purpose, with the reason it gave
+    Tls(String),                    // TLS setup failed or the server's certificate isn't trusted
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> NetError {
+        NetError::Io(e)
+    }
+}
+
+impl From<HandshakeRejection> for NetError {
+    fn from(rejection: HandshakeRejection) -> NetError {
+        NetError::Rejected(rejection)
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetError::Io(ref e) => write!(f, "Connection error: {}", e),
+            NetError::Rejected(ref rejection) => write!(f, "{}", rejection),
+            NetError::Disconnected => write!(f, "Lost connection to server"),
+            NetError::ServerShutdown(ref reason) => write!(f, "Server shut down: {}", reason),
+            NetError::Kicked(ref reason) => write!(f, "Kicked from server: {}", reason),
+            NetError::Tls(ref reason) => write!(f, "Secure connection failed: {}", reason),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::io::{Read, Write};
+
+use super::wire::{read_u8, write_u8, read_u32, write_u32};
+
+// Largest message accepted unless a connection is configured otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+// A u64 never takes more than 10 bytes as a varint
+const MAX_VARINT_BYTES: usize = 10;
+
+// Frames are a varint byte length, a one byte frame kind, then the message data. The length counts
+// only the message data and is LEB128: 7 bits per byte, least significant group first, with the
+// high bit set on every byte except the last.
+
+/// What a frame carries. Only packet frames are ever seen by slots; the rest are handled by net.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FrameKind {
+    Packet,
+    Ping,   // Data is a u32 sequence number the other side should echo back
+    Pong,   // Echo of a ping's sequence number
+    Shutdown,   // Server is going away. Data is the UTF-8 reason to show the player.
+    CompressedPacket,   // Deflated packet, only sent if both sides negotiated compression
+    Kicked,     // Server dropped this client on purpose. Data is the UTF-8 reason to show the player.
+}
+
+impl FrameKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            FrameKind::Packet => 0,
+            FrameKind::Ping => 1,
+            FrameKind::Pong => 2,
+            FrameKind::Shutdown => 3,
+            FrameKind::CompressedPacket => 4,
+            FrameKind::Kicked => 5,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<FrameKind> {
+        match kind {
+            0 => Some(FrameKind::Packet),
+            1 => Some(FrameKind::Ping),
+            2 => Some(FrameKind::Pong),
+            3 => Some(FrameKind::Shutdown),
+            4 => Some(FrameKind::CompressedPacket),
+            5 => Some(FrameKind::Kicked),
+            _ => None,
+        }
+    }
+}
+
+pub fn write_varint<T: Write>(writer: &mut T, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            try!(write_u8(writer, byte));
+            return Ok(());
+        } else {
+            try!(write_u8(writer, byte | 0x80));
+        }
+    }
+}
+
+pub fn read_varint<T: Read>(reader: &mut T) -> io::Result<u64> {
+    use std::io::{Error, ErrorKind};
+
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_BYTES {
+        let byte = try!(read_u8(reader));
+        value |= ((byte & 0x7F) as u64) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::new(ErrorKind::InvalidInput, "Varint is too long"))
+}
+
+/// Writes one framed message. The whole frame goes to the writer in one call, so transports that
+/// carry messages rather than a byte stream keep each frame in one message.
+pub fn write_frame<T: Write>(writer: &mut T, kind: FrameKind, data: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(data.len() + MAX_VARINT_BYTES + 1);
+    try!(write_varint(&mut frame, data.len() as u64));
+    try!(write_u8(&mut frame, kind.to_u8()));
+    frame.extend(data.iter().cloned());
+    writer.write_all(&frame)
+}
+
+/// Reads one framed message. Frames longer than `max_size` are refused before any of the data is
+/// read, and the buffer only grows as data actually arrives, so a peer can't make us allocate
+/// whatever it likes by claiming a big frame and stalling.
+pub fn read_frame<T: Read>(reader: &mut T, max_size: u64) -> io::Result<(FrameKind, Vec<u8>)> {
+    use std::io::{Error, ErrorKind};
+
+    let size = try!(read_varint(reader));
+    if size > max_size {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Message of {} bytes exceeds limit of {} bytes", size, max_size)));
+    }
+
+    let kind =
+        match FrameKind::from_u8(try!(read_u8(reader))) {
+            Some(kind) => kind,
+            None => { return Err(Error::new(ErrorKind::InvalidInput, "Unknown frame kind")); },
+        };
+
+    let mut data = vec!();
+    let bytes_read = try!(reader.take(size).read_to_end(&mut data));
+    if bytes_read as u64 != size {
+        return Err(Error::new(ErrorKind::Other,
+                              format!("Expected {} bytes, got {} bytes", size, bytes_read)));
+    }
+
+    Ok((kind, data))
+}
+
+/// Writes a ping or pong frame carrying a sequence number
+pub fn write_heartbeat_frame<T: Write>(writer: &mut T, kind: FrameKind, seq: u32) -> io::Result<()> {
+    let mut data = vec!();
+    try!(write_u32(&mut data, seq));
+    write_frame(writer, kind, &data)
+}
+
+/// Reads the sequence number out of a ping or pong frame's data
+pub fn read_heartbeat_seq(mut data: &[u8]) -> io::Result<u32> {
+    read_u32(&mut data)
+}
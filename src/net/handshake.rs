@@ -0,0 +1,339 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use super::ClientId;
+use super::wire::{
+    read_full,
+    read_u8, write_u8,
+    read_u32, write_u32,
+    read_u64, write_u64,
+    read_string, write_string,
+};
+
+// Bump this whenever a change to the packet types would make older builds misparse packets
+pub const PROTOCOL_VERSION: u32 = 17;
+
+// First bytes of every client hello, so stray connections are rejected before anything is parsed
+const HANDSHAKE_MAGIC: [u8; 4] = [b'R', b'F', b'R', b'G'];
+
+// Tags for the server's reply to a client hello
+const HANDSHAKE_ACCEPTED: u8 = 0;
+const HANDSHAKE_REJECTED: u8 = 1;
+
+/// Identifies the build a binary came from. Set REFORGE_BUILD_ID at compile time to override.
+pub fn build_id() -> &'static str {
+    match option_env!("REFORGE_BUILD_ID") {
+        Some(id) => id,
+        None => env!("CARGO_PKG_VERSION"),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Capabilities
+
+/// Set of optional protocol features a peer supports
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Capabilities(pub u32);
+
+// Peer accepts deflated packet frames
+pub const CAP_COMPRESSION: Capabilities = Capabilities(0x1);
+
+// Every capability flag above. Add new ones here so `Capabilities::all` advertises them.
+const KNOWN_CAPABILITIES: [Capabilities; 1] = [CAP_COMPRESSION];
+
+// How long a new connection gets to finish its handshake before the server gives up on it
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 10000;
+
+impl Capabilities {
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Every capability this build knows how to speak
+    pub fn all() -> Capabilities {
+        KNOWN_CAPABILITIES.iter().fold(Capabilities::empty(), |all, cap| Capabilities(all.0 | cap.0))
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersect(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn difference(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub fn union(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Rejection
+
+/// Reason the server refused a connection during the handshake
+#[derive(Clone, PartialEq, Debug)]
+pub enum HandshakeRejection {
+    BadMagic,                                           // Peer isn't speaking the reforge protocol at all
+    ProtocolMismatch { server: u32, client: u32 },      // Protocol versions differ
+    BuildMismatch { server: String, client: String },   // Server requires matching builds and they differ
+    MissingCapabilities(Capabilities),                  // Client lacks capabilities the server requires
+}
+
+impl HandshakeRejection {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match *self {
+            HandshakeRejection::BadMagic => {
+                try!(write_u8(writer, 0));
+            },
+            HandshakeRejection::ProtocolMismatch { server, client } => {
+                try!(write_u8(writer, 1));
+                try!(write_u32(writer, server));
+                try!(write_u32(writer, client));
+            },
+            HandshakeRejection::BuildMismatch { ref server, ref client } => {
+                try!(write_u8(writer, 2));
+                try!(write_string(writer, server));
+                try!(write_string(writer, client));
+            },
+            HandshakeRejection::MissingCapabilities(caps) => {
+                try!(write_u8(writer, 3));
+                try!(write_u32(writer, caps.0));
+            },
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<HandshakeRejection> {
+        use std::io::{Error, ErrorKind};
+
+        match try!(read_u8(reader)) {
+            0 => Ok(HandshakeRejection::BadMagic),
+            1 => {
+                let server = try!(read_u32(reader));
+                let client = try!(read_u32(reader));
+                Ok(HandshakeRejection::ProtocolMismatch { server: server, client: client })
+            },
+            2 => {
+                let server = try!(read_string(reader));
+                let client = try!(read_string(reader));
+                Ok(HandshakeRejection::BuildMismatch { server: server, client: client })
+            },
+            3 => Ok(HandshakeRejection::MissingCapabilities(Capabilities(try!(read_u32(reader))))),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Unknown handshake rejection")),
+        }
+    }
+}
+
+impl fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeRejection::BadMagic =>
+                write!(f, "Not a reforge server"),
+            HandshakeRejection::ProtocolMismatch { server, client } =>
+                write!(f, "Version mismatch: server v{}, client v{}", server, client),
+            HandshakeRejection::BuildMismatch { ref server, ref client } =>
+                write!(f, "Build mismatch: server {}, client {}", server, client),
+            HandshakeRejection::MissingCapabilities(caps) =>
+                write!(f, "Client missing required features ({:#x})", caps.0),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Sessions
+
+/// Secret the server gives each client so it can prove who it was after reconnecting
+pub type ResumeToken = u64;
+
+/// Asks the server to move a dropped client's session onto a new connection
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ResumeRequest {
+    pub client_id: ClientId,
+    pub token: ResumeToken,
+}
+
+/// What an accepted client is told about its session
+#[derive(Copy, Clone, Debug)]
+pub struct HandshakeAccepted {
+    pub client_id: ClientId,
+    pub capabilities: Capabilities,
+    pub resume_token: ResumeToken,  // Needed to resume this session later
+    pub resumed: bool,              // Whether the session asked for was resumed. If not, this is a new client.
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Client hello
+
+/// First message on every connection, sent from client to server
+pub struct ClientHello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    pub capabilities: Capabilities,
+    pub resume: Option<ResumeRequest>,
+}
+
+impl ClientHello {
+    /// Hello describing this build
+    pub fn new() -> ClientHello {
+        ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: build_id().to_string(),
+            capabilities: Capabilities::all(),
+            resume: None,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writer.write_all(&HANDSHAKE_MAGIC));
+        try!(write_u32(writer, self.protocol_version));
+        try!(write_string(writer, &self.build_id));
+        try!(write_u32(writer, self.capabilities.0));
+        match self.resume {
+            Some(resume) => {
+                try!(write_u8(writer, 1));
+                try!(write_u32(writer, resume.client_id));
+                try!(write_u64(writer, resume.token));
+            },
+            None => { try!(write_u8(writer, 0)); },
+        }
+        Ok(())
+    }
+
+    /// Reads a hello. A peer that doesn't start with the handshake magic gets `BadMagic`.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Result<ClientHello, HandshakeRejection>> {
+        let mut magic = [0u8; 4];
+        try!(read_full(reader, &mut magic));
+        if magic != HANDSHAKE_MAGIC {
+            return Ok(Err(HandshakeRejection::BadMagic));
+        }
+
+        let protocol_version = try!(read_u32(reader));
+        let build_id = try!(read_string(reader));
+        let capabilities = Capabilities(try!(read_u32(reader)));
+        let resume =
+            match try!(read_u8(reader)) {
+                0 => None,
+                _ => {
+                    let client_id = try!(read_u32(reader));
+                    let token = try!(read_u64(reader));
+                    Some(ResumeRequest { client_id: client_id, token: token })
+                },
+            };
+
+        Ok(Ok(ClientHello {
+            protocol_version: protocol_version,
+            build_id: build_id,
+            capabilities: capabilities,
+            resume: resume,
+        }))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Policy
+
+/// Server-side rules for which clients may connect
+#[derive(Clone)]
+pub struct HandshakePolicy {
+    // Reject clients whose build ID differs from the server's, not just the protocol version
+    pub require_matching_build: bool,
+
+    // Capabilities every client must support
+    pub required_capabilities: Capabilities,
+
+    // Capabilities the server is willing to use. Clients get whichever of these they support.
+    pub offered_capabilities: Capabilities,
+}
+
+impl HandshakePolicy {
+    pub fn new() -> HandshakePolicy {
+        HandshakePolicy {
+            require_matching_build: false,
+            required_capabilities: Capabilities::empty(),
+            offered_capabilities: Capabilities::all(),
+        }
+    }
+
+    /// Checks a client's hello, returning the negotiated capabilities if it's accepted
+    pub fn check(&self, hello: &ClientHello) -> Result<Capabilities, HandshakeRejection> {
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(HandshakeRejection::ProtocolMismatch { server: PROTOCOL_VERSION, client: hello.protocol_version });
+        }
+
+        if self.require_matching_build && hello.build_id != build_id() {
+            return Err(HandshakeRejection::BuildMismatch { server: build_id().to_string(), client: hello.build_id.clone() });
+        }
+
+        if !hello.capabilities.contains(self.required_capabilities) {
+            return Err(HandshakeRejection::MissingCapabilities(self.required_capabilities.difference(hello.capabilities)));
+        }
+
+        Ok(self.offered_capabilities.intersect(hello.capabilities))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Handshake procedures
+
+/// Server side of the handshake, up to the point where a client ID is needed. Returns the
+/// negotiated capabilities and the session the client wants to resume, if any. Rejected clients
+/// are told why before the rejection is returned.
+pub fn server_handshake<S: Read + Write>(stream: &mut S, policy: &HandshakePolicy) -> io::Result<Result<(Capabilities, Option<ResumeRequest>), HandshakeRejection>> {
+    let result =
+        match try!(ClientHello::read_from(stream)) {
+            Ok(hello) => policy.check(&hello).map(|capabilities| (capabilities, hello.resume)),
+            Err(rejection) => Err(rejection),
+        };
+
+    if let Err(ref rejection) = result {
+        try!(write_u8(stream, HANDSHAKE_REJECTED));
+        try!(rejection.write_to(stream));
+    }
+
+    Ok(result)
+}
+
+/// Finishes an accepted handshake by telling the client about its session
+pub fn server_accept<W: Write>(writer: &mut W, accepted: &HandshakeAccepted) -> io::Result<()> {
+    try!(write_u8(writer, HANDSHAKE_ACCEPTED));
+    try!(write_u32(writer, accepted.capabilities.0));
+    try!(write_u32(writer, accepted.client_id));
+    try!(write_u64(writer, accepted.resume_token));
+    try!(write_u8(writer, accepted.resumed as u8));
+    Ok(())
+}
+
+/// Client side of the handshake, optionally asking to resume an earlier session
+pub fn client_handshake<S: Read + Write>(stream: &mut S, resume: Option<ResumeRequest>) -> io::Result<Result<HandshakeAccepted, HandshakeRejection>> {
+    use std::io::{Error, ErrorKind};
+
+    let mut hello = ClientHello::new();
+    hello.resume = resume;
+    try!(hello.write_to(stream));
+
+    match try!(read_u8(stream)) {
+        HANDSHAKE_ACCEPTED => {
+            let capabilities = Capabilities(try!(read_u32(stream)));
+            let client_id = try!(read_u32(stream));
+            let resume_token = try!(read_u64(stream));
+            let resumed = try!(read_u8(stream)) != 0;
+            Ok(Ok(HandshakeAccepted {
+                client_id: client_id,
+                capabilities: capabilities,
+                resume_token: resume_token,
+                resumed: resumed,
+            }))
+        },
+        HANDSHAKE_REJECTED => Ok(Err(try!(HandshakeRejection::read_from(stream)))),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "Malformed handshake reply")),
+    }
+}
@@ -0,0 +1,316 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use super::transport::{Connection, Listener};
+
+// In-process transport. Each end of a loopback connection has an inbox of bytes written by the
+// other end, which its reads wait on.
+
+/// Creates a loopback listener for `Server::listen_on` and a connector clients can use to reach it
+pub fn loopback() -> (LoopbackListener, LoopbackConnector) {
+    let (sender, receiver) = channel();
+    (LoopbackListener { incoming: receiver }, LoopbackConnector { sender: sender })
+}
+
+/// Creates a pair of connected loopback streams
+pub fn loopback_pair() -> (LoopbackStream, LoopbackStream) {
+    let a_inbox = Arc::new(Inbox::new());
+    let b_inbox = Arc::new(Inbox::new());
+
+    let a = LoopbackStream::new(a_inbox.clone(), b_inbox.clone());
+    let b = LoopbackStream::new(b_inbox, a_inbox);
+    (a, b)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct LoopbackListener {
+    incoming: Receiver<LoopbackStream>,
+}
+
+impl Listener for LoopbackListener {
+    fn accept(&mut self) -> io::Result<Box<Connection>> {
+        use std::io::{Error, ErrorKind};
+
+        match self.incoming.recv() {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(_) => Err(Error::new(ErrorKind::NotConnected, "All loopback connectors are gone")),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    sender: Sender<LoopbackStream>,
+}
+
+impl LoopbackConnector {
+    /// Opens a new connection to the listener. Pass the result to `Client::from_connection`.
+    pub fn connect(&self) -> io::Result<LoopbackStream> {
+        use std::io::{Error, ErrorKind};
+
+        let (server_end, client_end) = loopback_pair();
+        match self.sender.send(server_end) {
+            Ok(_) => Ok(client_end),
+            Err(_) => Err(Error::new(ErrorKind::ConnectionRefused, "Loopback listener is gone")),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Bytes written to one end that it hasn't read yet
+struct Inbox {
+    received: Mutex<Received>,
+    arrived: Condvar,
+}
+
+struct Received {
+    data: VecDeque<u8>,
+    closed: bool,                       // The other end hung up or this end shut down, nothing more will arrive
+    read_timeout: Option<Duration>,     // See `Connection::set_read_timeout`
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        Inbox {
+            received: Mutex::new(Received { data: VecDeque::new(), closed: false, read_timeout: None }),
+            arrived: Condvar::new(),
+        }
+    }
+
+    fn close(&self) {
+        self.received.lock().unwrap().closed = true;
+        self.arrived.notify_all();
+    }
+}
+
+// State shared by every handle to one end of a loopback connection
+struct LoopbackEnd {
+    inbox: Arc<Inbox>,
+    peer_inbox: Arc<Inbox>,
+}
+
+impl Drop for LoopbackEnd {
+    fn drop(&mut self) {
+        // Last handle to this end is gone, so the other end sees end of stream
+        self.peer_inbox.close();
+    }
+}
+
+/// One end of an in-memory connection
+pub struct LoopbackStream {
+    end: Arc<LoopbackEnd>,
+}
+
+impl LoopbackStream {
+    fn new(inbox: Arc<Inbox>, peer_inbox: Arc<Inbox>) -> LoopbackStream {
+        LoopbackStream {
+            end: Arc::new(LoopbackEnd {
+                inbox: inbox,
+                peer_inbox: peer_inbox,
+            }),
+        }
+    }
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let ref inbox = self.end.inbox;
+        let mut received = inbox.received.lock().unwrap();
+        while received.data.is_empty() && !received.closed {
+            received =
+                match received.read_timeout {
+                    Some(timeout) => {
+                        let (received, result) = inbox.arrived.wait_timeout(received, timeout).unwrap();
+                        if result.timed_out() && received.data.is_empty() && !received.closed {
+                            return Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for the loopback peer"));
+                        }
+                        received
+                    },
+                    None => inbox.arrived.wait(received).unwrap(),
+                };
+        }
+
+        let bytes = cmp::min(buf.len(), received.data.len());
+        for i in 0..bytes {
+            buf[i] = received.data.pop_front().unwrap();
+        }
+
+        Ok(bytes)
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let ref peer_inbox = self.end.peer_inbox;
+        let mut received = peer_inbox.received.lock().unwrap();
+        if received.closed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Loopback peer hung up"));
+        }
+
+        received.data.extend(buf.iter().cloned());
+        peer_inbox.arrived.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for LoopbackStream {
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(LoopbackStream { end: self.end.clone() }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.end.peer_inbox.close();
+        self.end.inbox.close();
+        Ok(())
+    }
+
+    fn peer_name(&self) -> String {
+        "loopback".to_string()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.end.inbox.received.lock().unwrap().read_timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read};
+    use std::sync::mpsc::channel;
+    use std::thread::{spawn, JoinHandle};
+    use std::time::Duration;
+
+    use client_action::ClientAction;
+    use login::{self, LoginError, LoginPacket, MemoryAccountStore};
+    use net::{Client, Connection, NetError, OutPacket, Server, ShutdownHandle};
+    use packet_types::ClientStationPacket;
+    use sector_data::{SectorData, SectorId};
+    use ship::{Ship, ShipStored};
+    use star_map::{GameTiming, StarMapServer};
+    use star_map::station::StationAction;
+    use super::{loopback, loopback_pair};
+
+    // Starts the whole server the way the client's local server does, but reachable over loopback
+    // and with jumps and turns short enough to play through quickly
+    fn start_server() -> (Client, ShutdownHandle, JoinHandle<()>) {
+        let mut server = Server::new();
+        let shutdown = server.shutdown_handle();
+        let login_slot = server.create_slot();
+        let star_map_slot = server.create_slot();
+        let star_map_slot_id = star_map_slot.get_id();
+        let (star_map_account_sender, star_map_account_receiver) = channel();
+        let (logout_sender, logout_receiver) = channel();
+        let (mod_sender, mod_receiver) = channel();
+        let (chat_mute_sender, chat_mute_receiver) = channel();
+        let (account_save_sender, account_save_receiver) = channel();
+        let (_, sector_close_receiver) = channel();
+
+        let timing = GameTiming {
+            jump_ms: 0,
+            simulate_turn_ms: 50,
+            turn_length_ms: 100,
+        };
+
+        let (listener, connector) = loopback();
+        let server_master = spawn(move || {
+            server.listen_on(Box::new(listener));
+        });
+        spawn(move || {
+            login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender, logout_receiver, Box::new(MemoryAccountStore),
+                                    mod_receiver, chat_mute_sender, account_save_receiver);
+        });
+        spawn(move || {
+            let mut star_map_server = StarMapServer::with_timing(star_map_slot, mod_sender, chat_mute_receiver, account_save_sender, timing);
+            star_map_server.run(star_map_account_receiver, logout_sender, sector_close_receiver);
+        });
+
+        let stream = connector.connect().ok().expect("Failed to connect over loopback");
+        let client = Client::from_connection(Box::new(stream)).ok().expect("Handshake failed");
+        (client, shutdown, server_master)
+    }
+
+    // A new player registers, lands at the station, then jumps into a battle sector
+    #[test]
+    fn login_to_sector() {
+        let (mut client, shutdown, server_master) = start_server();
+
+        let mut packet = OutPacket::new();
+        packet.write(&LoginPacket::Register("pilot".to_string(), "hunter22".to_string())).unwrap();
+        client.send(&packet).unwrap();
+        let login_result: Option<LoginError> = client.receive().unwrap().read().unwrap();
+        assert!(login_result.is_none());
+
+        // Star map
+        let sectors: Vec<SectorData> = client.receive().unwrap().read().unwrap();
+        assert_eq!(sectors.len(), 3);
+        match client.receive().unwrap().read().unwrap() {
+            ClientAction::JoinStation => { },
+            _ => panic!("New accounts should start at the station"),
+        }
+
+        // Station. What else it sends on arrival isn't what this test is about.
+        let ship: Option<ShipStored> = client.receive().unwrap().read().unwrap();
+        assert!(ship.is_some());
+        let _: ClientStationPacket = client.receive().unwrap().read().unwrap();
+        let _: ClientStationPacket = client.receive().unwrap().read().unwrap();
+
+        let mut packet = OutPacket::new();
+        packet.write(&StationAction::Jump(SectorId(1))).unwrap();
+        client.send(&packet).unwrap();
+
+        // Sector, once the jump's done
+        match client.receive().unwrap().read().unwrap() {
+            ClientAction::JoinSector => { },
+            _ => panic!("Expected to join the sector jumped to"),
+        }
+        let mut packet = client.receive().unwrap();
+        let my_ship: Ship = packet.read().unwrap();
+        assert_eq!(my_ship.name, "pilot");
+
+        // Shutting down logs the player out at the end of the turn and tells them why
+        shutdown.shutdown("Test over");
+        loop {
+            match client.receive() {
+                Ok(_) => { },
+                Err(NetError::ServerShutdown(reason)) => {
+                    assert_eq!(reason, "Test over");
+                    break;
+                },
+                Err(e) => panic!("Expected the shutdown notice, got: {}", e),
+            }
+        }
+        server_master.join().ok().expect("Server master panicked");
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (mut a, b) = loopback_pair();
+        a.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        let mut buf = [0u8; 1];
+        match a.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => { },
+            _ => panic!("Read should have timed out"),
+        }
+
+        // Hanging up still ends the stream with a timeout set
+        drop(b);
+        assert_eq!(a.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,1684 @@
+pub use self::capture::{replay_slot, CaptureKind, CaptureReader, CaptureRecord, CaptureWriter};
+pub use self::compression::{CompressionStats, COMPRESSION_THRESHOLD};
+pub use self::error::{NetError, NetResult};
+pub use self::framing::DEFAULT_MAX_MESSAGE_SIZE;
+pub use self::handshake::{
+    CAP_COMPRESSION,
+    Capabilities,
+    ClientHello,
+    HandshakeAccepted,
+    HandshakePolicy,
+    HandshakeRejection,
+    PROTOCOL_VERSION,
+    ResumeRequest,
+    ResumeToken,
+    build_id,
+};
+pub use self::loopback::{loopback, loopback_pair, LoopbackConnector, LoopbackListener, LoopbackStream};
+pub use self::rate_limit::{Penalty, RateLimits};
+pub use self::stats::{ClientStats, NetStats, SlotStats, Traffic};
+#[cfg(feature = "tls")]
+pub use self::tls::{TlsClientConfig, TlsListener, TlsServerConfig, TlsStream, TlsTrust};
+pub use self::transport::{Connection, Listener};
+pub use self::websocket::{WebSocketListener, WebSocketStream};
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{Builder, JoinHandle, sleep_ms, spawn};
+use std::time::Duration as StdDuration;
+use time;
+
+use crypto::util::fixed_time_eq;
+use rand::{OsRng, Rng};
+use rustc_serialize::Encodable;
+use rustc_serialize::Decodable;
+
+use bincode::{EncoderWriter, EncodingError, DecoderReader, DecodingError, encode_into, decode_from, SizeLimit};
+
+use event_mux::EventMux;
+
+use self::compression::{decompress, write_packet_frame};
+use self::framing::{FrameKind, read_frame, read_heartbeat_seq, write_frame, write_heartbeat_frame};
+use self::handshake::{client_handshake, server_accept, server_handshake, HANDSHAKE_TIMEOUT_MS};
+use self::rate_limit::{RateLimiter, Verdict};
+use self::transport::accept_backoff_ms;
+
+mod capture;
+mod compression;
+mod error;
+mod framing;
+mod handshake;
+mod loopback;
+mod rate_limit;
+mod stats;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+mod websocket;
+mod wire;
+
+pub mod protocol;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Some basic types
+
+pub type ClientId = u32;
+
+pub type ServerSlotId = u32;
+
+// Default time between pings sent to each client
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: i64 = 5000;
+
+// Default time a client can go without sending anything before it's disconnected
+pub const DEFAULT_IDLE_TIMEOUT_MS: i64 = 30000;
+
+// Default time a client that lost its connection has to reconnect and resume where it left off
+pub const DEFAULT_RESUME_GRACE_MS: i64 = 60000;
+
+// Default time slots get to finish up once the server starts shutting down
+pub const DEFAULT_SHUTDOWN_GRACE_MS: u32 = 15000;
+
+// Time clients' connections get to send the shutdown notice before the server exits without them
+const DISCONNECT_TIMEOUT_MS: u32 = 5000;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server Slot
+
+// Messages incoming to slots
+pub enum SlotInMsg {
+    Joined(ClientId),                   // Client joined slot (client_id)
+    Disconnected(ClientId),             // Client was disconnected from server (client_id)
+    ReceivedPacket(ClientId, InPacket), // Received packet from client (client_id, packet)
+    Latency(ClientId, u32),             // Measured round trip time to client (client_id, milliseconds)
+    SlotDestroyed(ServerSlotId),        // A slot this slot created was destroyed (slot_id)
+    ShuttingDown,                       // Server is shutting down. Hand back everything, then call `shutdown_complete`.
+    RateLimited(ClientId, Penalty),     // Client is sending too much (client_id, what was done about it)
+    Resumed(ClientId),                  // Client reconnected after losing its connection. Packets sent meanwhile were lost. (client_id)
+    NetStats(NetStats),                 // Answer to `ServerSlot::query_stats`
+}
+
+// Messages outgoing from slots
+pub enum SlotOutMsg {
+    SendPacket(ServerSlotId, ClientId, OutPacket),        // Send a packet to a client (my_slot_id, client_id, packet)
+    BroadcastPacket(ServerSlotId, OutPacket),             // Send packet to all clients in slot (my_slot_id, packet)
+    CreateSlot(ServerSlotId),                             // Tell the server to make a new ServerSlot (slot_id)
+    TransferClient(ServerSlotId, ClientId, ServerSlotId), // Tell the server to transfer a client to a different slot
+    DestroySlot(ServerSlotId, ServerSlotId, Option<ServerSlotId>), // Tear down a slot (my_slot_id, slot_id, slot to move its clients to or None to disconnect them)
+    ShutdownComplete(ServerSlotId),                       // Slot has nothing left to flush for shutdown (my_slot_id)
+    QueryStats(ServerSlotId),                             // Ask for traffic stats on every client and slot (my_slot_id)
+    Kick(ServerSlotId, ClientId, String),                 // Drop a client from whatever slot it's in (my_slot_id, client_id, reason)
+}
+
+pub struct ServerSlot {
+    id: ServerSlotId,
+    sender: Sender<SlotOutMsg>,
+    receiver: Receiver<SlotInMsg>,
+    
+    // When this server slot requests to make a new slot, the new slot will come on this channel.
+    create_slot: Receiver<ServerSlot>,
+}
+
+impl ServerSlot {
+    fn new(id: ServerSlotId, sender: Sender<SlotOutMsg>, receiver: Receiver<SlotInMsg>, create_slot: Receiver<ServerSlot>) -> ServerSlot {
+        ServerSlot{id: id, sender: sender, receiver: receiver, create_slot: create_slot}
+    }
+    
+    pub fn send(&self, client_id: ClientId, packet: OutPacket) {
+        self.sender.send(SlotOutMsg::SendPacket(self.id, client_id, packet));
+    }
+    
+    pub fn broadcast(&self, packet: OutPacket) {
+        self.sender.send(SlotOutMsg::BroadcastPacket(self.id, packet));
+    }
+    
+    pub fn receive(&self) -> SlotInMsg {
+        match self.receiver.recv() {
+            Ok(msg) => msg,
+            _ => panic!("Failed to receive SlotInMsg"),
+        }
+    }
+    
+    pub fn try_receive(&self) -> Result<SlotInMsg, TryRecvError> {
+        self.receiver.try_recv()
+    }
+    
+    /// Delivers incoming messages to `events`, wrapped by `wrap`, so the slot can block on them
+    /// along with its other channels. `receive` and `try_receive` can't be used afterwards.
+    pub fn forward_incoming<E, F>(&mut self, events: &EventMux<E>, wrap: F)
+        where E: Send + 'static, F: Fn(SlotInMsg) -> E + Send + 'static
+    {
+        use std::mem;
+        
+        let (_, dead_receiver) = channel();
+        let receiver = mem::replace(&mut self.receiver, dead_receiver);
+        events.forward(receiver, wrap);
+    }
+    
+    pub fn create_slot(&self) -> ServerSlot {
+        self.sender.send(SlotOutMsg::CreateSlot(self.id));
+        match self.create_slot.recv() {
+            Ok(slot) => slot,
+            _ => panic!("Failed to receive newly created ServerSlot"),
+        }
+    }
+    
+    // Transfer a client to a different slot
+    pub fn transfer_client(&self, client_id: ClientId, to_slot: ServerSlotId) {
+        self.sender.send(SlotOutMsg::TransferClient(self.id, client_id, to_slot));
+    }
+    
+    /// Destroys a slot this slot created, or this slot itself. Clients still in it are moved to
+    /// `migrate_to`, or disconnected if that's None. The destroyed slot's creator is sent
+    /// `SlotInMsg::SlotDestroyed`.
+    pub fn destroy_slot(&self, slot_id: ServerSlotId, migrate_to: Option<ServerSlotId>) {
+        self.sender.send(SlotOutMsg::DestroySlot(self.id, slot_id, migrate_to));
+    }
+    
+    /// Destroys this slot. See `destroy_slot`.
+    pub fn destroy(self, migrate_to: Option<ServerSlotId>) {
+        self.destroy_slot(self.id, migrate_to);
+    }
+    
+    /// Tells the server this slot has finished flushing after `SlotInMsg::ShuttingDown`. The
+    /// server exits once every slot has done this. Saying it more than once is harmless.
+    pub fn shutdown_complete(&self) {
+        self.sender.send(SlotOutMsg::ShutdownComplete(self.id));
+    }
+    
+    /// Disconnects a client wherever it is, telling it why. It can't resume its session.
+    pub fn kick(&self, client_id: ClientId, reason: String) {
+        self.sender.send(SlotOutMsg::Kick(self.id, client_id, reason));
+    }
+    
+    /// Asks the server master for traffic stats on every client and slot. They arrive as
+    /// `SlotInMsg::NetStats`.
+    pub fn query_stats(&self) {
+        self.sender.send(SlotOutMsg::QueryStats(self.id));
+    }
+    
+    pub fn create_slot_and_transfer_clients(&self, clients: &Vec<ClientId>) -> ServerSlot {
+        let new_slot = self.create_slot();
+        
+        for client_id in clients.iter() {
+            self.transfer_client(*client_id, new_slot.get_id());
+        }
+        
+        new_slot
+    }
+    
+    pub fn get_id(&self) -> ServerSlotId {
+        self.id
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server
+
+pub struct Server {
+    // Server slots. Maps slot ID to communication channels with slot
+    slots: HashMap<ServerSlotId, (Sender<SlotInMsg>, Sender<ServerSlot>)>,
+    
+    // Maps slots created on request of another slot to the slot that created them
+    slot_owners: HashMap<ServerSlotId, ServerSlotId>,
+    
+    // Channel for communication between server master task and slots
+    slot_channel_t: Sender<SlotOutMsg>,
+    
+    // Everything the server master reacts to comes through here: new clients, client input, slot
+    // messages and heartbeat ticks
+    events: EventMux<MasterMsg>,
+    
+    // ID to give to next slot
+    next_slot_id: ServerSlotId,
+    
+    // Rules for which clients are allowed to connect
+    handshake_policy: HandshakePolicy,
+    
+    // Largest packet a client may send. Clients that go over are disconnected.
+    max_message_size: u64,
+    
+    // How often clients are pinged, and how long they can be silent before being disconnected
+    heartbeat_interval: time::Duration,
+    idle_timeout: time::Duration,
+    
+    // How long a client that lost its connection keeps its place before its slot is told it's gone
+    resume_grace: time::Duration,
+    
+    // Number for the next client connection, so messages from a replaced one can be told apart
+    next_connection: u32,
+    
+    // Set once shutdown starts. Clients are sent the reason when the server exits.
+    shutdown_reason: Option<String>,
+    
+    // Slots that haven't finished flushing since shutdown started
+    slots_flushing: HashSet<ServerSlotId>,
+    
+    // How long slots get to flush before the server exits anyway
+    shutdown_grace_ms: u32,
+    
+    // Where to record packets going through the master, if anywhere
+    capture: Option<CaptureWriter>,
+    
+    // Budget each client's packets are held to
+    rate_limits: RateLimits,
+    
+    
+    // Packets through each slot, and when the last one went through
+    slot_activity: HashMap<ServerSlotId, SlotActivity>,
+}
+
+// Traffic through one slot
+struct SlotActivity {
+    traffic: Traffic,
+    last_activity: Option<time::Timespec>,
+}
+
+impl SlotActivity {
+    fn new() -> SlotActivity {
+        SlotActivity {
+            traffic: Traffic::new(),
+            last_activity: None,
+        }
+    }
+}
+
+/// Fetches traffic stats from a running server, from any thread
+#[derive(Clone)]
+pub struct StatsHandle {
+    sender: Sender<MasterMsg>,
+}
+
+impl StatsHandle {
+    /// Returns stats on every client and slot, or None if the server isn't running
+    pub fn query(&self) -> Option<NetStats> {
+        let (reply_t, reply_r) = channel();
+        self.sender.send(MasterMsg::QueryStats(reply_t));
+        reply_r.recv().ok()
+    }
+}
+
+/// Stops a running server from any thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Sender<MasterMsg>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting clients and tells every slot to flush. Once they all have, or the grace
+    /// period runs out, clients are sent `reason` and disconnected, and `Server::listen` returns.
+    pub fn shutdown(&self, reason: &str) {
+        self.sender.send(MasterMsg::Shutdown(reason.to_string()));
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        let (slot_channel_t, slot_channel_r) = channel();
+        
+        let events = EventMux::new();
+        events.forward(slot_channel_r, MasterMsg::FromSlot);
+    
+        Server {
+            slots: HashMap::new(),
+            slot_owners: HashMap::new(),
+            slot_channel_t: slot_channel_t,
+            events: events,
+            next_slot_id: 0,
+            handshake_policy: HandshakePolicy::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            heartbeat_interval: time::Duration::milliseconds(DEFAULT_HEARTBEAT_INTERVAL_MS),
+            idle_timeout: time::Duration::milliseconds(DEFAULT_IDLE_TIMEOUT_MS),
+            resume_grace: time::Duration::milliseconds(DEFAULT_RESUME_GRACE_MS),
+            next_connection: 0,
+            shutdown_reason: None,
+            slots_flushing: HashSet::new(),
+            shutdown_grace_ms: DEFAULT_SHUTDOWN_GRACE_MS,
+            capture: None,
+            rate_limits: RateLimits::new(),
+            slot_activity: HashMap::new(),
+        }
+    }
+    
+    /// Returns a handle that can shut this server down once it's listening
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.events.sender(),
+        }
+    }
+    
+    /// Returns a handle for fetching traffic stats once the server's listening
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            sender: self.events.sender(),
+        }
+    }
+    
+    /// Sets how many packets and bytes each client may send, and how hard the ones that send more
+    /// are dealt with
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.rate_limits = rate_limits;
+    }
+    
+    /// Sets how long slots get to flush during shutdown before the server exits without them
+    pub fn set_shutdown_grace(&mut self, grace_ms: u32) {
+        self.shutdown_grace_ms = grace_ms;
+    }
+    
+    pub fn set_handshake_policy(&mut self, policy: HandshakePolicy) {
+        self.handshake_policy = policy;
+    }
+    
+    /// Sets whether compression is offered to clients. It's on by default; turning it off trades
+    /// bandwidth for CPU.
+    pub fn set_compression(&mut self, enabled: bool) {
+        let policy = &mut self.handshake_policy;
+        policy.offered_capabilities =
+            if enabled {
+                policy.offered_capabilities.union(CAP_COMPRESSION)
+            } else {
+                policy.offered_capabilities.difference(CAP_COMPRESSION)
+            };
+    }
+    
+    /// Sets the largest packet, in bytes, that each client connection may send
+    pub fn set_max_message_size(&mut self, max_message_size: u64) {
+        self.max_message_size = max_message_size;
+    }
+    
+    /// Sets how often clients are pinged and how long a client may go without sending anything,
+    /// pongs included, before it's disconnected
+    pub fn set_heartbeat(&mut self, interval_ms: i64, idle_timeout_ms: i64) {
+        self.heartbeat_interval = time::Duration::milliseconds(interval_ms);
+        self.idle_timeout = time::Duration::milliseconds(idle_timeout_ms);
+    }
+    
+    /// Sets how long a client that loses its connection has to reconnect and resume its session.
+    /// Zero disconnects clients as soon as their connection drops.
+    pub fn set_resume_grace(&mut self, grace_ms: i64) {
+        self.resume_grace = time::Duration::milliseconds(grace_ms);
+    }
+    
+    /// Records every packet between clients and slots, plus clients joining and leaving slots, to
+    /// a capture file. Use `replay_slot` to play one back.
+    pub fn set_capture(&mut self, capture: CaptureWriter) {
+        self.capture = Some(capture);
+    }
+    
+    pub fn create_slot(&mut self) -> ServerSlot {
+        let (slot_in_t, slot_in_r) = channel();
+        let (create_slot_t, create_slot_r) = channel(); // Channel for sending newly created ServerSlots to the slot upon request
+        
+        let slot_id = self.next_slot_id;
+        self.slots.insert(slot_id, (slot_in_t, create_slot_t));
+        self.slot_activity.insert(slot_id, SlotActivity::new());
+        self.next_slot_id += 1;
+    
+        ServerSlot::new(slot_id, self.slot_channel_t.clone(), slot_in_r, create_slot_r)
+    }
+    
+    pub fn listen(&mut self, address: &str) {
+        let listener = 
+            match TcpListener::bind(address) {
+                Ok(listener) => listener,
+                Err(e) => panic!("Server failed to listen on address {}: {}", address, e),
+            };
+        
+        self.listen_on(Box::new(listener));
+    }
+    
+    /// Like `listen`, but clients must connect with TLS
+    #[cfg(feature = "tls")]
+    pub fn listen_tls(&mut self, address: &str, config: &TlsServerConfig) {
+        let listener =
+            match TlsListener::bind(address, config) {
+                Ok(listener) => listener,
+                Err(e) => panic!("Server failed to listen with TLS on address {}: {}", address, e),
+            };
+        
+        self.listen_on(Box::new(listener));
+    }
+    
+    /// Also accepts clients over WebSocket on `address`, on top of whatever `listen` is given.
+    /// WebSocket clients speak the same protocol and are just more clients to the slots. Call
+    /// `set_max_message_size` first, since WebSocket messages are held to it too.
+    pub fn listen_websocket(&mut self, address: &str) {
+        let listener =
+            match WebSocketListener::bind(address, self.max_message_size) {
+                Ok(listener) => listener,
+                Err(e) => panic!("Server failed to listen for WebSockets on address {}: {}", address, e),
+            };
+        
+        self.add_listener(Box::new(listener));
+    }
+    
+    /// Accepts clients from another transport alongside the one the server listens on. Clients
+    /// are only taken in once the server is listening.
+    pub fn add_listener(&mut self, listener: Box<Listener>) {
+        let handshake_policy = self.handshake_policy.clone();
+        let new_client_t = self.events.sender();
+        spawn(move || {
+            client_acceptor(listener, new_client_t, handshake_policy);
+        });
+    }
+    
+    /// Runs the server master, accepting clients from any transport. Blocks until there's
+    /// something to do, so an idle server uses no CPU. Returns once shut down through a
+    /// `ShutdownHandle`.
+    pub fn listen_on(&mut self, listener: Box<Listener>) {
+        // Every connected client
+        let mut clients: HashMap<ClientId, ClientConn> = HashMap::new();
+        
+        // Next ID to give to each client
+        let mut next_client_id = 0;
+        
+        self.add_listener(listener);
+        
+        self.events.schedule(self.heartbeat_interval.num_milliseconds() as u32, MasterMsg::HeartbeatTick);
+        
+        // Manage server slots
+        loop {
+            match self.events.recv() {
+                MasterMsg::NewClient(stream, capabilities, resume) => {
+                    if self.shutdown_reason.is_some() {
+                        // Not taking anyone new
+                        stream.shutdown();
+                        continue;
+                    }
+                    
+                    // Clients that can't be resumed are let in as new ones
+                    let stream =
+                        match resume {
+                            Some(resume) => match self.resume_client(&mut clients, stream, capabilities, resume) {
+                                Some(stream) => stream,
+                                None => { continue; },
+                            },
+                            None => stream,
+                        };
+                    
+                    let client_id = next_client_id;
+                    next_client_id += 1;
+                    
+                    if let Some(client) = self.accept_client(client_id, stream, capabilities) {
+                        clients.insert(client_id, client);
+                    }
+                },
+                MasterMsg::FromClient(client_id, msg) => {
+                    self.handle_client_msg(&mut clients, client_id, msg);
+                },
+                MasterMsg::FromSlot(msg) => {
+                    self.handle_slot_msg(&mut clients, msg);
+                },
+                MasterMsg::HeartbeatTick => {
+                    self.check_heartbeats(&mut clients);
+                    self.events.schedule(self.heartbeat_interval.num_milliseconds() as u32, MasterMsg::HeartbeatTick);
+                },
+                MasterMsg::Shutdown(reason) => {
+                    self.begin_shutdown(reason);
+                },
+                MasterMsg::QueryStats(reply) => {
+                    reply.send(self.net_stats(&clients));
+                },
+                MasterMsg::ShutdownDeadline => {
+                    println!("WARNING: Slots {:?} didn't finish shutting down in time", self.slots_flushing);
+                    self.slots_flushing.clear();
+                },
+                MasterMsg::ClientsDisconnected | MasterMsg::DisconnectDeadline => {},
+            }
+            
+            if self.shutdown_reason.is_some() && self.slots_flushing.is_empty() {
+                break;
+            }
+        }
+        
+        self.disconnect_all(clients);
+    }
+    
+    fn begin_shutdown(&mut self, reason: String) {
+        if self.shutdown_reason.is_some() {
+            return;
+        }
+        
+        println!("Server shutting down: {}", reason);
+        self.shutdown_reason = Some(reason);
+        
+        for (slot_id, &(ref slot_in_t, _)) in self.slots.iter() {
+            slot_in_t.send(SlotInMsg::ShuttingDown);
+            self.slots_flushing.insert(*slot_id);
+        }
+        
+        self.events.schedule(self.shutdown_grace_ms, MasterMsg::ShutdownDeadline);
+    }
+    
+    // Sends every client the shutdown notice and waits a while for it to be written. Connections
+    // that are stuck writing are left behind.
+    fn disconnect_all(&mut self, mut clients: HashMap<ClientId, ClientConn>) {
+        let reason = self.shutdown_reason.clone().unwrap_or(String::new());
+        
+        let mut out_threads = vec!();
+        for (_, client) in clients.drain() {
+            client.out.send(OutFrame::Shutdown(reason.clone()));
+            out_threads.push(client.out_thread);
+        }
+        
+        let disconnected_t = self.events.sender();
+        spawn(move || {
+            for out_thread in out_threads.into_iter() {
+                out_thread.join();
+            }
+            disconnected_t.send(MasterMsg::ClientsDisconnected);
+        });
+        self.events.schedule(DISCONNECT_TIMEOUT_MS, MasterMsg::DisconnectDeadline);
+        
+        loop {
+            match self.events.recv() {
+                MasterMsg::ClientsDisconnected => { break; },
+                MasterMsg::DisconnectDeadline => {
+                    println!("WARNING: Some clients weren't sent the shutdown notice in time");
+                    break;
+                },
+                _ => {},
+            }
+        }
+        
+        println!("Server shut down");
+    }
+    
+    // Finishes the handshake with a new client, starts its IO threads and puts it in the default slot
+    fn accept_client(&mut self, client_id: ClientId, mut stream: Box<Connection>, capabilities: Capabilities) -> Option<ClientConn> {
+        // Finish the handshake by sending back the client ID
+        let accepted = HandshakeAccepted {
+            client_id: client_id,
+            capabilities: capabilities,
+            resume_token: new_resume_token(),
+            resumed: false,
+        };
+        if let Err(e) = server_accept(&mut stream, &accepted) {
+            println!("Failed to send client ID to client: {}", e);
+            return None;
+        }
+        
+        let io = self.start_client_io(client_id, stream, capabilities);
+        
+        // Assign client to default slot, and tell it that it's been joined
+        let default_slot = self.slots[&0].0.clone();
+        default_slot.send(SlotInMsg::Joined(client_id));
+        self.record_capture(CaptureKind::Joined, client_id, 0, &[]);
+        
+        Some(ClientConn {
+            slot_id: 0, // Zero is the slot ID of the default slot
+            slot_in: default_slot,
+            out: io.out,
+            out_thread: io.out_thread,
+            heartbeat: io.heartbeat,
+            rate: RateLimiter::new(),
+            traffic: Traffic::new(),
+            connection: io.connection,
+            resume_token: accepted.resume_token,
+            suspended_since: None,
+            kicked: false,
+            compression_stats: io.compression_stats,
+        })
+    }
+    
+    // Moves a client that lost its connection onto a new one, keeping its ID and slot. Hands the
+    // connection back if there's no such session to resume, so it can join as a new client.
+    fn resume_client(&mut self,
+                     clients: &mut HashMap<ClientId, ClientConn>,
+                     mut stream: Box<Connection>,
+                     capabilities: Capabilities,
+                     resume: ResumeRequest) -> Option<Box<Connection>> {
+        let client_id = resume.client_id;
+        
+        let resumable =
+            match clients.get(&client_id) {
+                Some(client) => resume_token_matches(client.resume_token, resume.token) && !client.kicked,
+                None => false,
+            };
+        if !resumable {
+            println!("Client {} can't be resumed, joining as a new client", client_id);
+            return Some(stream);
+        }
+        
+        // Tokens are good for one resume
+        let accepted = HandshakeAccepted {
+            client_id: client_id,
+            capabilities: capabilities,
+            resume_token: new_resume_token(),
+            resumed: true,
+        };
+        if let Err(e) = server_accept(&mut stream, &accepted) {
+            println!("Failed to resume client {}: {}", client_id, e);
+            return None;
+        }
+        
+        let io = self.start_client_io(client_id, stream, capabilities);
+        
+        let client = clients.get_mut(&client_id).expect("Client must exist here");
+        
+        // The old connection may not have noticed it's dead yet
+        client.heartbeat.stream.shutdown();
+        
+        client.out = io.out;
+        client.out_thread = io.out_thread;
+        client.heartbeat = io.heartbeat;
+        client.connection = io.connection;
+        client.compression_stats = io.compression_stats;
+        client.resume_token = accepted.resume_token;
+        client.suspended_since = None;
+        
+        client.slot_in.send(SlotInMsg::Resumed(client_id));
+        println!("Client {} resumed its session in slot {}", client_id, client.slot_id);
+        
+        None
+    }
+    
+    // Starts the input and output threads for a client's connection
+    fn start_client_io(&mut self, client_id: ClientId, stream: Box<Connection>, capabilities: Capabilities) -> ClientIo {
+        let connection = self.next_connection;
+        self.next_connection = self.next_connection.wrapping_add(1);
+        
+        // Create client packet output channel
+        let (client_out_t, client_out_r) = channel();
+        let client_out_t = OutQueue { sender: client_out_t, depth: Arc::new(AtomicUsize::new(0)) };
+        let out_depth = client_out_t.depth.clone();
+        
+        // Clone stream for output stream
+        let out_stream = stream.try_clone().ok().expect("Failed to clone to-client stream");
+        
+        // Keep a handle to the stream so an idle client can be cut off
+        let control_stream = stream.try_clone().ok().expect("Failed to clone client control stream");
+    
+        // Client input process
+        let packet_in_t = self.events.sender();
+        let pong_t = client_out_t.clone();
+        let max_message_size = self.max_message_size;
+        let compress = capabilities.contains(CAP_COMPRESSION);
+        spawn(move || {
+            handle_client_in(client_id, connection, stream, packet_in_t, pong_t, max_message_size, compress);
+        });
+        
+        // Client output process
+        let compression_stats = Arc::new(CompressionStats::new());
+        let out_compression_stats = compression_stats.clone();
+        let out_thread = spawn(move || {
+            handle_client_out(out_stream, client_out_r, out_depth, compress, out_compression_stats);
+        });
+        
+        ClientIo {
+            connection: connection,
+            out: client_out_t,
+            out_thread: out_thread,
+            heartbeat: Heartbeat::new(control_stream),
+            compression_stats: if compress { Some(compression_stats) } else { None },
+        }
+    }
+    
+    // Forgets a client and tells its slot it's gone
+    fn disconnect_client(&mut self, clients: &mut HashMap<ClientId, ClientConn>, client_id: ClientId) {
+        if let Some(client) = clients.remove(&client_id) {
+            client.slot_in.send(SlotInMsg::Disconnected(client_id));
+            self.record_capture(CaptureKind::Disconnected, client_id, client.slot_id, &[]);
+        }
+        
+        println!("Client {} disconnected from server master", client_id);
+    }
+    
+    fn handle_client_msg(&mut self, clients: &mut HashMap<ClientId, ClientConn>, client_id: ClientId, msg: ClientInMsg) {
+        match msg {
+            ClientInMsg::Closed(connection) => {
+                let resumable = self.shutdown_reason.is_none() && self.resume_grace > time::Duration::zero();
+                
+                match clients.get_mut(&client_id) {
+                    // A connection the client has already replaced by resuming
+                    Some(ref client) if client.connection != connection => { return; },
+                    Some(ref mut client) if resumable && !client.kicked => {
+                        // Keep the client's place in case it comes back
+                        println!("Client {} lost its connection, holding its session for {} seconds", client_id, self.resume_grace.num_seconds());
+                        client.suspended_since = Some(time::now().to_timespec());
+                        return;
+                    },
+                    _ => { },
+                }
+                
+                self.disconnect_client(clients, client_id);
+            },
+            msg => {
+                let client =
+                    match clients.get_mut(&client_id) {
+                        Some(client) => client,
+                        None => { return; },
+                    };
+                
+                // Anything at all from the client shows it's still alive
+                let now = time::now().to_timespec();
+                client.heartbeat.last_heard = now;
+                
+                match msg {
+                    ClientInMsg::Packet(packet) => {
+                        // Counted before rate limiting, so floods show up in the stats
+                        client.traffic.count_in(packet.len());
+                        
+                        match client.rate.check(&self.rate_limits, packet.len(), now) {
+                            Verdict::Pass => { },
+                            Verdict::Drop => { return; },
+                            Verdict::Penalize(penalty) => {
+                                println!("Client {} is flooding the server: {:?}", client_id, penalty);
+                                client.slot_in.send(SlotInMsg::RateLimited(client_id, penalty));
+                                
+                                if penalty == Penalty::Disconnected {
+                                    // The input thread will report the client gone like any other
+                                    // disconnect. It doesn't get to resume.
+                                    client.kicked = true;
+                                    client.heartbeat.stream.shutdown();
+                                }
+                                return;
+                            },
+                        }
+                        
+                        // Send the received packet to the slot the client is in
+                        self.record_capture(CaptureKind::In, client_id, client.slot_id, packet.buffer.get_ref());
+                        self.count_slot_traffic(client.slot_id, packet.len(), true);
+                        client.slot_in.send(SlotInMsg::ReceivedPacket(client_id, packet));
+                    },
+                    ClientInMsg::Pong(seq) => {
+                        if let Some(latency) = client.heartbeat.on_pong(seq) {
+                            client.slot_in.send(SlotInMsg::Latency(client_id, latency));
+                        }
+                    },
+                    ClientInMsg::ProtocolError(e) => {
+                        // Tell it what it did. It doesn't get to resume.
+                        println!("Client {} broke the protocol: {}", client_id, e);
+                        client.kicked = true;
+                        client.out.send(OutFrame::Kicked(format!("Protocol error: {}", e)));
+                    },
+                    ClientInMsg::Closed(_) => unreachable!(),
+                }
+            },
+        }
+    }
+    
+    fn handle_slot_msg(&mut self, clients: &mut HashMap<ClientId, ClientConn>, msg: SlotOutMsg) {
+        match msg {
+            SlotOutMsg::SendPacket(slot_id, client_id, packet) => match clients.get_mut(&client_id) {
+                Some(client) => {
+                    self.record_capture(CaptureKind::Out, client_id, slot_id, packet.buffer.get_ref());
+                    self.count_slot_traffic(slot_id, packet.len(), false);
+                    client.traffic.count_out(packet.len());
+                    client.out.send(OutFrame::Packet(packet));
+                    /*if slot_id == client.slot_id {
+                        client.out.send(packet);
+                    } else {
+                        println!("Failed to send packet to client {} from server slot {} because the client's server slot is {}", client_id, slot_id, client.slot_id);
+                    }*/
+                },
+                None => { println!("WARNING: Failed to send packet to invalid client ID {}", client_id); }
+            },
+            SlotOutMsg::BroadcastPacket(slot_id, packet) => for (client_id, client) in clients.iter_mut() {
+                if slot_id == client.slot_id {
+                    self.record_capture(CaptureKind::Out, *client_id, slot_id, packet.buffer.get_ref());
+                    self.count_slot_traffic(slot_id, packet.len(), false);
+                    client.traffic.count_out(packet.len());
+                    client.out.send(OutFrame::Packet(packet.clone()));
+                }
+            },
+            SlotOutMsg::CreateSlot(slot_id) =>  {
+                let new_slot = self.create_slot();
+                self.slot_owners.insert(new_slot.get_id(), slot_id);
+                let (_, ref create_slot_t) = self.slots[&slot_id];
+                create_slot_t.send(new_slot);
+            },
+            SlotOutMsg::DestroySlot(slot_id, target_slot_id, migrate_to) => {
+                self.destroy_slot(clients, slot_id, target_slot_id, migrate_to);
+            },
+            SlotOutMsg::ShutdownComplete(slot_id) => {
+                self.slots_flushing.remove(&slot_id);
+            },
+            SlotOutMsg::Kick(slot_id, client_id, reason) => {
+                let suspended =
+                    match clients.get_mut(&client_id) {
+                        Some(client) => {
+                            println!("Slot {} kicked client {}: {}", slot_id, client_id, reason);
+                            client.kicked = true;
+                            client.suspended_since.is_some()
+                        },
+                        None => {
+                            println!("WARNING: Slot {} tried to kick invalid client ID {}", slot_id, client_id);
+                            return;
+                        },
+                    };
+                
+                if suspended {
+                    // Its connection is already gone, so there's nothing to tell it and nothing
+                    // left to report it gone. It goes now, resume token and all, rather than when
+                    // its resume grace runs out.
+                    self.disconnect_client(clients, client_id);
+                } else {
+                    // The output thread closes the connection after the notice, and the input
+                    // thread reports it gone
+                    clients[&client_id].out.send(OutFrame::Kicked(reason));
+                }
+            },
+            SlotOutMsg::QueryStats(slot_id) => {
+                let stats = self.net_stats(clients);
+                if let Some(&(ref slot_in_t, _)) = self.slots.get(&slot_id) {
+                    slot_in_t.send(SlotInMsg::NetStats(stats));
+                }
+            },
+            SlotOutMsg::TransferClient(slot_id, client_id, new_slot_id) => {
+                match self.slots.get(&new_slot_id).map(|&(ref slot_in_t, _)| slot_in_t.clone()) {
+                    Some(slot_in_t) => {
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            if client.slot_id == slot_id {
+                                client.slot_id = new_slot_id; // set the client's new slot ID
+                                client.slot_in.clone_from(&slot_in_t);
+                                slot_in_t.send(SlotInMsg::Joined(client_id));
+                                self.record_capture(CaptureKind::Joined, client_id, new_slot_id, &[]);
+                            } else {
+                                println!("WARNING: Non-owning slot can't transfer client {}", client_id);
+                            }
+                        } else {
+                            println!("WARNING: Can't transfer non-existant client {}", client_id);
+                        }
+                    },
+                    None => { println!("WARNING: Failed to transfer client {} to non-existant slot {}", client_id, new_slot_id); }
+                }
+            },
+        }
+    }
+    
+    fn count_slot_traffic(&mut self, slot_id: ServerSlotId, bytes: usize, incoming: bool) {
+        if let Some(activity) = self.slot_activity.get_mut(&slot_id) {
+            if incoming {
+                activity.traffic.count_in(bytes);
+            } else {
+                activity.traffic.count_out(bytes);
+            }
+            activity.last_activity = Some(time::now().to_timespec());
+        }
+    }
+    
+    fn net_stats(&self, clients: &HashMap<ClientId, ClientConn>) -> NetStats {
+        let now = time::now().to_timespec();
+        
+        let mut client_stats: Vec<ClientStats> = clients.iter().map(|(client_id, client)| {
+            ClientStats {
+                client_id: *client_id,
+                slot_id: client.slot_id,
+                traffic: client.traffic,
+                queue_depth: client.out.depth(),
+                idle_ms: (now - client.heartbeat.last_heard).num_milliseconds() as u64,
+                latency_ms: client.heartbeat.latency,
+                suspended: client.suspended_since.is_some(),
+                compression_ratio: client.compression_stats.as_ref().map(|stats| stats.ratio()),
+            }
+        }).collect();
+        client_stats.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        
+        let mut slot_stats: Vec<SlotStats> = self.slot_activity.iter().map(|(slot_id, activity)| {
+            SlotStats {
+                slot_id: *slot_id,
+                clients: clients.values().filter(|c| c.slot_id == *slot_id).count(),
+                traffic: activity.traffic,
+                idle_ms: activity.last_activity.map(|last| (now - last).num_milliseconds() as u64),
+            }
+        }).collect();
+        slot_stats.sort_by(|a, b| a.slot_id.cmp(&b.slot_id));
+        
+        NetStats {
+            clients: client_stats,
+            slots: slot_stats,
+        }
+    }
+    
+    // Writes to the capture file, if there is one. Capturing stops at the first error.
+    fn record_capture(&mut self, kind: CaptureKind, client_id: ClientId, slot_id: ServerSlotId, data: &[u8]) {
+        let failed =
+            match self.capture {
+                Some(ref mut capture) => capture.record(kind, client_id, slot_id, data).err(),
+                None => None,
+            };
+        
+        if let Some(e) = failed {
+            println!("WARNING: Packet capture failed, no longer capturing: {}", e);
+            self.capture = None;
+        }
+    }
+    
+    fn destroy_slot(&mut self,
+                    clients: &mut HashMap<ClientId, ClientConn>,
+                    slot_id: ServerSlotId,
+                    target_slot_id: ServerSlotId,
+                    migrate_to: Option<ServerSlotId>) {
+        let owner_id = self.slot_owners.get(&target_slot_id).map(|id| *id);
+        
+        // Only a slot itself or the slot that created it may destroy it. The default slot is
+        // where new clients go, so it stays.
+        if target_slot_id == 0 || !self.slots.contains_key(&target_slot_id) {
+            println!("WARNING: Can't destroy slot {}", target_slot_id);
+            return;
+        }
+        if slot_id != target_slot_id && owner_id != Some(slot_id) {
+            println!("WARNING: Slot {} can't destroy slot {} it doesn't own", slot_id, target_slot_id);
+            return;
+        }
+        
+        // Find where the remaining clients are going
+        let migrate_to =
+            match migrate_to {
+                Some(migrate_to) if migrate_to != target_slot_id => {
+                    match self.slots.get(&migrate_to) {
+                        Some(&(ref slot_in_t, _)) => Some((migrate_to, slot_in_t.clone())),
+                        None => {
+                            println!("WARNING: Can't migrate clients to non-existant slot {}, disconnecting them", migrate_to);
+                            None
+                        },
+                    }
+                },
+                _ => None,
+            };
+        
+        let stranded: Vec<ClientId> = clients.iter().filter(|&(_, c)| c.slot_id == target_slot_id).map(|(id, _)| *id).collect();
+        for client_id in stranded {
+            match migrate_to {
+                Some((new_slot_id, ref slot_in_t)) => {
+                    let client = clients.get_mut(&client_id).expect("Client must exist here");
+                    client.slot_id = new_slot_id;
+                    client.slot_in.clone_from(slot_in_t);
+                    slot_in_t.send(SlotInMsg::Joined(client_id));
+                    self.record_capture(CaptureKind::Joined, client_id, new_slot_id, &[]);
+                },
+                None => {
+                    // The input thread will report the connection closed, but there's no slot
+                    // left to tell, so forget the client now.
+                    let client = clients.remove(&client_id).expect("Client must exist here");
+                    client.heartbeat.stream.shutdown();
+                    println!("Client {} disconnected because slot {} was destroyed", client_id, target_slot_id);
+                },
+            }
+        }
+        
+        // Dropping the channels hangs up on the slot
+        self.slots.remove(&target_slot_id);
+        self.slot_activity.remove(&target_slot_id);
+        self.slot_owners.remove(&target_slot_id);
+        self.slots_flushing.remove(&target_slot_id);
+        
+        // Slots it created belong to nobody now
+        let orphans: Vec<ServerSlotId> = self.slot_owners.iter().filter(|&(_, owner)| *owner == target_slot_id).map(|(id, _)| *id).collect();
+        for orphan_id in orphans {
+            self.slot_owners.remove(&orphan_id);
+        }
+        
+        if let Some(owner_id) = owner_id {
+            if let Some(&(ref owner_in_t, _)) = self.slots.get(&owner_id) {
+                owner_in_t.send(SlotInMsg::SlotDestroyed(target_slot_id));
+            }
+        }
+        
+        println!("Destroyed slot {}", target_slot_id);
+    }
+    
+    // Ping clients and cut off the ones that have gone quiet
+    fn check_heartbeats(&mut self, clients: &mut HashMap<ClientId, ClientConn>) {
+        let now = time::now().to_timespec();
+        let mut expired = vec!();
+        for (client_id, client) in clients.iter_mut() {
+            // Clients that lost their connection only have until the grace period is up
+            if let Some(suspended_since) = client.suspended_since {
+                if now - suspended_since > self.resume_grace {
+                    expired.push(*client_id);
+                }
+                continue;
+            }
+            
+            let heartbeat = &mut client.heartbeat;
+            
+            if heartbeat.timed_out {
+                continue;
+            }
+            
+            if now - heartbeat.last_heard > self.idle_timeout {
+                // Shutting the socket down makes the client's input thread report it closed,
+                // which disconnects it the usual way.
+                println!("Client {} timed out", client_id);
+                heartbeat.stream.shutdown();
+                heartbeat.timed_out = true;
+            } else if now - heartbeat.last_ping >= self.heartbeat_interval {
+                client.out.send(OutFrame::Ping(heartbeat.next_ping(now)));
+            }
+        }
+        
+        for client_id in expired {
+            println!("Client {} didn't come back in time", client_id);
+            self.disconnect_client(clients, client_id);
+        }
+    }
+}
+
+// Makes an unguessable token for resuming a session
+fn new_resume_token() -> ResumeToken {
+    let mut rng = OsRng::new().ok().expect("Failed to open the OS random number generator");
+    rng.next_u64()
+}
+
+// Tokens are as good as a password, so they're compared in constant time
+fn resume_token_matches(expected: ResumeToken, given: ResumeToken) -> bool {
+    let expected_bytes: Vec<u8> = (0..8).map(|i| (expected >> (i * 8)) as u8).collect();
+    let given_bytes: Vec<u8> = (0..8).map(|i| (given >> (i * 8)) as u8).collect();
+    fixed_time_eq(&expected_bytes, &given_bytes)
+}
+
+// Everything the server master waits on
+enum MasterMsg {
+    NewClient(Box<Connection>, Capabilities, Option<ResumeRequest>), // A client finished its handshake, maybe asking to resume
+    FromClient(ClientId, ClientInMsg),          // A client's input thread has something
+    FromSlot(SlotOutMsg),                       // A slot wants something done
+    HeartbeatTick,                              // Time to ping clients and check for idle ones
+    Shutdown(String),                           // Start shutting down, for this reason
+    ShutdownDeadline,                           // Slots have had long enough to flush
+    QueryStats(Sender<NetStats>),               // Someone wants traffic stats sent here
+    ClientsDisconnected,                        // Every client was sent the shutdown notice
+    DisconnectDeadline,                         // Clients have had long enough to be sent it
+}
+
+// The server master's view of one connected client
+struct ClientConn {
+    // Slot the client is in. Also kept so packets sent from server slots can be verified.
+    slot_id: ServerSlotId,
+    slot_in: Sender<SlotInMsg>,
+    
+    // Out packet channel, and the thread writing it out
+    out: OutQueue,
+    out_thread: JoinHandle<()>,
+    
+    heartbeat: Heartbeat,
+    
+    // How much of its packet budget the client has used
+    rate: RateLimiter,
+    
+    // Packets to and from the client since it connected
+    traffic: Traffic,
+    
+    // Number of the client's current connection
+    connection: u32,
+    
+    // Secret the client must show to resume its session on a new connection
+    resume_token: ResumeToken,
+    
+    // When the client lost its connection, if it's waiting to resume
+    suspended_since: Option<time::Timespec>,
+    
+    // Set when the server cut the client off on purpose, so it can't resume
+    kicked: bool,
+    
+    // How well packets to the client are compressing on its current connection, if it negotiated
+    // compression
+    compression_stats: Option<Arc<CompressionStats>>,
+}
+
+// One connection's worth of a client's IO
+struct ClientIo {
+    connection: u32,
+    out: OutQueue,
+    out_thread: JoinHandle<()>,
+    heartbeat: Heartbeat,
+    compression_stats: Option<Arc<CompressionStats>>,
+}
+
+// A client's outgoing frames, counted so the queue's depth can be reported
+#[derive(Clone)]
+struct OutQueue {
+    sender: Sender<OutFrame>,
+    depth: Arc<AtomicUsize>,    // Taken off by the output thread as it takes frames
+}
+
+impl OutQueue {
+    fn send(&self, frame: OutFrame) {
+        // Counted first so the output thread can't take it off before it's on
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(frame).is_err() {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    
+    fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+// Frames queued for a client's output thread
+enum OutFrame {
+    Packet(OutPacket),
+    Ping(u32),
+    Pong(u32),
+    Shutdown(String),   // Last frame sent. The connection is closed after it's written.
+    Kicked(String),     // Same, for a client dropped on purpose
+}
+
+// Messages from a client's input thread to the server master
+enum ClientInMsg {
+    Packet(InPacket),
+    Pong(u32),
+    Closed(u32),    // The connection with this number closed
+    ProtocolError(String),  // The client broke the protocol and is being dropped, for this reason
+}
+
+// Keepalive bookkeeping for one client
+struct Heartbeat {
+    stream: Box<Connection>,            // Used to shut the connection down if it goes idle
+    last_heard: time::Timespec,         // Last time anything arrived from the client
+    last_ping: time::Timespec,          // Last time a ping was sent
+    ping_seq: u32,                      // Sequence number of the last ping sent
+    ping_outstanding: bool,             // Whether the last ping is still waiting on a pong
+    latency: Option<u32>,               // Round trip time measured by the last answered ping
+    timed_out: bool,
+}
+
+impl Heartbeat {
+    fn new(stream: Box<Connection>) -> Heartbeat {
+        let now = time::now().to_timespec();
+        Heartbeat {
+            stream: stream,
+            last_heard: now,
+            last_ping: now,
+            ping_seq: 0,
+            ping_outstanding: false,
+            latency: None,
+            timed_out: false,
+        }
+    }
+    
+    // Returns the sequence number for a new ping
+    fn next_ping(&mut self, now: time::Timespec) -> u32 {
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        self.last_ping = now;
+        self.ping_outstanding = true;
+        self.ping_seq
+    }
+    
+    // Returns the round trip time in milliseconds if this pong answers the last ping
+    fn on_pong(&mut self, seq: u32) -> Option<u32> {
+        if self.ping_outstanding && seq == self.ping_seq {
+            self.ping_outstanding = false;
+            self.latency = Some((time::now().to_timespec() - self.last_ping).num_milliseconds() as u32);
+            self.latency
+        } else {
+            None
+        }
+    }
+}
+
+fn client_acceptor(mut listener: Box<Listener>, new_client_t: Sender<MasterMsg>, policy: HandshakePolicy) {
+    let mut failures = 0;
+    
+    loop {
+        match listener.accept() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => {
+                println!("Listener closed, no longer accepting clients from it: {}", e);
+                break;
+            },
+            Err(e) => {
+                // Out of file descriptors or the like. Wait it out rather than spin.
+                let backoff_ms = accept_backoff_ms(failures);
+                failures += 1;
+                println!("Incoming connection failed, trying again in {} ms: {}", backoff_ms, e);
+                sleep_ms(backoff_ms);
+            },
+            Ok(mut stream) => {
+                failures = 0;
+                
+                // Handshake on its own thread so a slow client can't hold up the acceptor
+                let new_client_t = new_client_t.clone();
+                let policy = policy.clone();
+                spawn(move || {
+                    // A client that connects and never says anything doesn't get to keep this thread
+                    if let Err(e) = stream.set_read_timeout(Some(StdDuration::from_millis(HANDSHAKE_TIMEOUT_MS))) {
+                        println!("Incoming connection failed: {}", e);
+                        return;
+                    }
+                    
+                    match server_handshake(&mut stream, &policy) {
+                        Ok(Ok((capabilities, resume))) => {
+                            match stream.set_read_timeout(None) {
+                                Ok(()) => { new_client_t.send(MasterMsg::NewClient(stream, capabilities, resume)); },
+                                Err(e) => { println!("Incoming connection failed: {}", e); },
+                            }
+                        },
+                        Ok(Err(rejection)) => { println!("Rejected incoming connection: {}", rejection); },
+                        Err(e) => { println!("Incoming connection failed handshake: {}", e); },
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn handle_client_in(client_id: ClientId,
+                    connection: u32,
+                    mut stream: Box<Connection>,
+                    packet_in_t: Sender<MasterMsg>,
+                    out_t: OutQueue,
+                    max_message_size: u64,
+                    compressed: bool) {
+    loop {
+        // Oversized or malformed frames end up here too, which drops the client
+        let msg =
+            match read_frame(&mut stream, max_message_size) {
+                Ok((FrameKind::Packet, data)) => ClientInMsg::Packet(InPacket::new(data)),
+                Ok((FrameKind::CompressedPacket, _)) if !compressed => {
+                    println!("Client {} sent a compressed packet without negotiating compression, input thread shutting down", client_id);
+                    let e = "compressed packet without negotiating compression".to_string();
+                    packet_in_t.send(MasterMsg::FromClient(client_id, ClientInMsg::ProtocolError(e)));
+                    packet_in_t.send(MasterMsg::FromClient(client_id, ClientInMsg::Closed(connection)));
+                    break;
+                },
+                Ok((FrameKind::CompressedPacket, data)) => {
+                    match decompress(&data, max_message_size) {
+                        Ok(data) => ClientInMsg::Packet(InPacket::new(data)),
+                        Err(e) => {
+                            println!("Client {} sent a bad compressed packet, input thread shutting down: {}", client_id, e);
+                            packet_in_t.send(MasterMsg::FromClient(client_id, ClientInMsg::Closed(connection)));
+                            break;
+                        },
+                    }
+                },
+                Ok((kind, data)) => {
+                    match read_heartbeat_seq(&data) {
+                        Ok(seq) if kind == FrameKind::Ping => {
+                            // Answer straight away, the master doesn't need to know
+                            out_t.send(OutFrame::Pong(seq));
+                            continue;
+                        },
+                        Ok(seq) => ClientInMsg::Pong(seq),
+                        Err(e) => {
+                            println!("Client {} sent a bad heartbeat, input thread shutting down: {}", client_id, e);
+                            packet_in_t.send(MasterMsg::FromClient(client_id, ClientInMsg::Closed(connection)));
+                            break;
+                        },
+                    }
+                },
+                Err(e) => {
+                    println!("Client {} input thread shutting down: {}", client_id, e);
+                    packet_in_t.send(MasterMsg::FromClient(client_id, ClientInMsg::Closed(connection)));
+                    break;
+                },
+            };
+        packet_in_t.send(MasterMsg::FromClient(client_id, msg));
+    }
+}
+
+fn handle_client_out(mut stream: Box<Connection>,
+                     out_r: Receiver<OutFrame>,
+                     depth: Arc<AtomicUsize>,
+                     compress: bool,
+                     compression_stats: Arc<CompressionStats>) {
+    loop {
+        // Receive a frame to send
+        let frame = 
+            match out_r.recv() {
+                Ok(frame) => {
+                    depth.fetch_sub(1, Ordering::Relaxed);
+                    frame
+                },
+                Err(_) => {
+                    println!("Client out packet channel closed, shutting output thread down");
+                    break;
+                },
+            };
+        
+        let result =
+            match frame {
+                OutFrame::Packet(packet) => write_packet_frame(&mut stream, packet.buffer.get_ref(), compress, &compression_stats),
+                OutFrame::Ping(seq) => write_heartbeat_frame(&mut stream, FrameKind::Ping, seq),
+                OutFrame::Pong(seq) => write_heartbeat_frame(&mut stream, FrameKind::Pong, seq),
+                OutFrame::Shutdown(reason) => {
+                    write_frame(&mut stream, FrameKind::Shutdown, reason.as_bytes());
+                    stream.shutdown();
+                    break;
+                },
+                OutFrame::Kicked(reason) => {
+                    write_frame(&mut stream, FrameKind::Kicked, reason.as_bytes());
+                    stream.shutdown();
+                    break;
+                },
+            };
+        
+        if let Err(e) = result {
+            println!("Client out failed to write packet, shutting output thread down: {}", e);
+            break;
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Client
+
+pub struct Client {
+    id: ClientId,
+    capabilities: Capabilities,
+    
+    // Shared with the receiver thread, which answers the server's pings
+    stream: Arc<Mutex<Box<Connection>>>,
+    packet_receiver: Receiver<NetResult<InPacket>>,
+    
+    // How well packets to the server are compressing, if compression was negotiated
+    compression_stats: Arc<CompressionStats>,
+    
+    // Proves who we are if the connection drops and we need to resume
+    resume_token: ResumeToken,
+    
+    // Opens a new connection to the same server, if we know how
+    connector: Option<Connector>,
+}
+
+// Opens a connection to a particular server
+type Connector = Box<Fn() -> NetResult<Box<Connection>> + Send>;
+
+impl Client {
+    /// Connects and handshakes with a server. If the server refuses this client, the
+    /// `NetError::Rejected` reason can be shown to the player.
+    pub fn new(host: &str) -> NetResult<Client> {
+        let host = host.to_string();
+        let connector: Connector = Box::new(move || -> NetResult<Box<Connection>> {
+            let stream = try!(TcpStream::connect(&host[..]));
+            Ok(Box::new(stream))
+        });
+        
+        let mut client = try!(Client::from_connection(try!(connector())));
+        client.connector = Some(connector);
+        Ok(client)
+    }
+    
+    /// Connects to a server over TLS, refusing servers `config` doesn't trust
+    #[cfg(feature = "tls")]
+    pub fn new_tls(host: &str, config: &TlsClientConfig) -> NetResult<Client> {
+        let host = host.to_string();
+        let config = config.clone();
+        let connector: Connector = Box::new(move || -> NetResult<Box<Connection>> {
+            let stream = try!(TlsStream::connect(&host, &config));
+            Ok(Box::new(stream))
+        });
+        
+        let mut client = try!(Client::from_connection(try!(connector())));
+        client.connector = Some(connector);
+        Ok(client)
+    }
+    
+    /// Handshakes with a server over an already open connection. Clients made this way can
+    /// `resume`, but can't `reconnect` by themselves.
+    pub fn from_connection(mut stream: Box<Connection>) -> NetResult<Client> {
+        let accepted = try!(try!(client_handshake(&mut stream, None)));
+        let (stream, packet_receiver) = try!(start_receiving(stream));
+    
+        Ok(Client {
+            id: accepted.client_id,
+            capabilities: accepted.capabilities,
+            stream: stream,
+            packet_receiver: packet_receiver,
+            compression_stats: Arc::new(CompressionStats::new()),
+            resume_token: accepted.resume_token,
+            connector: None,
+        })
+    }
+    
+    /// Picks up this client's session over a new connection after losing the old one. Returns
+    /// whether the server still had the session. If it didn't, this is now a brand new client
+    /// with a new ID, and has to log in again.
+    pub fn resume(&mut self, mut stream: Box<Connection>) -> NetResult<bool> {
+        let request = ResumeRequest { client_id: self.id, token: self.resume_token };
+        let accepted = try!(try!(client_handshake(&mut stream, Some(request))));
+        let (stream, packet_receiver) = try!(start_receiving(stream));
+        
+        // Make sure the old connection is really gone
+        self.stream.lock().unwrap().shutdown();
+        
+        self.id = accepted.client_id;
+        self.capabilities = accepted.capabilities;
+        self.stream = stream;
+        self.packet_receiver = packet_receiver;
+        self.resume_token = accepted.resume_token;
+        
+        Ok(accepted.resumed)
+    }
+    
+    /// Opens a new connection to the server this client was made for with `new` or `new_tls`, and
+    /// resumes the session on it. See `resume`.
+    pub fn reconnect(&mut self) -> NetResult<bool> {
+        let stream =
+            match self.connector {
+                Some(ref connect) => try!(connect()),
+                None => { return Err(NetError::Disconnected); },
+            };
+        
+        self.resume(stream)
+    }
+    
+    pub fn send(&mut self, packet: &OutPacket) -> NetResult<()> {
+        let data = &packet.buffer.get_ref();
+        let compress = self.capabilities.contains(CAP_COMPRESSION);
+        let mut stream = self.stream.lock().unwrap();
+        try!(write_packet_frame(&mut *stream, &(*data)[..], compress, &self.compression_stats));
+        Ok(())
+    }
+    
+    /// Blocks until the next packet arrives. If the connection drops, reconnects and resumes once
+    /// before giving up. The slot the client is in then sends whatever it needs to catch up.
+    pub fn receive(&mut self) -> NetResult<InPacket> {
+        match self.receive_once() {
+            Err(e) => {
+                try!(self.try_resume(e));
+                self.receive_once()
+            },
+            packet => packet,
+        }
+    }
+    
+    /// Returns the next packet if one has arrived. Resumes like `receive` does.
+    pub fn try_receive(&mut self) -> NetResult<Option<InPacket>> {
+        use std::sync::mpsc::TryRecvError;
+    
+        let result =
+            match self.packet_receiver.try_recv() {
+                Ok(packet) => packet.map(Some),
+                Err(TryRecvError::Empty) => Ok(None),
+                Err(TryRecvError::Disconnected) => Err(NetError::Disconnected),
+            };
+        
+        match result {
+            Err(e) => {
+                try!(self.try_resume(e));
+                Ok(None)
+            },
+            packet => packet,
+        }
+    }
+    
+    fn receive_once(&mut self) -> NetResult<InPacket> {
+        match self.packet_receiver.recv() {
+            Ok(packet) => packet,
+            Err(_) => Err(NetError::Disconnected),
+        }
+    }
+    
+    // Reconnects after the connection failed with `error`. Gives back `error` if that's not
+    // possible, or if the server no longer has our session.
+    fn try_resume(&mut self, error: NetError) -> NetResult<()> {
+        let lost_connection =
+            match error {
+                NetError::Io(_) | NetError::Disconnected => self.connector.is_some(),
+                _ => false,
+            };
+        if !lost_connection {
+            return Err(error);
+        }
+        
+        println!("Lost connection to server ({}), reconnecting...", error);
+        match self.reconnect() {
+            Ok(true) => {
+                println!("Resumed session as client {}", self.id);
+                Ok(())
+            },
+            Ok(false) => {
+                // The server let the new connection in as a fresh client, which is no use here
+                println!("Reconnected, but the server had already let our session go");
+                self.stream.lock().unwrap().shutdown();
+                Err(error)
+            },
+            Err(e) => {
+                println!("Failed to reconnect: {}", e);
+                Err(error)
+            },
+        }
+    }
+    
+    pub fn get_id(&self) -> ClientId {
+        self.id
+    }
+    
+    /// Capabilities both this client and the server support
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    
+    pub fn get_compression_stats(&self) -> Arc<CompressionStats> {
+        self.compression_stats.clone()
+    }
+}
+
+// Starts the thread that reads frames from the server, answering pings and passing packets on
+fn start_receiving(stream: Box<Connection>) -> NetResult<(Arc<Mutex<Box<Connection>>>, Receiver<NetResult<InPacket>>)> {
+    let (packet_sender, packet_receiver) = channel();
+    
+    let mut thread_stream = try!(stream.try_clone());
+    let stream = Arc::new(Mutex::new(stream));
+    let pong_stream = stream.clone();
+    Builder::new().name("client_packet_receiver".to_string()).spawn(move || {
+        loop {
+            match read_frame(&mut thread_stream, DEFAULT_MAX_MESSAGE_SIZE) {
+                Ok((FrameKind::Packet, data)) => {
+                    if packet_sender.send(Ok(InPacket::new(data))).is_err() {
+                        break;
+                    }
+                },
+                Ok((FrameKind::CompressedPacket, data)) => {
+                    let packet = decompress(&data, DEFAULT_MAX_MESSAGE_SIZE).map(InPacket::new);
+                    let failed = packet.is_err();
+                    if packet_sender.send(packet.map_err(NetError::from)).is_err() || failed {
+                        break;
+                    }
+                },
+                Ok((FrameKind::Ping, data)) => {
+                    let pong =
+                        read_heartbeat_seq(&data).and_then(|seq| {
+                            let mut stream = pong_stream.lock().unwrap();
+                            write_heartbeat_frame(&mut *stream, FrameKind::Pong, seq)
+                        });
+                    if let Err(e) = pong {
+                        packet_sender.send(Err(NetError::from(e)));
+                        break;
+                    }
+                },
+                Ok((FrameKind::Pong, _)) => {
+                    // The client never pings, so there's nothing to match this against
+                },
+                Ok((FrameKind::Shutdown, data)) => {
+                    let reason = String::from_utf8_lossy(&data).into_owned();
+                    packet_sender.send(Err(NetError::ServerShutdown(reason)));
+                    break;
+                },
+                Ok((FrameKind::Kicked, data)) => {
+                    let reason = String::from_utf8_lossy(&data).into_owned();
+                    packet_sender.send(Err(NetError::Kicked(reason)));
+                    break;
+                },
+                Err(e) => {
+                    // The connection is dead. Report why once and hang up, which tells the
+                    // client it's been disconnected.
+                    packet_sender.send(Err(NetError::from(e)));
+                    break;
+                },
+            }
+        }
+    });
+    
+    Ok((stream, packet_receiver))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Packet
+
+#[derive(Clone)]
+pub struct OutPacket {
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl OutPacket {
+    pub fn new() -> OutPacket {
+        OutPacket{buffer: io::Cursor::new(vec!())}
+    }
+    
+    pub fn len(&self) -> usize {
+        self.buffer.get_ref().len()
+    }
+    
+    /// What's been written so far, as it goes on the wire before compression
+    pub fn bytes(&self) -> &[u8] {
+        self.buffer.get_ref()
+    }
+    
+    pub fn write<'a, T>(&mut self, t: &T) -> Result<(), EncodingError>
+        where T: Encodable
+    {
+        encode_into(t, &mut self.buffer, SizeLimit::Infinite)
+    }
+}
+
+pub struct InPacket {
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl InPacket {
+    pub fn new(data: Vec<u8>) -> InPacket {
+        InPacket{buffer: io::Cursor::new(data)}
+    }
+    
+    pub fn try_new_from_reader<T: Read>(reader: &mut T) -> io::Result<InPacket> {
+        InPacket::try_new_from_reader_limited(reader, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+    
+    /// Reads a packet, failing if it's bigger than `max_size` bytes or the server is shutting
+    /// down. Heartbeat frames are skipped.
+    pub fn try_new_from_reader_limited<T: Read>(reader: &mut T, max_size: u64) -> io::Result<InPacket> {
+        use std::io::{Error, ErrorKind};
+        
+        loop {
+            match try!(read_frame(reader, max_size)) {
+                (FrameKind::Packet, data) => { return Ok(InPacket::new(data)); },
+                (FrameKind::CompressedPacket, data) => { return decompress(&data, max_size).map(InPacket::new); },
+                (FrameKind::Shutdown, data) => {
+                    let reason = String::from_utf8_lossy(&data).into_owned();
+                    return Err(Error::new(ErrorKind::ConnectionAborted, format!("Server shut down: {}", reason)));
+                },
+                (FrameKind::Kicked, data) => {
+                    let reason = String::from_utf8_lossy(&data).into_owned();
+                    return Err(Error::new(ErrorKind::ConnectionAborted, format!("Kicked from server: {}", reason)));
+                },
+                _ => { },
+            }
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        self.buffer.get_ref().len()
+    }
+    
+    pub fn read<T: Decodable>(&mut self) -> Result<T, DecodingError> {
+        Ok(try!(decode_from(&mut self.buffer, SizeLimit::Infinite)))
+    }
+}
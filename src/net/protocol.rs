@@ -0,0 +1,214 @@
+//! Wire protocol reference, for tools that want to talk to a server without linking this crate.
+//! Nothing in here is code; it describes what `net` and the packet types put on the wire.
+//!
+//! # Primitives
+//!
+//! All multi-byte integers are big endian.
+//!
+//! * `u8`, `u16`, `u32`: 1, 2 and 4 bytes.
+//! * handshake string: `u16` byte length, then that many bytes of UTF-8.
+//! * varint: LEB128. 7 bits per byte, least significant group first, high bit set on every byte
+//!   except the last. At most 10 bytes.
+//!
+//! # Handshake
+//!
+//! Sent once, straight after connecting, before any frames.
+//!
+//! Client to server:
+//!
+//! 1. magic: the 4 bytes `RFRG`
+//! 2. protocol version: `u32`, currently `PROTOCOL_VERSION`
+//! 3. build ID: string
+//! 4. capabilities: `u32` bit set. The only bit so far is `0x1`, compression.
+//! 5. resume: `u8` 0 for a new session, or `u8` 1 then the old client ID `u32` and resume token
+//!    `u64`, to pick up a session whose connection dropped
+//!
+//! Server to client, either:
+//!
+//! * `u8` 0 (accepted), negotiated capabilities `u32`, client ID `u32`, resume token `u64`, then
+//!   `u8` 1 if the requested session was resumed or 0 if this is a new one
+//! * `u8` 1 (rejected), then the reason, after which the server hangs up:
+//!     * `u8` 0: bad magic
+//!     * `u8` 1: protocol mismatch, server version `u32`, client version `u32`
+//!     * `u8` 2: build mismatch, server build string, client build string
+//!     * `u8` 3: missing capabilities, the missing bits as `u32`
+//!
+//! # Frames
+//!
+//! Everything after the handshake is a frame: a varint length of the data, a `u8` frame kind, then
+//! the data. The length doesn't count the kind byte. The server drops clients that send frames
+//! over its size limit, 16 MiB unless configured otherwise.
+//!
+//! * 0 packet: data is one packet, below.
+//! * 1 ping: data is a `u32` sequence number. Answer with a pong carrying the same number.
+//! * 2 pong: data is the `u32` sequence number of the ping being answered.
+//! * 3 shutdown: the server is going away. Data is a UTF-8 reason. Sent last, by the server only.
+//! * 4 compressed packet: data is one packet compressed with raw deflate (RFC 1951, no zlib
+//!   header). Only sent when compression was negotiated, and only for packets of 512 bytes or more
+//!   that come out smaller. The inflated packet is held to the same size limit as plain ones.
+//!   Servers can be run without compression, and clients that send compressed packets without
+//!   having negotiated it are kicked.
+//! * 5 kicked: the server is dropping this client, usually on a moderator's say or for breaking
+//!   the protocol. Data is a UTF-8 reason. Sent last, by the server only. Kicked clients can't
+//!   resume.
+//!
+//! The server pings every 5 seconds and disconnects clients that send nothing, pongs included,
+//! for 30 seconds.
+//!
+//! # Resuming
+//!
+//! When a client's connection drops, the server holds its place for 60 seconds. Its slot isn't
+//! told, and packets sent to it meanwhile are lost. A client that reconnects in time and hands over
+//! its client ID and latest resume token in the handshake gets the same client ID back, in the
+//! same slot, which then sends it whatever it needs to catch up. Each token is good for one resume.
+//! Clients the server disconnected on purpose can't resume.
+//!
+//! Catching up means the login server sending its last refusal again, the star map sending the
+//! star map and `ClientAction` again (or the last `ClientAction` for a client it logged out), a
+//! sector sending a snapshot of the battle, and a station nothing. A client that isn't given its
+//! session back should hang up and log in again.
+//!
+//! # WebSocket
+//!
+//! Servers started with `--websocket <address>` also take clients over WebSocket (RFC 6455), for
+//! browsers and tools that can't open a raw socket. The connection carries exactly the bytes a TCP
+//! connection would, handshake included, in binary messages. The server sends each frame in one
+//! message, but messages from the client are joined into one stream, so it can split them however
+//! it likes. Text messages get the client dropped.
+//!
+//! # Packet encoding
+//!
+//! Packet data is one or more values encoded back to back with bincode:
+//!
+//! * integers and floats: big endian, at their natural width. `bool` is a `u8` 0 or 1.
+//! * `String` and `Vec<T>`: `u64` length, then the bytes or elements.
+//! * `Option<T>`: `u8` 0 for `None`, or `u8` 1 then the value.
+//! * enums: `u32` variant index, counting from 0 in declaration order, then the variant's fields.
+//! * structs and tuples: fields in declaration order, with nothing between them.
+//!
+//! Newtypes like `SectorId(u32)`, `ShipIndex(u32)`, `ModuleIndex(u32)` and `ModelIndex(u16)` are
+//! encoded as the value they wrap. `Vec2f` is two `f64`s, `x` then `y`.
+//!
+//! # Session
+//!
+//! ## Login
+//!
+//! 1. Client sends `LoginPacket`:
+//!     * 0 `Login`: `username: String`, `password: String`, `take_over: bool`. With `take_over`
+//!       set, an account that's already logged in has its session kicked and is handed to this
+//!       connection once it's back, which can take a few seconds. If it isn't back within 15
+//!       seconds the reply is `AlreadyLoggedIn`.
+//!     * 1 `Register`: `username: String`, `password: String`. Creates the account, then logs in.
+//!       Usernames are 3 to 24 ASCII letters, digits, `_` or `-`. Passwords are at least 6
+//!       characters.
+//! 2. Server replies `Option<LoginError>`. `None` means logged in. `LoginError` is:
+//!     * 0 `NoSuchAccount`
+//!     * 1 `WrongPassword`
+//!     * 2 `AlreadyLoggedIn`
+//!     * 3 `Unavailable`, the server couldn't load the account from storage
+//!     * 4 `LockedOut`, too many wrong passwords in a row. Lasts 5 minutes.
+//!     * 5 `UsernameTaken`
+//!     * 6 `InvalidUsername`, characters other than the ones allowed
+//!     * 7 `UsernameTooShort`
+//!     * 8 `UsernameTooLong`
+//!     * 9 `PasswordTooShort`
+//!     * 10 `Banned`: `until: Option<i64>`, unix time in seconds the ban ends or none if it never
+//!       does, then `reason: String`
+//!
+//! ## Star map
+//!
+//! After logging in, the server sends `Vec<SectorData>`, each being `id: SectorId`,
+//! `kind: SectorKind` (0 `Sector`, 1 `Station`) and `map_position: Vec2f`.
+//!
+//! From then on the server sends a `ClientAction` whenever the client moves, and the client
+//! follows it:
+//!
+//! * 0 `JoinSector`: see Sectors.
+//! * 1 `JoinStation`: see Stations.
+//! * 2 `Logout`: the account is logged out. The client goes back to logging in.
+//! * 3 `ServerShutdown`: the account is logged out because the server is shutting down. Nothing
+//!   else follows but the `Shutdown` frame with the reason.
+//!
+//! Jumping between sectors takes a few seconds, during which nothing is sent. The next
+//! `ClientAction` arrives when the jump is done.
+//!
+//! ## Stations
+//!
+//! The server sends the player's ship as `Option<ShipStored>`. Then:
+//!
+//! Client sends `StationAction`:
+//!
+//! * 0 `Jump(SectorId)`
+//! * 1 `ShipEdit(ShipEditAction)`, where `ShipEditAction` is
+//!     * 0 `Place(ModelIndex, u8, u8)`: model and x, y position on the ship
+//!     * 1 `Remove(ModuleIndex)`
+//! * 2 `Chat(String)`
+//! * 3 `Logout`
+//! * 4 `CareerStats`: asks for the player's `CareerStats`
+//! * 5 `Hangar(HangarAction)`, where `HangarAction` is
+//!     * 0 `List`
+//!     * 1 `Switch(ShipId)`: makes a hangar ship the active one, the ship that goes out on jumps
+//!     * 2 `Rename(ShipId, String)`: names are 1 to 24 characters
+//!     * 3 `Retire(ShipId)`: scraps a ship that isn't the active one
+//!
+//! Server sends `ClientStationPacket`:
+//!
+//! * 0 `Chat(ChatMsg)`, where `ChatMsg` is `author_name: String`, `content: String`
+//! * 1 `CareerStats(CareerStats)`: `battles: u32`, `turns: u32`, `kills: u32`, `deaths: u32`,
+//!   `damage_dealt: u64`, `damage_taken: u64`, `repairs: u64`, `jumps: u32`. Counted over the
+//!   account's whole life.
+//! * 2 `Credits(u64)`: the player's balance. Sent on arrival and whenever it changes.
+//! * 3 `Hangar(Vec<HangarShip>)`: every ship the account owns, each `id: ShipId`, `name: String`,
+//!   `level: u8` and `active: bool`. Sent on arrival and after each `HangarAction` that worked.
+//!   New accounts start with two ships in the hangar besides the active one.
+//! * 4 `HangarError(String)`: why a `HangarAction` didn't work
+//! * 5 `ActiveShip`, followed by the new active ship as `Option<ShipStored>`. Sent when a
+//!   `HangarAction` changed it.
+//!
+//! Chat from moderators and admins that starts with `/` is a moderator command, not chat:
+//! `/kick <username> [reason]`, `/mute <username> <minutes>`, `/unmute <username>`,
+//! `/ban <username> <minutes|forever> [reason]`, `/unban <username>`, and for admins only
+//! `/role <username> <player|moderator|admin>`. This goes for sector chat too. A command that
+//! doesn't parse is answered with a `Chat` from "Moderation", to the moderator only, saying how it
+//! should look. Muted players' chat is dropped.
+//!
+//! ## Sectors
+//!
+//! The server sends one packet holding the player's `Ship`, a `bool` that's true if this turn has
+//! already been simulated, and every ship in the sector as `Vec<Option<Ship>>`, indexed by
+//! `ShipIndex`.
+//!
+//! Client sends `ServerBattlePacket`:
+//!
+//! * 0 `Plan`, followed in the same packet by the ship's `ShipPlans`
+//! * 1 `Chat(String)`
+//! * 2 `Logout`: the ship leaves at the end of the turn
+//! * 3 `CareerStats`: asks for the player's `CareerStats`
+//!
+//! Server sends `ClientBattlePacket`:
+//!
+//! * 0 `NewShipsPre`, followed by ships added as `Vec<Ship>`, then ships removed as
+//!   `Vec<ShipIndex>`. Applies before the turn's results.
+//! * 1 `SimResults`, followed by a `u32` ship count, then for each ship its `ShipIndex`, power use
+//!   `u8` and jumping `bool`, then for each of its modules in order whether it's active, its target,
+//!   and whatever that module class writes in `write_results`.
+//! * 2 `NewShipsPost`, laid out like `NewShipsPre`. Applies after the turn's results.
+//! * 3 `Tick(Option<u8>)`: the turn is over. `Some(n)` means the client's ship is leaving the
+//!   sector and `n` more turns will be played out before the next `ClientAction`.
+//! * 4 `Chat(ChatMsg)`
+//! * 5 `Snapshot`, followed by whether this turn has already been simulated `bool`, then every
+//!   ship as `Vec<Option<Ship>>` indexed by `ShipIndex`. Sent to a client that resumed its
+//!   session, which should replace its battle with it. If the turn was already simulated, the
+//!   next `Tick` ends a turn the client has no results for and is skipped.
+//! * 6 `CareerStats(CareerStats)`, laid out as for stations. Stats from the turn in progress are
+//!   only counted once it's simulated.
+//! * 7 `Credits(u64)`: the player's balance. Sent on arrival and whenever it changes, like when
+//!   the player's ship destroys another and earns 10 credits per level of the ship destroyed.
+//!
+//! Each turn is 5 seconds. Plans for a turn must arrive in the first 3.5 seconds, when the server
+//! simulates it and sends `NewShipsPre`, `SimResults` and `NewShipsPost`, then `Tick` at the end of
+//! the turn. Both are pushed back by the slowest round trip to a player in the sector, up to a
+//! second, so lagging players' plans still make it.
+//!
+//! `Ship`, `ShipStored` and `ShipPlans` are encoded field by field as declared in `ship`. A
+//! module's inner state is its `ModuleClass` variant index followed by that module type's fields.
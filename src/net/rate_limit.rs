@@ -0,0 +1,110 @@
+use time;
+
+/// Per-client packet budgets enforced by the server master. Budgets are per second. Each second a
+/// client goes over counts as a strike, and each second it stays under takes one away.
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimits {
+    pub packets_per_second: u32,
+    pub bytes_per_second: u64,
+
+    // Strikes at which the client's slot is warned about it, and at which it's disconnected
+    pub warn_strikes: u32,
+    pub disconnect_strikes: u32,
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits {
+            packets_per_second: 50,
+            bytes_per_second: 256 * 1024,
+            warn_strikes: 3,
+            disconnect_strikes: 10,
+        }
+    }
+
+    /// Lets clients send as much as they like
+    pub fn unlimited() -> RateLimits {
+        use std::u32;
+        use std::u64;
+
+        RateLimits {
+            packets_per_second: u32::MAX,
+            bytes_per_second: u64::MAX,
+            warn_strikes: u32::MAX,
+            disconnect_strikes: u32::MAX,
+        }
+    }
+}
+
+/// What happened to a client that went over its budget. The slot it's in is sent
+/// `SlotInMsg::RateLimited` the first time it goes over in any second.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Penalty {
+    Dropped,        // Packets over the budget are being thrown away
+    Warned,         // Same, and it's been doing it for a while
+    Disconnected,   // It kept going and has been cut off
+}
+
+// What to do with one packet
+pub enum Verdict {
+    Pass,
+    Drop,               // Over budget, and already penalized this second
+    Penalize(Penalty),  // Over budget for the first time this second. The packet is dropped too.
+}
+
+// One client's usage of its budget
+pub struct RateLimiter {
+    window_start: time::Timespec,
+    packets: u32,
+    bytes: u64,
+    window_violated: bool,
+    strikes: u32,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            window_start: time::now().to_timespec(),
+            packets: 0,
+            bytes: 0,
+            window_violated: false,
+            strikes: 0,
+        }
+    }
+
+    pub fn check(&mut self, limits: &RateLimits, bytes: usize, now: time::Timespec) -> Verdict {
+        if now - self.window_start >= time::Duration::seconds(1) {
+            // Good behaviour slowly earns strikes back
+            if !self.window_violated && self.strikes > 0 {
+                self.strikes -= 1;
+            }
+
+            self.window_start = now;
+            self.packets = 0;
+            self.bytes = 0;
+            self.window_violated = false;
+        }
+
+        self.packets = self.packets.saturating_add(1);
+        self.bytes = self.bytes.saturating_add(bytes as u64);
+
+        if self.packets <= limits.packets_per_second && self.bytes <= limits.bytes_per_second {
+            return Verdict::Pass;
+        }
+
+        if self.window_violated {
+            return Verdict::Drop;
+        }
+
+        self.window_violated = true;
+        self.strikes += 1;
+
+        if self.strikes >= limits.disconnect_strikes {
+            Verdict::Penalize(Penalty::Disconnected)
+        } else if self.strikes >= limits.warn_strikes {
+            Verdict::Penalize(Penalty::Warned)
+        } else {
+            Verdict::Penalize(Penalty::Dropped)
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+
+use super::{ClientId, ServerSlotId};
+
+/// Packets and bytes through one client or slot. Bytes are packet data, not counting framing or
+/// compression.
+#[derive(Copy, Clone, Debug)]
+pub struct Traffic {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+}
+
+impl Traffic {
+    pub fn new() -> Traffic {
+        Traffic {
+            packets_in: 0,
+            bytes_in: 0,
+            packets_out: 0,
+            bytes_out: 0,
+        }
+    }
+
+    pub fn count_in(&mut self, bytes: usize) {
+        self.packets_in += 1;
+        self.bytes_in += bytes as u64;
+    }
+
+    pub fn count_out(&mut self, bytes: usize) {
+        self.packets_out += 1;
+        self.bytes_out += bytes as u64;
+    }
+}
+
+/// One client as the server master sees it
+#[derive(Clone, Debug)]
+pub struct ClientStats {
+    pub client_id: ClientId,
+    pub slot_id: ServerSlotId,
+    pub traffic: Traffic,
+    pub queue_depth: usize,         // Frames waiting for the client's output thread to write
+    pub idle_ms: u64,               // Since anything last arrived from the client
+    pub latency_ms: Option<u32>,    // Last measured round trip, if there's been one
+    pub suspended: bool,            // Lost its connection and waiting to resume
+    pub compression_ratio: Option<f64>, // Bytes sent per byte of packet data, if compression was negotiated
+}
+
+/// One slot as the server master sees it
+#[derive(Clone, Debug)]
+pub struct SlotStats {
+    pub slot_id: ServerSlotId,
+    pub clients: usize,
+    pub traffic: Traffic,
+    pub idle_ms: Option<u64>,       // Since a packet last went in or out of the slot, if one has
+}
+
+/// Snapshot of every client and slot, from `SlotOutMsg::QueryStats` or a `StatsHandle`
+#[derive(Clone, Debug)]
+pub struct NetStats {
+    pub clients: Vec<ClientStats>,
+    pub slots: Vec<SlotStats>,
+}
+
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{:>6} {:>5} {:>9} {:>11} {:>9} {:>11} {:>6} {:>8} {:>8} {:>6}",
+                      "client", "slot", "pkts in", "bytes in", "pkts out", "bytes out", "queue", "idle ms", "rtt ms", "comp"));
+        for client in self.clients.iter() {
+            let latency =
+                match client.latency_ms {
+                    Some(latency) => format!("{}", latency),
+                    None => "-".to_string(),
+                };
+            let compression =
+                match client.compression_ratio {
+                    Some(ratio) => format!("{:.1}%", ratio * 100.0),
+                    None => "-".to_string(),
+                };
+            try!(writeln!(f, "{:>6} {:>5} {:>9} {:>11} {:>9} {:>11} {:>6} {:>8} {:>8} {:>6}{}",
+                          client.client_id, client.slot_id,
+                          client.traffic.packets_in, client.traffic.bytes_in,
+                          client.traffic.packets_out, client.traffic.bytes_out,
+                          client.queue_depth, client.idle_ms, latency, compression,
+                          if client.suspended { " (suspended)" } else { "" }));
+        }
+
+        try!(writeln!(f, "{:>6} {:>7} {:>9} {:>11} {:>9} {:>11} {:>8}",
+                      "slot", "clients", "pkts in", "bytes in", "pkts out", "bytes out", "idle ms"));
+        for slot in self.slots.iter() {
+            let idle =
+                match slot.idle_ms {
+                    Some(idle) => format!("{}", idle),
+                    None => "-".to_string(),
+                };
+            try!(writeln!(f, "{:>6} {:>7} {:>9} {:>11} {:>9} {:>11} {:>8}",
+                          slot.slot_id, slot.clients,
+                          slot.traffic.packets_in, slot.traffic.bytes_in,
+                          slot.traffic.packets_out, slot.traffic.bytes_out,
+                          idle));
+        }
+
+        Ok(())
+    }
+}
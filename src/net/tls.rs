@@ -0,0 +1,474 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{sleep_ms, spawn};
+use std::time::Duration;
+
+use openssl::crypto::hash::Type as HashType;
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::x509::{X509, X509FileType};
+
+use super::error::{NetError, NetResult};
+use super::handshake::HANDSHAKE_TIMEOUT_MS;
+use super::transport::{accept_backoff_ms, Connection, Listener};
+
+// OpenSSL streams can't be split into separate read and write halves, so both go through one lock.
+// To keep a waiting reader from holding that lock, a thread per connection takes the ciphertext off
+// the socket and readers only lock the stream once there's something for OpenSSL to decrypt.
+
+/// Certificate and private key the server proves itself with, both PEM files
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    pub certificate_file: PathBuf,
+    pub private_key_file: PathBuf,
+}
+
+impl TlsServerConfig {
+    pub fn new<C: Into<PathBuf>, K: Into<PathBuf>>(certificate_file: C, private_key_file: K) -> TlsServerConfig {
+        TlsServerConfig {
+            certificate_file: certificate_file.into(),
+            private_key_file: private_key_file.into(),
+        }
+    }
+}
+
+/// How a client decides to trust the server's certificate
+#[derive(Clone)]
+pub enum TlsTrust {
+    CaFile(PathBuf),    // Certificate must be for the host and signed by one of the PEM certificates in this file
+    Fingerprint(Vec<u8>), // Certificate's SHA-256 fingerprint must be exactly this, for self-signed servers
+}
+
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    pub trust: TlsTrust,
+}
+
+impl TlsClientConfig {
+    /// Reads the client's TLS settings from the environment. REFORGE_TLS_FINGERPRINT pins the
+    /// server's certificate by its SHA-256 fingerprint in hex, colons allowed. Otherwise
+    /// REFORGE_TLS_CA names a PEM file of trusted certificates. With neither, TLS is off.
+    pub fn from_env() -> Option<TlsClientConfig> {
+        if let Ok(fingerprint) = env::var("REFORGE_TLS_FINGERPRINT") {
+            match parse_fingerprint(&fingerprint) {
+                Some(fingerprint) => { return Some(TlsClientConfig { trust: TlsTrust::Fingerprint(fingerprint) }); },
+                None => { println!("WARNING: Ignoring REFORGE_TLS_FINGERPRINT, it isn't a hex fingerprint"); },
+            }
+        }
+
+        match env::var("REFORGE_TLS_CA") {
+            Ok(ca_file) => Some(TlsClientConfig { trust: TlsTrust::CaFile(PathBuf::from(ca_file)) }),
+            Err(_) => None,
+        }
+    }
+}
+
+fn parse_fingerprint(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|b| *b != b':').collect();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut fingerprint = vec!();
+    for pair in digits.chunks(2) {
+        let high = match (pair[0] as char).to_digit(16) { Some(d) => d, None => { return None; } };
+        let low = match (pair[1] as char).to_digit(16) { Some(d) => d, None => { return None; } };
+        fingerprint.push((high * 16 + low) as u8);
+    }
+    Some(fingerprint)
+}
+
+fn tls_error<E: ::std::fmt::Display>(e: E) -> NetError {
+    NetError::Tls(format!("{}", e))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server
+
+/// Accepts TCP connections and does the TLS handshake with each on its own thread, so a slow
+/// client can't hold up the rest
+pub struct TlsListener {
+    incoming: Receiver<TlsStream>,
+}
+
+impl TlsListener {
+    pub fn bind(address: &str, config: &TlsServerConfig) -> NetResult<TlsListener> {
+        let mut context = try!(SslContext::new(SslMethod::Tlsv1_2).map_err(tls_error));
+        try!(context.set_certificate_file(&config.certificate_file, X509FileType::PEM).map_err(tls_error));
+        try!(context.set_private_key_file(&config.private_key_file, X509FileType::PEM).map_err(tls_error));
+        try!(context.check_private_key().map_err(tls_error));
+        context.set_verify(SSL_VERIFY_NONE, None);
+
+        let listener = try!(TcpListener::bind(address));
+        let context = Arc::new(context);
+        let (incoming_t, incoming_r) = channel();
+
+        spawn(move || {
+            let mut failures = 0;
+            for stream in listener.incoming() {
+                let stream =
+                    match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            let backoff_ms = accept_backoff_ms(failures);
+                            failures += 1;
+                            println!("Incoming connection failed, trying again in {} ms: {}", backoff_ms, e);
+                            sleep_ms(backoff_ms);
+                            continue;
+                        },
+                    };
+                failures = 0;
+
+                let context = context.clone();
+                let incoming_t = incoming_t.clone();
+                spawn(move || {
+                    match TlsStream::accept(&context, stream) {
+                        Ok(stream) => { incoming_t.send(stream); },
+                        Err(e) => { println!("Incoming connection failed TLS handshake: {}", e); },
+                    }
+                });
+            }
+        });
+
+        Ok(TlsListener { incoming: incoming_r })
+    }
+}
+
+impl Listener for TlsListener {
+    fn accept(&mut self) -> io::Result<Box<Connection>> {
+        use std::io::{Error, ErrorKind};
+
+        match self.incoming.recv() {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(_) => Err(Error::new(ErrorKind::NotConnected, "TLS listener thread is gone")),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Stream
+
+/// A TLS connection over TCP
+pub struct TlsStream {
+    ssl: Arc<Mutex<SslStream<TlsSocket>>>,
+    inbox: Arc<Inbox>,
+    tcp: TcpStream, // Same socket, for shutting down and naming the peer without the lock
+}
+
+impl TlsStream {
+    fn accept(context: &SslContext, stream: TcpStream) -> NetResult<TlsStream> {
+        // Same deadline as the protocol handshake, for clients that stall this one
+        try!(stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS))));
+        let tcp = try!(stream.try_clone());
+        let ssl = try!(SslStream::accept(context, TlsSocket::new(stream)).map_err(tls_error));
+        try!(tcp.set_read_timeout(None));
+        TlsStream::new(ssl, tcp)
+    }
+
+    /// Connects to a TLS server, checking its certificate the way `config` says
+    pub fn connect(host: &str, config: &TlsClientConfig) -> NetResult<TlsStream> {
+        let mut context = try!(SslContext::new(SslMethod::Tlsv1_2).map_err(tls_error));
+        match config.trust {
+            TlsTrust::CaFile(ref ca_file) => {
+                try!(context.set_CA_file(ca_file).map_err(tls_error));
+                context.set_verify(SSL_VERIFY_PEER, None);
+            },
+            TlsTrust::Fingerprint(_) => {
+                // Checked by hand below, since a pinned certificate is usually self-signed
+                context.set_verify(SSL_VERIFY_NONE, None);
+            },
+        }
+
+        let stream = try!(TcpStream::connect(host));
+        let tcp = try!(stream.try_clone());
+        let ssl = try!(SslStream::connect(&context, TlsSocket::new(stream)).map_err(tls_error));
+
+        let certificate =
+            match ssl.ssl().peer_certificate() {
+                Some(certificate) => certificate,
+                None => { return Err(NetError::Tls("Server sent no certificate".to_string())); },
+            };
+        match config.trust {
+            TlsTrust::CaFile(_) => {
+                // OpenSSL checked who signed it, but not who it was signed for
+                let name = host_name(host);
+                if !certificate_matches(&certificate, name) {
+                    return Err(NetError::Tls(format!("Server certificate isn't for {}", name)));
+                }
+            },
+            TlsTrust::Fingerprint(ref expected) => {
+                if certificate.fingerprint(HashType::SHA256).as_ref() != Some(expected) {
+                    return Err(NetError::Tls("Server certificate doesn't match the pinned fingerprint".to_string()));
+                }
+            },
+        }
+
+        TlsStream::new(ssl, tcp)
+    }
+
+    fn new(mut ssl: SslStream<TlsSocket>, tcp: TcpStream) -> NetResult<TlsStream> {
+        // The handshake is done, from here on OpenSSL reads what the receiver thread collects
+        let inbox = Arc::new(Inbox::new());
+        let receiver_tcp = try!(tcp.try_clone());
+        let receiver_inbox = inbox.clone();
+        spawn(move || {
+            receive_ciphertext(receiver_tcp, &receiver_inbox);
+        });
+        ssl.get_mut().inbox = Some(inbox.clone());
+
+        Ok(TlsStream {
+            ssl: Arc::new(Mutex::new(ssl)),
+            inbox: inbox,
+            tcp: tcp,
+        })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::ErrorKind;
+
+        loop {
+            let result = self.ssl.lock().unwrap().read(buf);
+            match result {
+                // OpenSSL wants more from the socket. Wait for it without the lock so writers get through.
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { try!(self.inbox.wait()); },
+                result => { return result; },
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ssl.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.ssl.lock().unwrap().flush()
+    }
+}
+
+impl Connection for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(TlsStream {
+            ssl: self.ssl.clone(),
+            inbox: self.inbox.clone(),
+            tcp: try!(self.tcp.try_clone()),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.tcp.shutdown(Shutdown::Both)
+    }
+
+    fn peer_name(&self) -> String {
+        match self.tcp.peer_addr() {
+            Ok(addr) => format!("{} (TLS)", addr),
+            Err(_) => "unknown TLS peer".to_string(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        // Reads wait on the inbox, not the socket, so that's where the timeout goes
+        self.inbox.received.lock().unwrap().read_timeout = timeout;
+        Ok(())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Socket
+
+// Ciphertext taken off the socket that OpenSSL hasn't asked for yet
+struct Inbox {
+    received: Mutex<Received>,
+    arrived: Condvar,
+}
+
+struct Received {
+    data: VecDeque<u8>,
+    closed: bool,                       // Socket reached end of stream or failed, nothing more will arrive
+    read_timeout: Option<Duration>,     // See `Connection::set_read_timeout`
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        Inbox {
+            received: Mutex::new(Received { data: VecDeque::new(), closed: false, read_timeout: None }),
+            arrived: Condvar::new(),
+        }
+    }
+
+    // Blocks until there's more ciphertext or the socket is done, or the read timeout runs out
+    fn wait(&self) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        let mut received = self.received.lock().unwrap();
+        while received.data.is_empty() && !received.closed {
+            received =
+                match received.read_timeout {
+                    Some(timeout) => {
+                        let (received, result) = self.arrived.wait_timeout(received, timeout).unwrap();
+                        if result.timed_out() && received.data.is_empty() && !received.closed {
+                            return Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for the socket"));
+                        }
+                        received
+                    },
+                    None => self.arrived.wait(received).unwrap(),
+                };
+        }
+        Ok(())
+    }
+}
+
+fn receive_ciphertext(mut tcp: TcpStream, inbox: &Inbox) {
+    use std::io::ErrorKind;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let size =
+            match tcp.read(&mut buf) {
+                Ok(size) => size,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => { continue; },
+                Err(_) => 0,
+            };
+
+        let mut received = inbox.received.lock().unwrap();
+        if size == 0 {
+            received.closed = true;
+            inbox.arrived.notify_all();
+            break;
+        }
+        received.data.extend(buf[..size].iter().cloned());
+        inbox.arrived.notify_all();
+    }
+}
+
+// What OpenSSL reads from and writes to. During the handshake reads come straight off the socket.
+// After it they come out of the inbox, and never block.
+struct TlsSocket {
+    tcp: TcpStream,
+    inbox: Option<Arc<Inbox>>,
+}
+
+impl TlsSocket {
+    fn new(tcp: TcpStream) -> TlsSocket {
+        TlsSocket {
+            tcp: tcp,
+            inbox: None,
+        }
+    }
+}
+
+impl Read for TlsSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let inbox =
+            match self.inbox {
+                Some(ref inbox) => inbox,
+                None => { return self.tcp.read(buf); },
+            };
+
+        let mut received = inbox.received.lock().unwrap();
+        if received.data.is_empty() {
+            if received.closed {
+                return Ok(0);
+            }
+            return Err(Error::new(ErrorKind::WouldBlock, "Waiting for the socket"));
+        }
+
+        let size = cmp::min(buf.len(), received.data.len());
+        for byte in buf[..size].iter_mut() {
+            *byte = received.data.pop_front().unwrap();
+        }
+        Ok(size)
+    }
+}
+
+impl Write for TlsSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Host names
+
+// The part of a `host:port` address a certificate is issued for
+fn host_name(host: &str) -> &str {
+    let name =
+        match host.rfind(':') {
+            // A bare IPv6 address has colons of its own, only a bracketed one can have a port
+            Some(colon) if host.starts_with('[') || !host[..colon].contains(':') => &host[..colon],
+            _ => host,
+        };
+    name.trim_left_matches('[').trim_right_matches(']')
+}
+
+// Checks the certificate was issued for `name`. Its subjectAltNames decide if it has any, otherwise
+// its common name does (RFC 6125).
+fn certificate_matches(certificate: &X509, name: &str) -> bool {
+    let address: Option<IpAddr> = name.parse().ok();
+
+    if let Some(alt_names) = certificate.subject_alt_names() {
+        let mut has_dns_names = false;
+        for i in 0..alt_names.len() {
+            let alt_name = alt_names.get(i);
+            if let Some(dns_name) = alt_name.dnsname() {
+                has_dns_names = true;
+                if address.is_none() && dns_name_matches(dns_name, name) {
+                    return true;
+                }
+            }
+            if let (Some(alt_address), Some(ref address)) = (alt_name.ipaddress(), address) {
+                if alt_address == &address_bytes(address)[..] {
+                    return true;
+                }
+            }
+        }
+
+        if has_dns_names || address.is_some() {
+            return false;
+        }
+    }
+
+    match certificate.subject_name().text_by_nid(Nid::CN) {
+        Some(common_name) => address.is_none() && dns_name_matches(&common_name, name),
+        None => false,
+    }
+}
+
+// A leading `*.` in the pattern stands for exactly one label
+fn dns_name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+
+    if pattern.starts_with("*.") {
+        match name.find('.') {
+            Some(dot) => dot > 0 && name[dot..] == pattern[1..],
+            None => false,
+        }
+    } else {
+        pattern == name
+    }
+}
+
+fn address_bytes(address: &IpAddr) -> Vec<u8> {
+    match *address {
+        IpAddr::V4(ref address) => address.octets().to_vec(),
+        IpAddr::V6(ref address) => address.segments().iter().flat_map(|s| vec![(*s >> 8) as u8, *s as u8]).collect(),
+    }
+}
@@ -0,0 +1,67 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// A two-way byte stream between a client and the server
+pub trait Connection: Read + Write + Send {
+    /// Makes another handle to the same connection, so reading and writing can happen on different
+    /// threads
+    fn try_clone(&self) -> io::Result<Box<Connection>>;
+
+    /// Closes both directions of the connection. Blocked reads on any handle return end of stream.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Human readable description of the other end, for logging
+    fn peer_name(&self) -> String;
+
+    /// Makes reads that wait longer than `timeout` fail with `TimedOut` or `WouldBlock`. None waits
+    /// forever again.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// Source of incoming connections for `Server`
+pub trait Listener: Send {
+    /// Waits for the next connection. Fails with `NotConnected` once no more can ever arrive, and
+    /// with anything else for failures that might pass.
+    fn accept(&mut self) -> io::Result<Box<Connection>>;
+}
+
+/// How long to wait before accepting again after `failures` failed accepts in a row. Doubles each
+/// time, up to a second.
+pub fn accept_backoff_ms(failures: u32) -> u32 {
+    cmp::min(10 << cmp::min(failures, 7), 1000)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// TCP
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        let stream = try!(TcpStream::try_clone(self));
+        Ok(Box::new(stream))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn peer_name(&self) -> String {
+        match self.peer_addr() {
+            Ok(addr) => format!("{}", addr),
+            Err(_) => "unknown TCP peer".to_string(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> io::Result<Box<Connection>> {
+        let (stream, _) = try!(TcpListener::accept(self));
+        Ok(Box::new(stream))
+    }
+}
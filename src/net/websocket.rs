@@ -0,0 +1,370 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{sleep_ms, spawn};
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use super::handshake::HANDSHAKE_TIMEOUT_MS;
+use super::transport::{accept_backoff_ms, Connection, Listener};
+use super::wire::{read_full, read_u8, read_u16, read_u32};
+
+// WebSocket transport (RFC 6455). The connection carries the same byte stream as TCP, split into
+// binary messages. Whatever a write is given goes out as one message, and incoming messages are
+// joined back into a stream, so a client can split things however suits it.
+
+// Appended to the client's key to prove the server understood the upgrade
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Longest upgrade request we'll read before giving up on the client
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Room for a packet frame's length and kind on top of its data, so one WebSocket message can
+// carry a whole packet frame of the largest allowed size
+const FRAME_OVERHEAD: u64 = 16;
+
+// Opcodes
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Server
+
+/// Accepts WebSocket connections. Each one's HTTP upgrade is done on its own thread, so a slow
+/// client can't hold up the rest. Clients' messages are held to the same max message size as the
+/// packets they carry.
+pub struct WebSocketListener {
+    incoming: Receiver<WebSocketStream>,
+}
+
+impl WebSocketListener {
+    pub fn bind(address: &str, max_message_size: u64) -> io::Result<WebSocketListener> {
+        let listener = try!(TcpListener::bind(address));
+        let (incoming_t, incoming_r) = channel();
+
+        spawn(move || {
+            let mut failures = 0;
+            for stream in listener.incoming() {
+                let stream =
+                    match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            let backoff_ms = accept_backoff_ms(failures);
+                            failures += 1;
+                            println!("Incoming WebSocket connection failed, trying again in {} ms: {}", backoff_ms, e);
+                            sleep_ms(backoff_ms);
+                            continue;
+                        },
+                    };
+                failures = 0;
+
+                let incoming_t = incoming_t.clone();
+                spawn(move || {
+                    match WebSocketStream::accept(stream, max_message_size) {
+                        Ok(stream) => { incoming_t.send(stream); },
+                        Err(e) => { println!("Incoming connection failed WebSocket upgrade: {}", e); },
+                    }
+                });
+            }
+        });
+
+        Ok(WebSocketListener { incoming: incoming_r })
+    }
+}
+
+impl Listener for WebSocketListener {
+    fn accept(&mut self) -> io::Result<Box<Connection>> {
+        use std::io::{Error, ErrorKind};
+
+        match self.incoming.recv() {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(_) => Err(Error::new(ErrorKind::NotConnected, "WebSocket listener thread is gone")),
+        }
+    }
+}
+
+// Reads the client's upgrade request and returns its Sec-WebSocket-Key
+fn read_upgrade_request<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    use std::io::{Error, ErrorKind};
+
+    let mut request_size = 0;
+    let mut upgrade = false;
+    let mut key = None;
+
+    let mut first_line = true;
+    loop {
+        let mut line = String::new();
+        let line_size = try!(reader.read_line(&mut line));
+        if line_size == 0 {
+            return Err(Error::new(ErrorKind::Other, "Connection closed during upgrade request"));
+        }
+
+        request_size += line_size;
+        if request_size > MAX_REQUEST_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "Upgrade request is too long"));
+        }
+
+        let line = line.trim();
+        if first_line {
+            if !line.starts_with("GET ") {
+                return Err(Error::new(ErrorKind::InvalidInput, "Upgrade request isn't a GET"));
+            }
+            first_line = false;
+            continue;
+        }
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim().to_ascii_lowercase();
+            let value = line[colon + 1..].trim();
+
+            match &name[..] {
+                "upgrade" => { upgrade = value.eq_ignore_ascii_case("websocket"); },
+                "sec-websocket-key" => { key = Some(value.to_string()); },
+                _ => { },
+            }
+        }
+    }
+
+    match (upgrade, key) {
+        (true, Some(key)) => Ok(key),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "Not a WebSocket upgrade request")),
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(ACCEPT_GUID);
+
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    digest.to_base64(STANDARD)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Stream
+
+/// A WebSocket connection from a client
+pub struct WebSocketStream {
+    reader: Arc<Mutex<MessageReader>>,
+    writer: Arc<Mutex<TcpStream>>,  // Locked so pongs from the reader don't land inside a message
+    tcp: TcpStream,                 // Same socket, for shutting down and naming the peer without a lock
+}
+
+impl WebSocketStream {
+    fn accept(stream: TcpStream, max_message_size: u64) -> io::Result<WebSocketStream> {
+        // Give up on clients that open a socket and never send the upgrade request
+        try!(stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS))));
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        let mut writer = try!(stream.try_clone());
+
+        let key =
+            match read_upgrade_request(&mut reader) {
+                Ok(key) => key,
+                Err(e) => {
+                    writer.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+                    return Err(e);
+                },
+            };
+
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                Upgrade: websocket\r\n\
+                                Connection: Upgrade\r\n\
+                                Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key));
+        try!(writer.write_all(response.as_bytes()));
+        try!(stream.set_read_timeout(None));
+
+        Ok(WebSocketStream {
+            reader: Arc::new(Mutex::new(MessageReader {
+                stream: reader,
+                data: vec!(),
+                pos: 0,
+                closed: false,
+                max_frame_size: max_message_size + FRAME_OVERHEAD,
+            })),
+            writer: Arc::new(Mutex::new(writer)),
+            tcp: stream,
+        })
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        reader.read(buf, &self.writer)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        try!(write_message(&mut *writer, OP_BINARY, buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl Connection for WebSocketStream {
+    fn try_clone(&self) -> io::Result<Box<Connection>> {
+        Ok(Box::new(WebSocketStream {
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            tcp: try!(self.tcp.try_clone()),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        // Say goodbye properly if we can, but the socket's closing either way
+        if let Ok(mut writer) = self.writer.try_lock() {
+            write_message(&mut *writer, OP_CLOSE, &[]);
+        }
+        self.tcp.shutdown(Shutdown::Both)
+    }
+
+    fn peer_name(&self) -> String {
+        match self.tcp.peer_addr() {
+            Ok(addr) => format!("{} (WebSocket)", addr),
+            Err(_) => "unknown WebSocket peer".to_string(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_read_timeout(timeout)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Messages
+
+// Turns the client's messages back into a byte stream
+struct MessageReader {
+    stream: BufReader<TcpStream>,
+    data: Vec<u8>,  // Payload of the last data frame
+    pos: usize,     // How much of it has been read
+    closed: bool,
+    max_frame_size: u64,    // Largest frame payload the client may send
+}
+
+impl MessageReader {
+    fn read(&mut self, buf: &mut [u8], writer: &Mutex<TcpStream>) -> io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        loop {
+            if self.pos < self.data.len() {
+                let size = cmp::min(buf.len(), self.data.len() - self.pos);
+                buf[..size].clone_from_slice(&self.data[self.pos..self.pos + size]);
+                self.pos += size;
+                return Ok(size);
+            }
+
+            if self.closed {
+                return Ok(0);
+            }
+
+            let (opcode, payload) = try!(read_message_frame(&mut self.stream, self.max_frame_size));
+            match opcode {
+                OP_CONTINUATION | OP_BINARY => {
+                    self.data = payload;
+                    self.pos = 0;
+                },
+                OP_PING => {
+                    let mut writer = writer.lock().unwrap();
+                    try!(write_message(&mut *writer, OP_PONG, &payload));
+                },
+                OP_PONG => { },
+                OP_CLOSE => {
+                    let mut writer = writer.lock().unwrap();
+                    write_message(&mut *writer, OP_CLOSE, &[]);
+                    self.closed = true;
+                },
+                OP_TEXT => { return Err(Error::new(ErrorKind::InvalidInput, "WebSocket clients must send binary messages")); },
+                _ => { return Err(Error::new(ErrorKind::InvalidInput, "Unknown WebSocket opcode")); },
+            }
+        }
+    }
+}
+
+// Reads one frame from the client. Returns its opcode and unmasked payload. Frames of a
+// fragmented message are returned one at a time, which is fine since it's all one stream anyway.
+fn read_message_frame<R: Read>(reader: &mut R, max_size: u64) -> io::Result<(u8, Vec<u8>)> {
+    use std::io::{Error, ErrorKind};
+
+    let first = try!(read_u8(reader));
+    let second = try!(read_u8(reader));
+    let opcode = first & 0x0F;
+    let masked = second & 0x80 != 0;
+
+    let size =
+        match second & 0x7F {
+            126 => try!(read_u16(reader)) as u64,
+            127 => ((try!(read_u32(reader)) as u64) << 32) | (try!(read_u32(reader)) as u64),
+            size => size as u64,
+        };
+
+    // Never more than one packet frame's worth
+    if size > max_size {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("WebSocket frame of {} bytes is too big", size)));
+    }
+
+    // Clients always mask what they send
+    if !masked {
+        return Err(Error::new(ErrorKind::InvalidInput, "WebSocket frame from client isn't masked"));
+    }
+
+    let mut mask = [0u8; 4];
+    try!(read_full(reader, &mut mask));
+
+    // The payload grows as it arrives, so a client can't make us allocate a whole frame's worth
+    // by claiming a big one and stalling
+    let mut payload = vec!();
+    let bytes_read = try!(reader.take(size).read_to_end(&mut payload));
+    if bytes_read as u64 != size {
+        return Err(Error::new(ErrorKind::Other, format!("Expected {} bytes of WebSocket frame, got {} bytes", size, bytes_read)));
+    }
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok((opcode, payload))
+}
+
+// Writes one unfragmented, unmasked message from the server
+fn write_message<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    let size = payload.len();
+    if size < 126 {
+        frame.push(size as u8);
+    } else if size <= 0xFFFF {
+        frame.push(126);
+        frame.push((size >> 8) as u8);
+        frame.push(size as u8);
+    } else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push(((size as u64) >> (shift * 8)) as u8);
+        }
+    }
+
+    frame.extend(payload.iter().cloned());
+    writer.write_all(&frame)
+}
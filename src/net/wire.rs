@@ -0,0 +1,92 @@
+use std::io;
+use std::io::{Read, Write};
+
+// Primitive values used by the handshake and framing. Multi-byte integers are big endian, the same
+// as bincode uses for packet contents, so the whole protocol has one byte order whatever the host.
+// Reads keep going until the value is complete, since a socket can hand back fewer bytes than asked
+// for without anything being wrong.
+
+/// Fills `buf` completely, retrying short and interrupted reads. End of stream partway through is
+/// an error.
+pub fn read_full<T: Read>(reader: &mut T, buf: &mut [u8]) -> io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => {
+                return Err(Error::new(ErrorKind::Other,
+                                      format!("Connection closed after {} of {} bytes", filled, buf.len())));
+            },
+            Ok(bytes_read) => { filled += bytes_read; },
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => { },
+            Err(e) => { return Err(e); },
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_u8<T: Read>(reader: &mut T) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    try!(read_full(reader, &mut buf));
+    Ok(buf[0])
+}
+
+pub fn write_u8<T: Write>(writer: &mut T, data: u8) -> io::Result<()> {
+    writer.write_all(&[data])
+}
+
+pub fn read_u16<T: Read>(reader: &mut T) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    try!(read_full(reader, &mut buf));
+    Ok(((buf[0] as u16) << 8) | (buf[1] as u16))
+}
+
+pub fn write_u16<T: Write>(writer: &mut T, data: u16) -> io::Result<()> {
+    writer.write_all(&[(data >> 8) as u8, data as u8])
+}
+
+pub fn read_u32<T: Read>(reader: &mut T) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    try!(read_full(reader, &mut buf));
+    Ok(((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32))
+}
+
+pub fn write_u32<T: Write>(writer: &mut T, data: u32) -> io::Result<()> {
+    writer.write_all(&[(data >> 24) as u8, (data >> 16) as u8, (data >> 8) as u8, data as u8])
+}
+
+pub fn read_u64<T: Read>(reader: &mut T) -> io::Result<u64> {
+    let high = try!(read_u32(reader)) as u64;
+    let low = try!(read_u32(reader)) as u64;
+    Ok((high << 32) | low)
+}
+
+pub fn write_u64<T: Write>(writer: &mut T, data: u64) -> io::Result<()> {
+    try!(write_u32(writer, (data >> 32) as u32));
+    write_u32(writer, data as u32)
+}
+
+// Strings are a u16 byte length followed by UTF-8 data
+pub fn read_string<T: Read>(reader: &mut T) -> io::Result<String> {
+    use std::io::{Error, ErrorKind};
+
+    let len = try!(read_u16(reader)) as usize;
+    let mut data = vec![0u8; len];
+    try!(read_full(reader, &mut data));
+
+    String::from_utf8(data).map_err(|_| Error::new(ErrorKind::InvalidInput, "String is not valid UTF-8"))
+}
+
+pub fn write_string<T: Write>(writer: &mut T, data: &str) -> io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::u16;
+
+    if data.len() > u16::MAX as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "String is too long to send"));
+    }
+
+    try!(write_u16(writer, data.len() as u16));
+    writer.write_all(data.as_bytes())
+}
@@ -1,4 +1,6 @@
 use chat::ChatMsg;
+use login::CareerStats;
+use star_map::station::HangarShip;
 
 // Packets sent from client to server
 #[derive(RustcEncodable, RustcDecodable)]
@@ -6,6 +8,7 @@ pub enum ServerBattlePacket {
     Plan,
     Chat(String),
     Logout,
+    CareerStats,      // Ask for the player's career stats
 }
 
 // Packets sent from server to client
@@ -16,4 +19,18 @@ pub enum ClientBattlePacket {
     NewShipsPost,
     Tick(Option<u8>), // Tick and whether it's the last
     Chat(ChatMsg),
+    Snapshot,         // The whole battle, for a client that reconnected
+    CareerStats(CareerStats),
+    Credits(u64),     // The player's balance, whenever it changes
+}
+
+// Packets sent from a station to its clients
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum ClientStationPacket {
+    Chat(ChatMsg),
+    CareerStats(CareerStats),
+    Credits(u64),     // The player's balance, whenever it changes
+    Hangar(Vec<HangarShip>),
+    HangarError(String),
+    ActiveShip,       // Followed by the player's new active ship
 }
\ No newline at end of file
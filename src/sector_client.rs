@@ -16,7 +16,7 @@ use window::Window;
 use asset_store::AssetStore;
 use battle_context::{BattleContext, TICKS_PER_SECOND};
 use chat::ChatGui;
-use net::{Client, InPacket, OutPacket};
+use net::{Client, InPacket, NetResult, OutPacket};
 use packet_types::{ClientBattlePacket, ServerBattlePacket};
 use sector_data::SectorData;
 use ship::{Ship, ShipId, ShipIndex};
@@ -37,6 +37,10 @@ pub struct ClientBattleState<'a> {
     new_ships_post: Option<InPacket>,
     
     final_ticks: Option<u8>,
+    
+    // Set after reconnecting partway through a turn whose results were lost. The rest of that
+    // turn is skipped.
+    skip_turn: bool,
 }
 
 impl<'a> ClientBattleState<'a> {
@@ -50,6 +54,7 @@ impl<'a> ClientBattleState<'a> {
             results: None,
             new_ships_post: None,
             final_ticks: None,
+            skip_turn: false,
         }
     }
     
@@ -60,7 +65,7 @@ impl<'a> ClientBattleState<'a> {
                asset_store: &AssetStore,
                chat_gui: &mut ChatGui,
                sectors: Vec<SectorData>,
-               server_results_sent: bool) {
+               server_results_sent: bool) -> NetResult<()> {
         use window::Window;
     
         let ref mut gui = SpaceGui::new(asset_store, &self.bc, chat_gui, sectors, self.player_ship);
@@ -73,7 +78,7 @@ impl<'a> ClientBattleState<'a> {
             // Wait for the tick
             loop {
                 // We might get chat packets here
-                let tick_packet = self.client.receive();
+                let tick_packet = try!(self.client.receive());
                 let ticked = self.handle_packet(gui, tick_packet);
                 
                 if ticked {
@@ -85,7 +90,7 @@ impl<'a> ClientBattleState<'a> {
         // Get first turn's results
         loop {
             // Loop until tick packet is received
-            let packet = self.client.receive();
+            let packet = try!(self.client.receive());
             let ticked = self.handle_packet(gui, packet);
             if ticked {
                 break;
@@ -112,7 +117,7 @@ impl<'a> ClientBattleState<'a> {
             self.handle_new_ships_packet(gui, &mut new_ships_pre);
             self.handle_simulation_results(&mut results);
             
-            self.run_simulation_phase(window, gl, glyph_cache, asset_store, gui, sim_effects);
+            try!(self.run_simulation_phase(window, gl, glyph_cache, asset_store, gui, sim_effects));
             
             // Receive ships after sim
             self.handle_new_ships_packet(gui, &mut new_ships_post);
@@ -120,6 +125,8 @@ impl<'a> ClientBattleState<'a> {
             // Check if it's time to exit
             if window.borrow().should_close() { break; }
         }
+        
+        Ok(())
     }
     
     fn run_simulation_phase(&mut self,
@@ -128,7 +135,7 @@ impl<'a> ClientBattleState<'a> {
                             glyph_cache: &mut GlyphCache,
                             asset_store: &AssetStore,
                             gui: &mut SpaceGui,
-                            mut sim_effects: &mut SimEffects) -> bool {
+                            mut sim_effects: &mut SimEffects) -> NetResult<bool> {
         // Unlock any exploding or jumping ships
         let ships_to_unlock: Vec<ShipIndex> =
             self.bc.ships_iter()
@@ -168,14 +175,14 @@ impl<'a> ClientBattleState<'a> {
             if !self.final_ticks.is_some() && !self.player_ship.get(&self.bc).exploding && !plans_sent && elapsed_seconds >= 2.5 {
                 // Send plans
                 let packet = self.build_plans_packet(gui);
-                self.client.send(&packet);
+                try!(self.client.send(&packet));
                 plans_sent = true;
                 println!("Sent plans at {}", elapsed_seconds);
             }
             
             if !self.final_ticks.is_some() {
                 if plans_sent || self.player_ship.get(&self.bc).exploding {
-                    if let Ok(packet) = self.client.try_receive() {
+                    if let Some(packet) = try!(self.client.try_receive()) {
                         let ticked = self.handle_packet(gui, packet);
                         
                         if ticked && !self.final_ticks.is_some() {
@@ -207,10 +214,13 @@ impl<'a> ClientBattleState<'a> {
             if let Some(gui_action) = gui_action {
                 match gui_action {
                     SpaceGuiAction::Chat(msg) => {
-                        self.send_chat(msg);
+                        try!(self.send_chat(msg));
+                    },
+                    SpaceGuiAction::CareerStats => {
+                        try!(self.send_career_stats_request());
                     },
                     SpaceGuiAction::Logout => {
-                        self.send_logout();
+                        try!(self.send_logout());
                     },
                 }
             }
@@ -244,7 +254,7 @@ impl<'a> ClientBattleState<'a> {
             }
         }
         
-        logging_out
+        Ok(logging_out)
     }
     
     fn build_plans_packet(&mut self, gui: &SpaceGui) -> OutPacket {
@@ -254,16 +264,22 @@ impl<'a> ClientBattleState<'a> {
         packet
     }
     
-    fn send_chat(&mut self, msg: String) {
+    fn send_chat(&mut self, msg: String) -> NetResult<()> {
         let mut packet = OutPacket::new();
         packet.write(&ServerBattlePacket::Chat(msg)).unwrap();
-        self.client.send(&packet);
+        self.client.send(&packet)
     }
     
-    fn send_logout(&mut self) {
+    fn send_career_stats_request(&mut self) -> NetResult<()> {
+        let mut packet = OutPacket::new();
+        packet.write(&ServerBattlePacket::CareerStats).unwrap();
+        self.client.send(&packet)
+    }
+    
+    fn send_logout(&mut self) -> NetResult<()> {
         let mut packet = OutPacket::new();
         packet.write(&ServerBattlePacket::Logout).unwrap();
-        self.client.send(&packet);
+        self.client.send(&packet)
     }
     
     fn handle_packet(&mut self, gui: &mut SpaceGui, mut packet: InPacket) -> bool {
@@ -280,12 +296,38 @@ impl<'a> ClientBattleState<'a> {
                 self.new_ships_post = Some(packet);
             },
             ClientBattlePacket::Tick(final_ticks) => {
+                if self.skip_turn {
+                    self.skip_turn = false;
+                    return false;
+                }
+                
                 self.final_ticks = final_ticks;
                 return true;
             },
             ClientBattlePacket::Chat(msg) => {
                 gui.chat_gui.add_message(msg);
             },
+            ClientBattlePacket::CareerStats(stats) => {
+                gui.chat_gui.show_career_stats(&stats);
+            },
+            ClientBattlePacket::Credits(credits) => {
+                gui.credits = Some(credits);
+            },
+            ClientBattlePacket::Snapshot => {
+                // We reconnected and may have missed anything, so start over from the server's
+                // copy of the battle
+                let simulated_turn: bool = packet.read().ok().expect("Failed to read simulated turn from snapshot");
+                let ships: Vec<Option<Ship>> = packet.read().ok().expect("Failed to read ships from snapshot");
+                
+                self.bc = BattleContext::new(ships);
+                self.player_ship = self.bc.get_ship_by_client_id(self.client.get_id()).index;
+                gui.set_client_ship(self.player_ship.get(&self.bc));
+                
+                self.new_ships_pre = None;
+                self.results = None;
+                self.new_ships_post = None;
+                self.skip_turn = simulated_turn;
+            },
         }
         
         false
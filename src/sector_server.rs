@@ -9,21 +9,52 @@ use time;
 use ai::run_ai;
 use battle_context::BattleContext;
 use chat::ChatMsg;
-use login::AccountBox;
+use event_mux::EventMux;
+use login::{moderator_command, send_balance, AccountBox, ModRequest};
 use module::Module;
 use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
 use packet_types::{ClientBattlePacket, ServerBattlePacket};
 use ship::{Ship, ShipId, ShipIndex, ShipPlans, ShipStored};
-use sim::SimEvents;
+use sim::{SimEvents, SimOutcome};
 use star_map::StarMapAction;
 
+// Round trip time above which a client is reported as lagging
+const LAG_WARNING_MS: u32 = 1000;
+
+// Time into each turn at which the turn is simulated, and the length of a whole turn, unless set
+// otherwise with `set_turn_timing`
+pub const SIMULATE_TURN_MS: u32 = 3500;
+pub const TURN_LENGTH_MS: u32 = 5000;
+
+// Most a turn is held back so a lagging client's plans can still make it
+const MAX_LAG_ALLOWANCE_MS: u32 = 1000;
+
+// Credits for destroying a ship, per level of the ship destroyed
+const KILL_REWARD_PER_LEVEL: u64 = 10;
+
+// Turns between snapshots of everyone's account sent to the login server to save, about a minute
+const SNAPSHOT_TURNS: u32 = 12;
+
+// Everything a sector waits on
+enum SectorEvent {
+    Slot(SlotInMsg),
+    Chat(ChatMsg),
+    FromMap(AccountBox),
+    MapClosed,      // The star map let go of this sector, so it should wind down
+    SimulateTurn,
+    EndTurn,
+}
+
 pub struct SectorState {
     slot: ServerSlot,
     star_map_slot_id: ServerSlotId,
     chat_sender: Sender<ChatMsg>,
-    chat_receiver: Receiver<ChatMsg>,
+    mod_sender: Sender<ModRequest>,
     to_map_sender: Sender<(AccountBox, StarMapAction)>,
-    from_map_receiver: Receiver<AccountBox>,
+    save_sender: Sender<AccountBox>,
+    
+    // Slot messages, chat, accounts arriving from the star map and turn timers
+    events: EventMux<SectorEvent>,
 
     // Context holding all the things involved in this battle
     context: BattleContext,
@@ -31,6 +62,9 @@ pub struct SectorState {
     turn_start_time: time::Timespec,
     simulated_turn: bool,
     
+    simulate_turn_ms: u32,
+    turn_length_ms: u32,
+    
     received_plans: HashSet<ClientId>,
     clients_waiting: HashSet<ClientId>,
     clients_active: HashSet<ClientId>,
@@ -41,6 +75,9 @@ pub struct SectorState {
     // All the clients' accounts
     accounts: HashMap<ClientId, AccountBox>,
     
+    // Last measured round trip time to each client in milliseconds, used to stretch turns for lag
+    client_latencies: HashMap<ClientId, u32>,
+    
     // Ships to add after simulation
     ships_to_add: Vec<ShipIndex>,
     
@@ -51,41 +88,67 @@ pub struct SectorState {
     
     turn_number: u32,
     
+    // Set once the server starts shutting down. Every ship is logged out at the end of the turn.
+    shutting_down: bool,
+    
+    // Set once the star map closes this sector. Once every ship has been logged out, the slot is
+    // destroyed and `run` returns.
+    closing: bool,
+    
     debug: bool,
 }
 
 impl SectorState {
-    pub fn new(slot: ServerSlot,
+    pub fn new(mut slot: ServerSlot,
                star_map_slot_id: ServerSlotId,
                chat_sender: Sender<ChatMsg>,
                chat_receiver: Receiver<ChatMsg>,
+               mod_sender: Sender<ModRequest>,
                to_map_sender: Sender<(AccountBox, StarMapAction)>,
                from_map_receiver: Receiver<AccountBox>,
+               save_sender: Sender<AccountBox>,
                context: BattleContext,
                debug: bool) -> SectorState {
+        let events = EventMux::new();
+        slot.forward_incoming(&events, SectorEvent::Slot);
+        events.forward(chat_receiver, SectorEvent::Chat);
+        events.forward_then(from_map_receiver, SectorEvent::FromMap, SectorEvent::MapClosed);
+        
         SectorState {
             slot: slot,
             star_map_slot_id: star_map_slot_id,
             chat_sender: chat_sender,
-            chat_receiver: chat_receiver,
+            mod_sender: mod_sender,
             to_map_sender: to_map_sender,
-            from_map_receiver: from_map_receiver,
+            save_sender: save_sender,
+            events: events,
             context: context,
             turn_start_time: time::now().to_timespec(),
             simulated_turn: false,
+            simulate_turn_ms: SIMULATE_TURN_MS,
+            turn_length_ms: TURN_LENGTH_MS,
             received_plans: HashSet::new(),
             clients_waiting: HashSet::new(),
             clients_active: HashSet::new(),
             ship_plans: vec!(),
             accounts: HashMap::new(),
+            client_latencies: HashMap::new(),
             ships_to_add: vec!(),
             ships_to_remove: vec!(),
             ships_to_logout: vec!(),
             turn_number: 0,
+            shutting_down: false,
+            closing: false,
             debug: debug,
         }
     }
     
+    /// Sets how far into each turn it's simulated and how long a whole turn is
+    pub fn set_turn_timing(&mut self, simulate_turn_ms: u32, turn_length_ms: u32) {
+        self.simulate_turn_ms = simulate_turn_ms;
+        self.turn_length_ms = turn_length_ms;
+    }
+    
     pub fn run(&mut self, ack: Sender<()>, create_ai: bool) {
         if create_ai {
             // TODO: come up with better way to generate AI ship IDs
@@ -102,89 +165,180 @@ impl SectorState {
             self.context.add_ship(ai_ship);
         }
     
+        self.start_turn_timers();
+    
         loop {
-            ///////////////////////////////////////////////////////////
-            // Check if it's time to simulate next turn
-            
-            // Get the current time from our turn timer
-            let turn_time = time::now().to_timespec() - self.turn_start_time;
-            
-            if !self.simulated_turn && turn_time.num_milliseconds() >= 3500 {
-                self.simulate_next_turn();
-
-                self.simulated_turn = true;
-            }
-            
-            if turn_time.num_milliseconds() >= 5000 {
-                // Reset the turn stuff
-                self.simulated_turn = false;
-                self.turn_start_time = time::now().to_timespec();
+            match self.events.recv() {
+                ///////////////////////////////////////////////////////////
+                // Turn timers
+                SectorEvent::SimulateTurn => {
+                    self.simulate_next_turn();
+                    
+                    self.simulated_turn = true;
+                },
                 
-                self.send_turn_tick();
-            }
-        
-            ///////////////////////////////////////////////////////////
-            // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
-                match msg {
+                SectorEvent::EndTurn => {
+                    // Reset the turn stuff
+                    self.simulated_turn = false;
+                    
+                    if self.closing && self.accounts.is_empty() {
+                        // Everyone's been handed back. With no new turn scheduled, the timers stop too.
+                        println!("Sector {} closed", self.slot.get_id());
+                        self.slot.destroy_slot(self.slot.get_id(), Some(self.star_map_slot_id));
+                        break;
+                    }
+                    
+                    self.start_turn_timers();
+                    
+                    self.send_turn_tick();
+                    
+                    if self.turn_number % SNAPSHOT_TURNS == 0 {
+                        self.save_accounts();
+                    }
+                    
+                    if self.shutting_down {
+                        if self.accounts.is_empty() {
+                            self.slot.shutdown_complete();
+                        } else {
+                            // Anyone who arrived since still needs to go
+                            self.log_out_everyone();
+                        }
+                    }
+                },
+                
+                ///////////////////////////////////////////////////////////
+                // Receiver ServerSlot messages
+                SectorEvent::Slot(msg) => match msg {
                     SlotInMsg::Joined(client_id) => {
                         println!("Client {} joined battle {}", client_id, self.slot.get_id());
                     },
                     SlotInMsg::Disconnected(client_id) => {
                         println!("Client {} disconnected at station {}, logging out...", client_id, self.slot.get_id());
                         
-                        let ship = self.context.get_ship_by_client_id(client_id);
-                        self.ships_to_logout.push(ship.index);
+                        let ship_index = self.context.get_ship_by_client_id(client_id).index;
+                        if !self.ships_to_logout.contains(&ship_index) {
+                            self.ships_to_logout.push(ship_index);
+                        }
+                        self.client_latencies.remove(&client_id);
                     },
                     SlotInMsg::ReceivedPacket(client_id, mut packet) => {
                         self.handle_packet(client_id, &mut packet);
                     },
-                }
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive messages from chat server
-            if let Ok(msg) = self.chat_receiver.try_recv() {
-                let mut msg_packet = OutPacket::new();
-                msg_packet.write(&ClientBattlePacket::Chat(msg)).unwrap();
-                self.slot.broadcast(msg_packet);
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive new clients
-            if let Ok(mut account) = self.from_map_receiver.try_recv() {
-                if self.debug {
-                    println!("Receiving account");
-                }
-                println!("Receiving account {}", self.simulated_turn);
-                let client_id = account.client_id.expect("This must have a client ID");
+                    SlotInMsg::Latency(client_id, latency) => {
+                        if latency >= LAG_WARNING_MS {
+                            println!("Client {} is lagging in battle {}: {} ms round trip", client_id, self.slot.get_id(), latency);
+                        }
+                        self.client_latencies.insert(client_id, latency);
+                    },
+                    SlotInMsg::SlotDestroyed(_) => { },
+                    SlotInMsg::NetStats(_) => { },
+                    SlotInMsg::RateLimited(client_id, penalty) => {
+                        if let Some(account) = self.accounts.get(&client_id) {
+                            println!("Client {} ({}) flooding battle {}: {:?}", client_id, account.username, self.slot.get_id(), penalty);
+                        }
+                    },
+                    SlotInMsg::Resumed(client_id) => {
+                        println!("Client {} reconnected to battle {}", client_id, self.slot.get_id());
+                        self.send_snapshot(client_id);
+                    },
+                    SlotInMsg::ShuttingDown => {
+                        // Finish the current turn, then log everyone out with the next one
+                        self.shutting_down = true;
+                        self.log_out_everyone();
+                    },
+                },
                 
-                // Add the client to the waiting list
-                self.clients_waiting.insert(client_id);
+                ///////////////////////////////////////////////////////////
+                // Receive messages from chat server
+                SectorEvent::Chat(msg) => {
+                    let mut msg_packet = OutPacket::new();
+                    msg_packet.write(&ClientBattlePacket::Chat(msg)).unwrap();
+                    self.slot.broadcast(msg_packet);
+                },
                 
-                // Get the ship out of storage
-                let ship_stored = account.ship.take().expect("This account must have a ship");
-                let ship = ship_stored.to_ship(Some(client_id));
+                SectorEvent::MapClosed => {
+                    // Everyone leaves with the next turn, like at shutdown
+                    println!("Closing sector {}", self.slot.get_id());
+                    self.closing = true;
+                    self.log_out_everyone();
+                },
                 
-                // Add the player's account
-                self.accounts.insert(client_id, account);
+                ///////////////////////////////////////////////////////////
+                // Receive new clients
+                SectorEvent::FromMap(mut account) => {
+                    if self.debug {
+                        println!("Receiving account");
+                    }
+                    println!("Receiving account {}", self.simulated_turn);
+                    let client_id = account.client_id.expect("This must have a client ID");
                 
-                // Send initial join packet
-                let mut packet = OutPacket::new();
-                packet.write(&ship).unwrap();
-                packet.write(&self.simulated_turn).unwrap(); // Whether or not to start at simulation instead of planning phase
-                packet.write(&self.context.ships).unwrap();
-                self.slot.send(client_id, packet);
+                    // Add the client to the waiting list
+                    self.clients_waiting.insert(client_id);
                 
-                // Add the player's ship
-                let ship_index = self.context.add_ship(ship);
-                self.ships_to_add.push(ship_index);
+                    account.career.battles += 1;
                 
-                ack.send(());
+                    // Get the ship out of storage
+                    let ship_stored = account.ship.take().expect("This account must have a ship");
+                    let ship = ship_stored.to_ship(Some(client_id));
+                
+                    // Add the player's account
+                    self.accounts.insert(client_id, account);
+                
+                    // Send initial join packet
+                    let mut packet = OutPacket::new();
+                    packet.write(&ship).unwrap();
+                    packet.write(&self.simulated_turn).unwrap(); // Whether or not to start at simulation instead of planning phase
+                    packet.write(&self.context.ships).unwrap();
+                    self.slot.send(client_id, packet);
+                    send_balance(&self.slot, client_id, &self.accounts[&client_id].wallet, ClientBattlePacket::Credits);
+                
+                    // Add the player's ship
+                    let ship_index = self.context.add_ship(ship);
+                    self.ships_to_add.push(ship_index);
+                
+                    ack.send(());
+                },
             }
         }
     }
     
+    // Sends a client that lost packets everything it needs to pick the battle back up
+    fn send_snapshot(&mut self, client_id: ClientId) {
+        let mut packet = OutPacket::new();
+        packet.write(&ClientBattlePacket::Snapshot).unwrap();
+        packet.write(&self.simulated_turn).unwrap(); // Whether this turn's results have already gone out
+        packet.write(&self.context.ships).unwrap();
+        self.slot.send(client_id, packet);
+    }
+    
+    // Sends the login server a copy of every account here, with its ship as it is now
+    fn save_accounts(&self) {
+        for (client_id, account) in self.accounts.iter() {
+            let ship = self.context.get_ship_by_client_id(*client_id);
+            self.save_sender.send(account.snapshot(Some(ship)));
+        }
+    }
+    
+    // Logs out every player ship when the next turn is simulated
+    fn log_out_everyone(&mut self) {
+        for client_id in self.accounts.keys() {
+            let ship_index = self.context.get_ship_by_client_id(*client_id).index;
+            if !self.ships_to_logout.contains(&ship_index) {
+                self.ships_to_logout.push(ship_index);
+            }
+        }
+    }
+    
+    // Schedules this turn's simulation and end, counting from now. Both are pushed back by the
+    // slowest client's round trip, so plans sent at the end of its planning phase arrive in time.
+    fn start_turn_timers(&mut self) {
+        let lag_allowance = cmp::min(self.client_latencies.values().cloned().max().unwrap_or(0), MAX_LAG_ALLOWANCE_MS);
+        
+        self.turn_start_time = time::now().to_timespec();
+        self.events.schedule(self.simulate_turn_ms + lag_allowance, SectorEvent::SimulateTurn);
+        self.events.schedule(self.turn_length_ms + lag_allowance, SectorEvent::EndTurn);
+    }
+    
     fn handle_packet(&mut self, client_id: ClientId, packet: &mut InPacket) {
         let battle_packet: ServerBattlePacket = packet.read().unwrap();
         
@@ -196,6 +350,22 @@ impl SectorState {
                 }
                 
                 let ref account = self.accounts[&client_id];
+                
+                // Moderator commands go to the login server instead of chat
+                match moderator_command(account, &msg) {
+                    Some(Ok(request)) => {
+                        println!("Moderator command from {}", request);
+                        self.mod_sender.send(request);
+                        return;
+                    },
+                    Some(Err(reply)) => {
+                        let mut packet = OutPacket::new();
+                        packet.write(&ClientBattlePacket::Chat(reply)).unwrap();
+                        self.slot.send(client_id, packet);
+                        return;
+                    },
+                    None => { },
+                }
             
                 let msg = ChatMsg {
                     author_name: account.username.clone(),
@@ -208,6 +378,11 @@ impl SectorState {
                 let ship = self.context.get_ship_by_client_id(client_id);
                 self.ships_to_logout.push(ship.index);
             },
+            ServerBattlePacket::CareerStats => {
+                let mut packet = OutPacket::new();
+                packet.write(&ClientBattlePacket::CareerStats(self.accounts[&client_id].career.clone())).unwrap();
+                self.slot.send(client_id, packet);
+            },
         }
     }
     
@@ -287,7 +462,8 @@ impl SectorState {
         self.slot.broadcast(results_packet);
         
         // Run the simulation
-        self.do_simulation();
+        let outcomes = self.do_simulation();
+        self.record_career_stats(outcomes);
         
         // Finish the results packet with ships to add and remove
         let mut new_ships = vec!();
@@ -339,6 +515,7 @@ impl SectorState {
                 
                 let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
                 account.ship = Some(ship_stored);
+                self.client_latencies.remove(&client_id);
                 
                 self.slot.transfer_client(client_id, self.star_map_slot_id);
                 
@@ -358,6 +535,7 @@ impl SectorState {
                 
                 let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
                 account.ship = Some(ship_stored);
+                self.client_latencies.remove(&client_id);
                 
                 self.slot.transfer_client(client_id, self.star_map_slot_id);
                 
@@ -374,7 +552,8 @@ impl SectorState {
         self.clients_active = self.clients_active.union(&self.clients_waiting).map(|&x| x).collect();
     }
     
-    fn do_simulation(&mut self) {
+    // Returns what the sim events did
+    fn do_simulation(&mut self) -> Vec<(ShipIndex, SimOutcome)> {
         let mut sim_events = SimEvents::new();
     
         // Pre simulation
@@ -391,6 +570,77 @@ impl SectorState {
         
         // Deactivate modules that can no longer be powered
         self.context.deactivate_unpowerable_modules();
+        
+        sim_events.take_outcomes()
+    }
+    
+    // Counts the turn towards the career stats of the players in it and pays for kills. Has to
+    // happen before ships that ran out of HP start exploding, so this turn's deaths can be told
+    // from earlier ones.
+    fn record_career_stats(&mut self, outcomes: Vec<(ShipIndex, SimOutcome)>) {
+        for client_id in &self.clients_active {
+            if let Some(account) = self.accounts.get_mut(client_id) {
+                account.career.turns += 1;
+            }
+        }
+        
+        // Who owns each player ship
+        let owners: HashMap<ShipId, ClientId> =
+            self.context.ships_iter().filter_map(|ship| ship.client_id.map(|client_id| (ship.id, client_id))).collect();
+        
+        // Whoever hit a ship last gets the kill
+        let mut last_hit_by = HashMap::new();
+        
+        for (ship, outcome) in outcomes {
+            let ship = ship.get(&self.context);
+            
+            match outcome {
+                SimOutcome::Damage(_, 0) => { },
+                SimOutcome::Damage(source, damage) => {
+                    if let Some(account) = ship.client_id.and_then(|client_id| self.accounts.get_mut(&client_id)) {
+                        account.career.damage_taken += damage as u64;
+                    }
+                    if let Some(account) = owners.get(&source).and_then(|client_id| self.accounts.get_mut(client_id)) {
+                        account.career.damage_dealt += damage as u64;
+                    }
+                    last_hit_by.insert(ship.id, source);
+                },
+                SimOutcome::Repair(repair) => {
+                    if let Some(account) = ship.client_id.and_then(|client_id| self.accounts.get_mut(&client_id)) {
+                        account.career.repairs += repair as u64;
+                    }
+                },
+            }
+        }
+        
+        // Ships that ran out of HP this turn
+        let mut paid = vec!();
+        for ship in self.context.ships_iter() {
+            if ship.state.get_hp() > 0 || ship.exploding {
+                continue;
+            }
+            
+            if let Some(account) = ship.client_id.and_then(|client_id| self.accounts.get_mut(&client_id)) {
+                account.career.deaths += 1;
+            }
+            
+            match last_hit_by.get(&ship.id) {
+                Some(killer) if *killer != ship.id => {
+                    if let Some(&client_id) = owners.get(killer) {
+                        if let Some(account) = self.accounts.get_mut(&client_id) {
+                            account.career.kills += 1;
+                            account.wallet.earn(ship.level as u64 * KILL_REWARD_PER_LEVEL, &format!("Destroyed {}", ship.name));
+                            paid.push(client_id);
+                        }
+                    }
+                },
+                _ => { },
+            }
+        }
+        
+        for client_id in paid {
+            send_balance(&self.slot, client_id, &self.accounts[&client_id].wallet, ClientBattlePacket::Credits);
+        }
     }
     
     fn simulate(&mut self, sim_events: &mut SimEvents) {
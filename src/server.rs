@@ -14,16 +14,28 @@
 #![feature(collections_drain)]
 
 extern crate bincode;
+extern crate crypto;
+extern crate flate2;
 extern crate float;
 extern crate num;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate time;
 
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::Read;
 use std::thread::Builder;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Sender};
 
-use net::Server;
+use login::{Account, AccountStore, FileAccountStore, ModAction, ModRequest, Role};
+use net::{replay_slot, CaptureKind, CaptureReader, CaptureWriter, ClientId, Server, ServerSlotId, ShutdownHandle,
+          SlotOutMsg, StatsHandle};
+use sector_data::SectorId;
 use star_map::StarMapServer;
 
 mod ai;
@@ -31,6 +43,7 @@ mod battle_context;
 mod battle_type;
 mod chat;
 mod client_action;
+mod event_mux;
 mod login;
 mod module;
 mod net;
@@ -45,21 +58,286 @@ mod star_map;
 mod vec;
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    
+    // `--accounts <directory>` is where accounts are saved between runs
+    let accounts_directory = arg_value(&args, "--accounts").map(|d| d.clone()).unwrap_or("accounts".to_string());
+    let mut account_store = FileAccountStore::open(&accounts_directory)
+        .ok().expect(&format!("Failed to open account directory {}", accounts_directory));
+    
+    // `--export-account <username>` prints an account as JSON, `--import-account <file>` saves one
+    // back, replacing the account only if `--force` is given. Both exit without starting the
+    // server, which must not be running on the same accounts. The password only goes along with
+    // `--with-credentials`. Otherwise an imported account needs `--password <new password>`.
+    let with_credentials = args.iter().any(|arg| *arg == "--with-credentials");
+    if let Some(username) = arg_value(&args, "--export-account") {
+        export(&mut account_store, username, with_credentials);
+        return;
+    }
+    if let Some(path) = arg_value(&args, "--import-account") {
+        let new_password = arg_value(&args, "--password").map(|password| &password[..]);
+        if new_password.is_none() && !with_credentials {
+            panic!("Importing an account needs --password <new password>, or --with-credentials to keep the exported one");
+        }
+        import(&mut account_store, path, new_password, args.iter().any(|arg| *arg == "--force"));
+        return;
+    }
+    
+    // `--replay <file>` plays a capture back through the login server and checks it sends what it
+    // sent at the time, with the capture's timing if `--realtime` is given. Accounts are loaded
+    // from the account directory but never saved.
+    if let Some(path) = arg_value(&args, "--replay") {
+        replay(account_store, path, args.iter().any(|arg| *arg == "--realtime"));
+        return;
+    }
+    
     let mut server = Server::new();
+    let shutdown = server.shutdown_handle();
+    let stats = server.stats_handle();
+    
+    // `--no-compression` saves CPU at the cost of bandwidth
+    if args.iter().any(|arg| *arg == "--no-compression") {
+        println!("Compression is off");
+        server.set_compression(false);
+    }
+    
+    // `--capture <file>` records all packets for replaying later
+    if let Some(path) = arg_value(&args, "--capture") {
+        match CaptureWriter::create(path) {
+            Ok(capture) => {
+                println!("Capturing packets to {}", path);
+                server.set_capture(capture);
+            },
+            Err(e) => panic!("Failed to create capture file {}: {}", path, e),
+        }
+    }
+
     let login_slot = server.create_slot();
     let star_map_slot = server.create_slot();
     let star_map_slot_id = star_map_slot.get_id();
     let (star_map_account_sender, star_map_account_receiver) = channel();
     let (logout_sender, logout_receiver) = channel();
+    let (mod_sender, mod_receiver) = channel();
+    let (chat_mute_sender, chat_mute_receiver) = channel();
+    let (sector_close_sender, sector_close_receiver) = channel();
+    let (account_save_sender, account_save_receiver) = channel();
+    
+    // `--websocket <address>` lets browser and other WebSocket clients in too
+    if let Some(address) = arg_value(&args, "--websocket") {
+        println!("Accepting WebSocket clients on {}", address);
+        server.listen_websocket(address);
+    }
+    
+    let server_master = Builder::new().name("server_master".to_string()).spawn(move || {
+        listen(&mut server, "0.0.0.0:30000", &args);
+    }).ok().expect("Failed to start server master");
+    
+    Builder::new().name("login_server".to_string()).spawn(move || {
+        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender, logout_receiver, Box::new(account_store),
+                                mod_receiver, chat_mute_sender, account_save_receiver);
+    });
     
-    Builder::new().name("server_master".to_string()).spawn(move || {
-        server.listen("0.0.0.0:30000");
+    let star_map_mod_sender = mod_sender.clone();
+    Builder::new().name("star_map_server".to_string()).spawn(move || {
+        let mut star_map_server = StarMapServer::new(star_map_slot, star_map_mod_sender, chat_mute_receiver, account_save_sender);
+        star_map_server.run(star_map_account_receiver, logout_sender, sector_close_receiver);
     });
     
+    Builder::new().name("console".to_string()).spawn(move || {
+        run_console(shutdown, stats, mod_sender, sector_close_sender);
+    });
+    
+    // Returns once the server has shut down and every client has been told
+    server_master.join();
+}
+
+// Returns the value following a `--name value` command line option
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    match args.iter().position(|arg| *arg == name) {
+        Some(i) => Some(args.get(i + 1).expect(&format!("{} needs a value", name))),
+        None => None,
+    }
+}
+
+fn export(account_store: &mut FileAccountStore, username: &str, with_credentials: bool) {
+    match account_store.load(username) {
+        Ok(Some(account)) => println!("{}", login::export_account(&account, with_credentials)),
+        Ok(None) => panic!("No account named {}", username),
+        Err(e) => panic!("Failed to load account {}: {}", username, e),
+    }
+}
+
+fn import(account_store: &mut FileAccountStore, path: &str, new_password: Option<&str>, force: bool) {
+    let mut text = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+        .ok().expect(&format!("Failed to read {}", path));
+    
+    let account =
+        match login::import_account(&text, new_password) {
+            Ok(account) => account,
+            Err(e) => panic!("Failed to import {}: {}", path, e),
+        };
+    
+    match account_store.load(&account.username) {
+        Ok(Some(_)) if !force => panic!("Account {} already exists, use --force to replace it", account.username),
+        Ok(_) => { },
+        Err(e) => panic!("Failed to check for account {}: {}", account.username, e),
+    }
+    
+    account_store.save(&account).ok().expect(&format!("Failed to save account {}", account.username));
+    println!("Imported account {}", account.username);
+}
+
+fn replay(account_store: FileAccountStore, path: &str, realtime: bool) {
+    // The login slot is the first one made, and the only one that runs without the rest of the game
+    const LOGIN_SLOT_ID: ServerSlotId = 0;
+    
+    // What the login server sent each client at the time
+    let mut expected: HashMap<ClientId, VecDeque<Vec<u8>>> = HashMap::new();
+    let mut capture = CaptureReader::open(path).ok().expect(&format!("Failed to open capture {}", path));
+    loop {
+        match capture.next_record() {
+            Ok(Some(record)) => {
+                if record.kind == CaptureKind::Out && record.slot_id == LOGIN_SLOT_ID {
+                    expected.entry(record.client_id).or_insert(VecDeque::new()).push_back(record.data);
+                }
+            },
+            Ok(None) => { break; },
+            Err(e) => {
+                println!("WARNING: Capture is damaged, only checking what comes before: {}", e);
+                break;
+            },
+        }
+    }
+    
+    let (login_slot, slot_out) = replay_slot(path, LOGIN_SLOT_ID, realtime)
+        .ok().expect(&format!("Failed to open capture {}", path));
+    let (star_map_account_sender, star_map_account_receiver) = channel();
+    let (logout_sender, logout_receiver) = channel();
+    let (_, mod_receiver) = channel();
+    let (chat_mute_sender, _) = channel();
+    let (_, account_save_receiver) = channel();
+    
     Builder::new().name("login_server".to_string()).spawn(move || {
-        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender, logout_receiver);
+        login::run_login_server(login_slot, LOGIN_SLOT_ID + 1, star_map_account_sender, logout_receiver,
+                                Box::new(ReadOnlyAccountStore(account_store)), mod_receiver, chat_mute_sender, account_save_receiver);
+    });
+    
+    // There's no game to play, so accounts that get in are logged straight back out
+    Builder::new().name("star_map_server".to_string()).spawn(move || {
+        for account in star_map_account_receiver.iter() {
+            logout_sender.send(account);
+        }
     });
     
-    let mut star_map_server = StarMapServer::new(star_map_slot);
-    star_map_server.run(star_map_account_receiver, logout_sender);
+    let mut matched = 0;
+    let mut differed = 0;
+    for msg in slot_out.iter() {
+        match msg {
+            SlotOutMsg::SendPacket(_, client_id, packet) => {
+                match expected.get_mut(&client_id).and_then(|packets| packets.pop_front()) {
+                    Some(ref data) if &data[..] == packet.bytes() => { matched += 1; },
+                    Some(_) => {
+                        println!("Client {} was sent a different packet", client_id);
+                        differed += 1;
+                    },
+                    None => {
+                        println!("Client {} was sent a packet it didn't get in the capture", client_id);
+                        differed += 1;
+                    },
+                }
+            },
+            SlotOutMsg::ShutdownComplete(_) => { break; },
+            _ => { },
+        }
+    }
+    
+    let missing = expected.values().fold(0, |total, packets| total + packets.len());
+    println!("Replayed {}: {} packets matched, {} differed, {} never sent", path, matched, differed, missing);
+}
+
+// Loads accounts but drops saves, so a replay leaves the real ones alone
+struct ReadOnlyAccountStore(FileAccountStore);
+
+impl AccountStore for ReadOnlyAccountStore {
+    fn load(&mut self, username: &str) -> io::Result<Option<Account>> {
+        self.0.load(username)
+    }
+    
+    fn save(&mut self, _account: &Account) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Listens with TLS if `--tls-cert <file> --tls-key <file>` were given
+#[cfg(feature = "tls")]
+fn listen(server: &mut Server, address: &str, args: &[String]) {
+    use net::TlsServerConfig;
+    
+    match (arg_value(args, "--tls-cert"), arg_value(args, "--tls-key")) {
+        (Some(cert), Some(key)) => {
+            println!("Clients must connect with TLS");
+            server.listen_tls(address, &TlsServerConfig::new(cert.clone(), key.clone()));
+        },
+        (None, None) => server.listen(address),
+        _ => panic!("--tls-cert and --tls-key must be given together"),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn listen(server: &mut Server, address: &str, args: &[String]) {
+    if arg_value(args, "--tls-cert").is_some() || arg_value(args, "--tls-key").is_some() {
+        panic!("This server was built without TLS support, rebuild with the tls feature");
+    }
+    
+    server.listen(address);
+}
+
+// Reads commands typed into the server's terminal
+fn run_console(shutdown: ShutdownHandle,
+               stats: StatsHandle,
+               mod_sender: Sender<ModRequest>,
+               sector_closer: Sender<SectorId>) {
+    let stdin = io::stdin();
+    
+    loop {
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => { break; }, // No terminal to read from
+            Ok(_) => { },
+        }
+        
+        match line.trim() {
+            "shutdown" | "quit" | "exit" => {
+                shutdown.shutdown("The server is shutting down");
+                break;
+            },
+            "stats" => {
+                if let Some(stats) = stats.query() {
+                    print!("{}", stats);
+                }
+            },
+            "" => { },
+            command if command.starts_with("close ") => {
+                // Logs out everyone in a sector and takes it down
+                match command["close ".len()..].trim().parse() {
+                    Ok(sector_id) => { sector_closer.send(SectorId(sector_id)); },
+                    Err(_) => { println!("Usage: close <sector id>"); },
+                }
+            },
+            command => {
+                // Anything else is a moderator command, with the console acting as an admin
+                match ModAction::parse(command) {
+                    Ok(action) => {
+                        mod_sender.send(ModRequest {
+                            moderator: "console".to_string(),
+                            role: Some(Role::Admin),
+                            action: action,
+                        });
+                    },
+                    Err(usage) => { println!("{}. Also 'stats', 'close <sector id>' and 'shutdown'.", usage); },
+                }
+            },
+        }
+    }
 }
@@ -3,6 +3,8 @@ use std::cell::RefCell;
 use std::cmp;
 use std::marker::Reflect;
 
+use rand::{OsRng, Rng};
+
 use battle_context::BattleContext;
 use module;
 use module::{
@@ -84,11 +86,12 @@ impl ShipState {
         }
     }
     
+    // Returns the damage done to the ship's HP
     pub fn deal_damage(&mut self,
                        module_index: ModuleIndex,
                        damage: u8,
                        shield_piercing: u8,
-                       damage_shields: bool) {
+                       damage_shields: bool) -> u8 {
         let shield_absorption =
             if self.shields > shield_piercing {
                 cmp::min(self.shields - shield_piercing, damage)
@@ -109,16 +112,18 @@ impl ShipState {
             .deal_damage(ship_damage);
         
         // Adjust the ship's HP state
-        self.hp -= cmp::min(self.hp, ship_damage);
+        let hp_damage = cmp::min(self.hp, ship_damage);
+        self.hp -= hp_damage;
+        hp_damage
     }
     
-    pub fn repair_damage(&mut self, module_index: ModuleIndex, repair: u8) {
+    // Returns the amount of damage repaired
+    pub fn repair_damage(&mut self, module_index: ModuleIndex, repair: u8) -> u8 {
         // Get the amount of damage dealt to the module
-        let repair =
-            self.module_stats
-                .get_mut(module_index.to_usize())
-                .expect("Failed to deal damage to non-existant module")
-                .repair_damage(repair);
+        self.module_stats
+            .get_mut(module_index.to_usize())
+            .expect("Failed to deal damage to non-existant module")
+            .repair_damage(repair)
     }
     
     pub fn add_power(&mut self, power: u8) {
@@ -151,6 +156,13 @@ impl ShipState {
 // Type for the ID of a ship
 pub type ShipId = u64;
 
+/// Makes an ID for a new player ship. Ships are saved with their accounts, so IDs are random
+/// rather than counted, and stay unique across restarts.
+pub fn new_ship_id() -> ShipId {
+    let mut rng = OsRng::new().ok().expect("Failed to open the OS random number generator");
+    rng.next_u64()
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct ShipIndex(pub u32);
 
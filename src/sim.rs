@@ -1,3 +1,4 @@
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -16,11 +17,19 @@ use sdl2_mixer;
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub trait SimEvent {
-    fn apply(&mut self, ship_state: &mut ShipState);
+    fn apply(&mut self, ship_state: &mut ShipState) -> SimOutcome;
+}
+
+/// What an event did to the ship it was applied to, for career stats
+#[derive(Copy, Clone, Debug)]
+pub enum SimOutcome {
+    Damage(ShipId, u8),     // Hull damage that got past the shields (ship that dealt it, damage)
+    Repair(u8),             // Module HP restored
 }
 
 pub struct SimEvents<'a> {
     events: Vec<Vec<(ShipIndex, Box<SimEvent+'a>)>>, // events[tick][event]
+    outcomes: Vec<(ShipIndex, SimOutcome)>,         // What the events applied so far did
 }
 
 impl<'a> SimEvents<'a> {
@@ -31,16 +40,23 @@ impl<'a> SimEvents<'a> {
         }
         SimEvents {
             events: events,
+            outcomes: vec!(),
         }
     }
     
     pub fn apply_tick(&mut self, bc: &mut BattleContext, tick: u32) {
         let tick = tick as usize;
         for (ship, mut event) in self.events[tick].drain(..) {
-            event.apply(&mut ship.get_mut(bc).state);
+            let outcome = event.apply(&mut ship.get_mut(bc).state);
+            self.outcomes.push((ship, outcome));
         }
     }
     
+    /// Takes what the events applied since the last call did
+    pub fn take_outcomes(&mut self) -> Vec<(ShipIndex, SimOutcome)> {
+        mem::replace(&mut self.outcomes, vec!())
+    }
+    
     pub fn add(&mut self, tick: u32, ship: ShipIndex, event: Box<SimEvent+'a>) {
         self.events[tick as usize].push((ship, event));
     }
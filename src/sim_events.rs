@@ -1,10 +1,11 @@
 use std::ops::DerefMut;
 
 use module::ModuleIndex;
-use ship::ShipState;
-use sim::SimEvent;
+use ship::{ShipId, ShipState};
+use sim::{SimEvent, SimOutcome};
 
 pub struct DamageEvent {
+    source: ShipId,     // Ship that dealt the damage
     module_index: ModuleIndex,
     damage: u8,
     shield_piercing: u8,
@@ -12,11 +13,13 @@ pub struct DamageEvent {
 }
 
 impl DamageEvent {
-    pub fn new(module_index: ModuleIndex,
+    pub fn new(source: ShipId,
+               module_index: ModuleIndex,
                damage: u8,
                shield_piercing: u8,
                damage_shields: bool) -> DamageEvent {
         DamageEvent {
+            source: source,
             module_index: module_index,
             damage: damage,
             shield_piercing: shield_piercing,
@@ -26,8 +29,8 @@ impl DamageEvent {
 }
 
 impl SimEvent for DamageEvent {
-    fn apply(&mut self, ship_state: &mut ShipState) {
-        ship_state.deal_damage(self.module_index, self.damage, self.shield_piercing, self.damage_shields);
+    fn apply(&mut self, ship_state: &mut ShipState) -> SimOutcome {
+        SimOutcome::Damage(self.source, ship_state.deal_damage(self.module_index, self.damage, self.shield_piercing, self.damage_shields))
     }
 }
 
@@ -48,7 +51,7 @@ impl RepairEvent {
 }
 
 impl SimEvent for RepairEvent {
-    fn apply(&mut self, ship_state: &mut ShipState) {
-        ship_state.repair_damage(self.module_index, self.repair);
+    fn apply(&mut self, ship_state: &mut ShipState) -> SimOutcome {
+        SimOutcome::Repair(ship_state.repair_damage(self.module_index, self.repair))
     }
 }
\ No newline at end of file
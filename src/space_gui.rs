@@ -13,7 +13,7 @@ use opengl_graphics::glyph_cache::GlyphCache;
 use asset_store::AssetStore;
 use battle_context::BattleContext;
 use chat::{ChatGui, ChatGuiAction};
-use gui::TextButton;
+use gui::{draw_credits, TextButton};
 use module;
 use module::{IModule, Module, ModuleIndex};
 use net::ClientId;
@@ -31,6 +31,7 @@ static ENEMY_OFFSET_Y: f64 = 50.0;
 
 pub enum SpaceGuiAction {
     Chat(String),
+    CareerStats,
     Logout,
 }
 
@@ -83,6 +84,9 @@ pub struct SpaceGui<'a> {
     
     // Logout button
     logout_button: TextButton,
+    
+    // Player's balance, once the server has said
+    pub credits: Option<u64>,
 
     // targets
     target_icons: Vec<TargetIcon>,
@@ -149,6 +153,8 @@ impl<'a> SpaceGui<'a> {
             chat_gui: chat_gui,
             
             logout_button: TextButton::new("logout".to_string(), 24, [550.0, 100.0], [120.0, 40.0]),
+            
+            credits: None,
 
             target_icons: target_icons,
         }
@@ -171,6 +177,9 @@ impl<'a> SpaceGui<'a> {
                 ChatGuiAction::SendMsg(msg) => {
                     return Some(SpaceGuiAction::Chat(msg));
                 },
+                ChatGuiAction::CareerStats => {
+                    return Some(SpaceGuiAction::CareerStats);
+                },
             }
         }
         
@@ -458,6 +467,8 @@ impl<'a> SpaceGui<'a> {
         
         self.star_map_button.draw(context, gl, glyph_cache);
         self.logout_button.draw(context, gl, glyph_cache);
+        
+        draw_credits(self.credits, context, gl, glyph_cache);
 
         // Draw target icons
         for (i, icon) in self.target_icons.iter().enumerate() {
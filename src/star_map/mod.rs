@@ -1,6 +1,6 @@
 #[cfg(feature = "client")]
 pub use self::star_map_gui::{StarMapGui, StarMapGuiAction};
-pub use self::star_map_server::{StarMapAction, StarMapServer};
+pub use self::star_map_server::{GameTiming, StarMapAction, StarMapServer};
 
 #[cfg(feature = "client")]
 pub mod star_map_gui;
@@ -5,31 +5,65 @@ use std::thread::Builder;
 use time;
 
 use battle_context::BattleContext;
-use chat::ChatServer;
+use chat::{ChatMute, ChatServer};
 use client_action::ClientAction;
-use login::AccountBox;
+use event_mux::EventMux;
+use login::{AccountBox, ModRequest};
 use module::ModelStore;
 use net::{
+    ClientId,
     OutPacket,
     ServerSlot,
     ServerSlotId,
     SlotInMsg,
 };
 use sector_data::{SectorData, SectorId, SectorKind};
-use sector_server::SectorState;
+use sector_server::{SectorState, SIMULATE_TURN_MS, TURN_LENGTH_MS};
 use super::station::StationServer;
 use vec::Vec2;
 
+// How long a jump between sectors takes
+const JUMP_TIME_MS: u32 = 6000;
+
+// Where new accounts start, and where anyone headed for a sector that's gone ends up. Never closed.
+const HOME_SECTOR: SectorId = SectorId(0);
+
+/// How long jumps and battle turns take. Tests shorten them to play through quickly.
+#[derive(Copy, Clone)]
+pub struct GameTiming {
+    pub jump_ms: u32,
+    pub simulate_turn_ms: u32,  // Time into each turn at which it's simulated
+    pub turn_length_ms: u32,
+}
+
+impl GameTiming {
+    pub fn new() -> GameTiming {
+        GameTiming {
+            jump_ms: JUMP_TIME_MS,
+            simulate_turn_ms: SIMULATE_TURN_MS,
+            turn_length_ms: TURN_LENGTH_MS,
+        }
+    }
+}
+
 // Reason a ship is leaving a sector
 pub enum StarMapAction {
     Jump(SectorId),
     Logout,
 }
 
+// Everything the star map waits on
+enum StarMapEvent {
+    Slot(SlotInMsg),
+    FromLogin(AccountBox),
+    FromSector(AccountBox, StarMapAction),
+    JumpReady,
+    CloseSector(SectorId),
+}
+
 pub struct Sector {
     pub slot_id: ServerSlotId,
     pub to_sector: Sender<AccountBox>,
-    pub from_sector: Receiver<(AccountBox, StarMapAction)>,
     pub ack: Receiver<()>,
     pub data: SectorData,
 }
@@ -38,15 +72,47 @@ pub struct StarMapServer {
     slot: ServerSlot,
     sectors: HashMap<SectorId, Sector>,
     
+    // Slot messages, accounts arriving from login and sectors, and jump timers
+    events: EventMux<StarMapEvent>,
+    
     jumping_accounts: VecDeque<(AccountBox, SectorId, time::Timespec)>,
+    jump_ms: u32,
+    
+    // Accounts handed to sectors that haven't come back yet
+    accounts_in_sectors: u32,
+    
+    // Set once the server starts shutting down. Accounts coming back go straight to the login
+    // server instead of to another sector.
+    shutting_down: bool,
+    
+    // What each client was last sent on its way out of the star map, sent again if it resumes
+    // before getting there. Forgotten when its account comes back from a sector.
+    last_sent: HashMap<ClientId, Vec<OutPacket>>,
 }
 
 impl StarMapServer {
-    pub fn new(slot: ServerSlot) -> StarMapServer {
+    /// Starts the star map and its sectors. Sectors send snapshots of the accounts in them on
+    /// `account_saves` from time to time.
+    pub fn new(slot: ServerSlot,
+               mod_sender: Sender<ModRequest>,
+               chat_mutes: Receiver<ChatMute>,
+               account_saves: Sender<AccountBox>) -> StarMapServer {
+        StarMapServer::with_timing(slot, mod_sender, chat_mutes, account_saves, GameTiming::new())
+    }
+    
+    /// Like `new`, but with jumps and turns taking as long as `timing` says
+    pub fn with_timing(mut slot: ServerSlot,
+                       mod_sender: Sender<ModRequest>,
+                       chat_mutes: Receiver<ChatMute>,
+                       account_saves: Sender<AccountBox>,
+                       timing: GameTiming) -> StarMapServer {
         // Chat server input channel
         let (to_chat_server, chat_from_sector) = channel();
         let mut chat_msg_senders = vec!();
         
+        // Accounts leaving sectors come in here
+        let events = EventMux::new();
+        
         // Fire up the universe
     
         let model_store = Arc::new(ModelStore::new());
@@ -56,16 +122,18 @@ impl StarMapServer {
         // Station
         let (to_sector_sender, to_sector_receiver) = channel();
         let (from_sector_sender, from_sector_receiver) = channel();
+        events.forward(from_sector_receiver, |(account, action)| StarMapEvent::FromSector(account, action));
         let (ack_sender, ack_receiver) = channel();
         let (chat_sender, sector_chat_in) = channel();
         chat_msg_senders.push(chat_sender);
         let sector_slot = slot.create_slot();
         let sector_id = SectorId(0);
         let sector_chat_out = to_chat_server.clone();
+        let sector_mod_sender = mod_sender.clone();
+        let sector_save_sender = account_saves.clone();
         sectors.insert(sector_id, Sector {
             slot_id: sector_slot.get_id(),
             to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
             ack: ack_receiver,
             data: SectorData {
                 id: sector_id,
@@ -82,8 +150,10 @@ impl StarMapServer {
                                                            slot_id,
                                                            sector_chat_out,
                                                            sector_chat_in,
+                                                           sector_mod_sender,
                                                            from_sector_sender,
                                                            to_sector_receiver,
+                                                           sector_save_sender,
                                                            model_store.clone());
                 sector_server.run(ack_sender);
             });
@@ -91,16 +161,18 @@ impl StarMapServer {
         // Sector 1
         let (to_sector_sender, to_sector_receiver) = channel();
         let (from_sector_sender, from_sector_receiver) = channel();
+        events.forward(from_sector_receiver, |(account, action)| StarMapEvent::FromSector(account, action));
         let (ack_sender, ack_receiver) = channel();
         let (chat_sender, sector_chat_in) = channel();
         chat_msg_senders.push(chat_sender);
         let sector_slot = slot.create_slot();
         let sector_id = SectorId(1);
         let sector_chat_out = to_chat_server.clone();
+        let sector_mod_sender = mod_sender.clone();
+        let sector_save_sender = account_saves.clone();
         sectors.insert(sector_id, Sector {
             slot_id: sector_slot.get_id(),
             to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
             ack: ack_receiver,
             data: SectorData {
                 id: sector_id,
@@ -116,26 +188,31 @@ impl StarMapServer {
                                                          slot_id,
                                                          sector_chat_out,
                                                          sector_chat_in,
+                                                         sector_mod_sender,
                                                          from_sector_sender,
                                                          to_sector_receiver,
+                                                         sector_save_sender,
                                                          BattleContext::new(vec!()),
                                                          false);
+                sector_server.set_turn_timing(timing.simulate_turn_ms, timing.turn_length_ms);
                 sector_server.run(ack_sender, false);
             });
         
         // Sector 2
         let (to_sector_sender, to_sector_receiver) = channel();
         let (from_sector_sender, from_sector_receiver) = channel();
+        events.forward(from_sector_receiver, |(account, action)| StarMapEvent::FromSector(account, action));
         let (ack_sender, ack_receiver) = channel();
         let (chat_sender, sector_chat_in) = channel();
         chat_msg_senders.push(chat_sender);
         let sector_slot = slot.create_slot();
         let sector_id = SectorId(2);
         let sector_chat_out = to_chat_server.clone();
+        let sector_mod_sender = mod_sender.clone();
+        let sector_save_sender = account_saves.clone();
         sectors.insert(sector_id, Sector {
             slot_id: sector_slot.get_id(),
             to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
             ack: ack_receiver,
             data: SectorData {
                 id: sector_id,
@@ -151,10 +228,13 @@ impl StarMapServer {
                                                          slot_id,
                                                          sector_chat_out,
                                                          sector_chat_in,
+                                                         sector_mod_sender,
                                                          from_sector_sender,
                                                          to_sector_receiver,
+                                                         sector_save_sender,
                                                          BattleContext::new(vec!()),
                                                          false);
+                sector_server.set_turn_timing(timing.simulate_turn_ms, timing.turn_length_ms);
                 sector_server.run(ack_sender, true);
             });
         
@@ -162,105 +242,236 @@ impl StarMapServer {
         Builder::new()
             .name("chat_server".to_string())
             .spawn(move || {
-                let mut chat_server = ChatServer::new(chat_from_sector, chat_msg_senders);
+                let mut chat_server = ChatServer::new(chat_from_sector, chat_mutes, chat_msg_senders);
                 chat_server.run();
             });
         
         ////////////////////////////////////////////////////////////////////////////////////////////
         
+        slot.forward_incoming(&events, StarMapEvent::Slot);
+        
         StarMapServer {
             slot: slot,
             sectors: sectors,
+            events: events,
             jumping_accounts: VecDeque::new(),
+            jump_ms: timing.jump_ms,
+            accounts_in_sectors: 0,
+            shutting_down: false,
+            last_sent: HashMap::new(),
         }
     }
     
-    pub fn run(&mut self, from_login: Receiver<AccountBox>, logout_sender: Sender<AccountBox>) {
+    /// Runs the star map. Sectors named on `close_requests` are closed: players in them are
+    /// logged out and the sector goes away.
+    pub fn run(&mut self, from_login: Receiver<AccountBox>, logout_sender: Sender<AccountBox>, close_requests: Receiver<SectorId>) {
+        self.events.forward(from_login, StarMapEvent::FromLogin);
+        self.events.forward(close_requests, StarMapEvent::CloseSector);
+    
         loop {
-            if let Ok(slot_msg) = self.slot.try_receive() {
-                match slot_msg {
-                    SlotInMsg::Joined(client_id) => {
-                        println!("Client {} joined the star map", client_id);
-                    },
-                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                    },
-                    _ => {},
-                }
-            }
-            
-            if let Ok(account) = from_login.try_recv() {
-                let client_id = account.client_id.expect("This needs to have a client ID");
-            
-                let sector_data: Vec<SectorData> = self.sectors.iter().map(|(_, s)| s.data.clone()).collect();
-                
-                let ref sector = self.sectors[&account.sector];
-            
-                let mut sectors_packet = OutPacket::new();
-                sectors_packet.write(&sector_data).unwrap();
-                self.slot.send(client_id, sectors_packet);
-                
-                ////////////////////////////////////////////////////////////////////////////////////
-                
-                let client_action =
-                    match sector.data.kind {
-                        SectorKind::Sector => ClientAction::JoinSector,
-                        SectorKind::Station => ClientAction::JoinStation,
-                    };
-                
-                let mut action_packet = OutPacket::new();
-                action_packet.write(&client_action).unwrap();
-                self.slot.send(client_id, action_packet);
-                
-                sector.to_sector.send(account);
-                sector.ack.recv();
-                self.slot.transfer_client(client_id, sector.slot_id);
-            }
-            
-            // Send any jumping ships to their new sector
-            for sector in self.sectors.values() {
-                if let Ok((account, exit_action)) = sector.from_sector.try_recv() {
-                    match exit_action {
-                        StarMapAction::Jump(sector) => {
-                            self.jumping_accounts.push_back((account, sector, time::now().to_timespec() + time::Duration::milliseconds(6000)));
+            match self.events.recv() {
+                StarMapEvent::Slot(slot_msg) => {
+                    match slot_msg {
+                        SlotInMsg::Joined(client_id) => {
+                            println!("Client {} joined the star map", client_id);
+                        },
+                        SlotInMsg::ReceivedPacket(client_id, mut packet) => {
+                        },
+                        SlotInMsg::Resumed(client_id) => {
+                            match self.last_sent.get(&client_id) {
+                                Some(packets) => {
+                                    println!("Client {} reconnected to the star map, sending where it's headed again", client_id);
+                                    for packet in packets.iter() {
+                                        self.slot.send(client_id, packet.clone());
+                                    }
+                                },
+                                None => { println!("Client {} reconnected mid-jump", client_id); },
+                            }
                         },
-                        StarMapAction::Logout => {    
-                            let client_id = account.client_id.expect("This needs to have a client ID");
-                        
-                            let mut action_packet = OutPacket::new();
-                            action_packet.write(&ClientAction::Logout).unwrap();
-                            self.slot.send(client_id, action_packet);
-                        
-                            logout_sender.send(account);
+                        SlotInMsg::Disconnected(client_id) => {
+                            self.last_sent.remove(&client_id);
+                            
+                            // Only clients mid-jump are here. Their accounts go back to login.
+                            let position = self.jumping_accounts.iter().position(|&(ref account, _, _)| account.client_id == Some(client_id));
+                            if let Some((mut account, target_sector, _)) = position.and_then(|position| self.jumping_accounts.remove(position)) {
+                                println!("Client {} disconnected mid-jump, logging out...", client_id);
+                                account.sector = target_sector;
+                                logout_sender.send(account);
+                                self.check_shutdown();
+                            }
                         },
+                        SlotInMsg::ShuttingDown => {
+                            self.shutting_down = true;
+                            
+                            // Jumps in progress end where they were headed
+                            while let Some((mut account, target_sector, _)) = self.jumping_accounts.pop_front() {
+                                account.sector = target_sector;
+                                self.log_out(account, &logout_sender);
+                            }
+                            
+                            self.check_shutdown();
+                        },
+                        SlotInMsg::SlotDestroyed(slot_id) => {
+                            // Usually a sector we closed finishing up. If not, letting go of it
+                            // still makes its thread hand back everyone in it.
+                            let gone: Vec<SectorId> = self.sectors.iter().filter(|&(_, s)| s.slot_id == slot_id).map(|(id, _)| *id).collect();
+                            for sector_id in gone {
+                                println!("Sector {} was destroyed", sector_id.0);
+                                self.sectors.remove(&sector_id);
+                            }
+                        },
+                        _ => {},
                     }
-                }
-            }
-            
-            while let Some((mut account, target_sector, jump_time)) = self.jumping_accounts.pop_front() {
-                if (time::now().to_timespec() - jump_time).num_milliseconds() < 0 {
-                    self.jumping_accounts.push_front((account, target_sector, jump_time));
-                    break;
-                } else {
+                },
+                
+                StarMapEvent::FromLogin(mut account) => {
+                    if self.shutting_down {
+                        self.log_out(account, &logout_sender);
+                        continue;
+                    }
+                
                     let client_id = account.client_id.expect("This needs to have a client ID");
+                
+                    let sector_data: Vec<SectorData> = self.sectors.iter().map(|(_, s)| s.data.clone()).collect();
                     
-                    account.sector = target_sector;
+                    // Logged out somewhere that's since been closed
+                    if !self.sectors.contains_key(&account.sector) {
+                        account.sector = HOME_SECTOR;
+                    }
+                    let ref sector = self.sectors[&account.sector];
+                
+                    let mut sectors_packet = OutPacket::new();
+                    sectors_packet.write(&sector_data).unwrap();
+                    self.slot.send(client_id, sectors_packet.clone());
                     
-                    let ref sector = self.sectors[&target_sector];
+                    ////////////////////////////////////////////////////////////////////////////////
                     
                     let client_action =
                         match sector.data.kind {
                             SectorKind::Sector => ClientAction::JoinSector,
                             SectorKind::Station => ClientAction::JoinStation,
                         };
-                
+                    
                     let mut action_packet = OutPacket::new();
                     action_packet.write(&client_action).unwrap();
-                    self.slot.send(client_id, action_packet);
+                    self.slot.send(client_id, action_packet.clone());
+                    
+                    self.last_sent.insert(client_id, vec!(sectors_packet, action_packet));
                     
                     sector.to_sector.send(account);
                     sector.ack.recv();
                     self.slot.transfer_client(client_id, sector.slot_id);
-                }
+                    self.accounts_in_sectors += 1;
+                },
+                
+                // Ships leaving a sector
+                StarMapEvent::FromSector(mut account, exit_action) => {
+                    self.accounts_in_sectors -= 1;
+                    
+                    if let Some(client_id) = account.client_id {
+                        self.last_sent.remove(&client_id);
+                    }
+                    
+                    if let StarMapAction::Jump(_) = exit_action {
+                        account.career.jumps += 1;
+                    }
+                    
+                    match exit_action {
+                        StarMapAction::Jump(sector) if self.shutting_down => {
+                            account.sector = sector;
+                            self.log_out(account, &logout_sender);
+                        },
+                        StarMapAction::Jump(sector) => {
+                            self.jumping_accounts.push_back((account, sector, time::now().to_timespec() + time::Duration::milliseconds(self.jump_ms as i64)));
+                            self.events.schedule(self.jump_ms, StarMapEvent::JumpReady);
+                        },
+                        StarMapAction::Logout => {
+                            self.log_out(account, &logout_sender);
+                        },
+                    }
+                    
+                    self.check_shutdown();
+                },
+                
+                // Send any jumping ships to their new sector
+                StarMapEvent::JumpReady => {
+                    self.finish_jumps();
+                },
+                
+                StarMapEvent::CloseSector(sector_id) => {
+                    self.close_sector(sector_id);
+                },
+            }
+        }
+    }
+    
+    fn log_out(&mut self, account: AccountBox, logout_sender: &Sender<AccountBox>) {
+        let client_id = account.client_id.expect("This needs to have a client ID");
+    
+        let client_action = if self.shutting_down { ClientAction::ServerShutdown } else { ClientAction::Logout };
+        
+        let mut action_packet = OutPacket::new();
+        action_packet.write(&client_action).unwrap();
+        self.slot.send(client_id, action_packet.clone());
+        self.last_sent.insert(client_id, vec!(action_packet));
+    
+        logout_sender.send(account);
+    }
+    
+    // Letting go of a sector's channel tells it to log out everyone in it and destroy its slot.
+    // Their accounts come back through `FromSector` as usual.
+    fn close_sector(&mut self, sector_id: SectorId) {
+        if sector_id == HOME_SECTOR {
+            println!("Can't close sector {}, new players start there", sector_id.0);
+            return;
+        }
+        
+        match self.sectors.remove(&sector_id) {
+            Some(_) => println!("Closing sector {}", sector_id.0),
+            None => println!("Can't close sector {}, there's no such sector", sector_id.0),
+        }
+    }
+    
+    // Once shutting down, the star map is done when every account has been sent back to login
+    fn check_shutdown(&mut self) {
+        if self.shutting_down && self.accounts_in_sectors == 0 && self.jumping_accounts.is_empty() {
+            self.slot.shutdown_complete();
+        }
+    }
+    
+    fn finish_jumps(&mut self) {
+        while let Some((mut account, target_sector, jump_time)) = self.jumping_accounts.pop_front() {
+            if (time::now().to_timespec() - jump_time).num_milliseconds() < 0 {
+                self.jumping_accounts.push_front((account, target_sector, jump_time));
+                break;
+            } else {
+                let client_id = account.client_id.expect("This needs to have a client ID");
+                
+                // The sector may have closed during the jump
+                account.sector =
+                    if self.sectors.contains_key(&target_sector) {
+                        target_sector
+                    } else {
+                        HOME_SECTOR
+                    };
+                
+                let ref sector = self.sectors[&account.sector];
+                
+                let client_action =
+                    match sector.data.kind {
+                        SectorKind::Sector => ClientAction::JoinSector,
+                        SectorKind::Station => ClientAction::JoinStation,
+                    };
+            
+                let mut action_packet = OutPacket::new();
+                action_packet.write(&client_action).unwrap();
+                self.slot.send(client_id, action_packet.clone());
+                self.last_sent.insert(client_id, vec!(action_packet));
+                
+                sector.to_sector.send(account);
+                sector.ack.recv();
+                self.slot.transfer_client(client_id, sector.slot_id);
+                self.accounts_in_sectors += 1;
             }
         }
     }
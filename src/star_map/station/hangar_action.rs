@@ -0,0 +1,53 @@
+use std::cmp;
+
+use ship::{ShipId, ShipStored};
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum HangarAction {
+    List,
+    Switch(ShipId),             // Make a hangar ship the active one
+    Rename(ShipId, String),     // Active ship or a hangar ship
+    Retire(ShipId),             // Scrap a hangar ship for good
+}
+
+impl HangarAction {
+    /// Parses a hangar command typed into chat, without the leading '/'. The error says how the
+    /// command should look.
+    pub fn parse(command: &str) -> Result<HangarAction, String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let id = words.get(2).and_then(|id| id.parse().ok());
+
+        let (action, usage) =
+            match words.get(1).map(|name| *name) {
+                None => (Some(HangarAction::List), "hangar"),
+                Some("switch") => (id.map(HangarAction::Switch), "hangar switch <ship id>"),
+                Some("rename") => {
+                    let name = words[cmp::min(3, words.len())..].join(" ");
+                    (id.map(|id| HangarAction::Rename(id, name)), "hangar rename <ship id> <name>")
+                },
+                Some("retire") => (id.map(HangarAction::Retire), "hangar retire <ship id>"),
+                Some(_) => (None, "hangar [switch|rename|retire]"),
+            };
+        action.ok_or(format!("Usage: /{}", usage))
+    }
+}
+
+/// What the hangar list shows of a ship
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct HangarShip {
+    pub id: ShipId,
+    pub name: String,
+    pub level: u8,
+    pub active: bool,
+}
+
+impl HangarShip {
+    pub fn new(ship: &ShipStored, active: bool) -> HangarShip {
+        HangarShip {
+            id: ship.id,
+            name: ship.name.clone(),
+            level: ship.level,
+            active: active,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+pub use self::hangar_action::{HangarAction, HangarShip};
 pub use self::ship_edit_action::ShipEditAction;
 pub use self::station_action::StationAction;
 #[cfg(feature = "client")]
@@ -6,6 +7,7 @@ pub use self::station_client::StationClient;
 pub use self::station_gui::StationGui;
 pub use self::station_server::StationServer;
 
+pub mod hangar_action;
 pub mod ship_edit_action;
 #[cfg(feature = "client")]
 pub mod ship_edit_gui;
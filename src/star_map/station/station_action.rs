@@ -1,6 +1,6 @@
 use sector_data::SectorId;
 
-use super::ShipEditAction;
+use super::{HangarAction, ShipEditAction};
 
 #[derive(Clone, RustcEncodable, RustcDecodable)]
 pub enum StationAction {
@@ -8,6 +8,8 @@ pub enum StationAction {
     ShipEdit(ShipEditAction),
     Chat(String),
     Logout,
+    CareerStats,    // Ask for the player's career stats
+    Hangar(HangarAction),
 }
 
 impl StationAction {
@@ -7,9 +7,10 @@ use opengl_graphics::glyph_cache::GlyphCache;
 use glutin_window::GlutinWindow;
 
 use asset_store::AssetStore;
-use chat::ChatGui;
+use chat::{ChatGui, ChatMsg};
 use module::{ModelIndex, ModelStore};
-use net::{Client, OutPacket};
+use net::{Client, NetResult, OutPacket};
+use packet_types::ClientStationPacket;
 use sector_data::SectorData;
 use ship::ShipStored;
 use sim::SimEffects;
@@ -38,7 +39,7 @@ impl<'a> StationClient<'a> {
                asset_store: &AssetStore,
                model_store: &ModelStore,
                chat_gui: &mut ChatGui,
-               sectors: Vec<SectorData>) {     
+               sectors: Vec<SectorData>) -> NetResult<()> {     
         let module_inventory =
             vec![
                 ("engine".to_string(), vec![(ModelIndex(0), 100)]),
@@ -92,20 +93,51 @@ impl<'a> StationClient<'a> {
                 });
             });
             
-            if let Ok(mut packet) = self.client.try_receive() {
-                let chat_msg = packet.read().unwrap();
-                gui.chat_gui.add_message(chat_msg);
+            if let Some(mut packet) = try!(self.client.try_receive()) {
+                match packet.read().unwrap() {
+                    ClientStationPacket::Chat(chat_msg) => {
+                        gui.chat_gui.add_message(chat_msg);
+                    },
+                    ClientStationPacket::CareerStats(stats) => {
+                        gui.chat_gui.show_career_stats(&stats);
+                    },
+                    ClientStationPacket::Credits(credits) => {
+                        gui.credits = Some(credits);
+                    },
+                    ClientStationPacket::Hangar(ships) => {
+                        for ship in ships {
+                            gui.chat_gui.add_message(ChatMsg {
+                                author_name: "Hangar".to_string(),
+                                content: format!("[{}] {}, level {}{}", ship.id, ship.name, ship.level, if ship.active { " (active)" } else { "" }),
+                            });
+                        }
+                    },
+                    ClientStationPacket::HangarError(e) => {
+                        gui.chat_gui.add_message(ChatMsg {
+                            author_name: "Hangar".to_string(),
+                            content: e,
+                        });
+                    },
+                    ClientStationPacket::ActiveShip => {
+                        self.player_ship = packet.read().unwrap();
+                        
+                        sim_effects.reset();
+                        if let Some(ref ship) = self.player_ship {
+                            ship.add_simulation_effects(asset_store, sim_effects);
+                        }
+                    },
+                }
             }
             
             // Handle GUI action
             if let Some(gui_action) = gui_action {
                 let mut packet = OutPacket::new();
                 packet.write(&gui_action);
-                self.client.send(&packet);
+                try!(self.client.send(&packet));
                 
                 match gui_action {
                     StationAction::Jump(_) => {
-                        return;
+                        return Ok(());
                     },
                     StationAction::ShipEdit(ship_edit) => {
                         if let Some(ref mut ship) = self.player_ship {
@@ -126,11 +158,15 @@ impl<'a> StationClient<'a> {
                         }
                     },
                     StationAction::Chat(_) => { },
+                    StationAction::CareerStats => { },
+                    StationAction::Hangar(_) => { },
                     StationAction::Logout => {
-                        return;
+                        return Ok(());
                     },
                 }
             }
         }
+        
+        Ok(())
     }
 }
@@ -5,8 +5,8 @@ use opengl_graphics::{GlGraphics, Texture};
 use opengl_graphics::glyph_cache::GlyphCache;
 
 use asset_store::AssetStore;
-use chat::{ChatGui, ChatGuiAction};
-use gui::TextButton;
+use chat::{ChatGui, ChatGuiAction, ChatMsg};
+use gui::{draw_credits, TextButton};
 use module::{IModule, ModelStore, Module, ModuleIndex};
 use net::ClientId;
 use sector_data::SectorData;
@@ -15,7 +15,7 @@ use sim::SimEffects;
 use star_map::{StarMapGuiAction, StarMapGui};
 use vec::{Vec2, Vec2f};
 
-use super::StationAction;
+use super::{HangarAction, StationAction};
 use super::ship_edit_gui::{ModuleInventory, ShipEditGui};
 
 pub struct StationGui<'a> {
@@ -35,6 +35,9 @@ pub struct StationGui<'a> {
     
     // Logout button
     logout_button: TextButton,
+    
+    // Player's balance, once the server has said
+    pub credits: Option<u64>,
 }
 
 impl<'a> StationGui<'a> {
@@ -55,6 +58,8 @@ impl<'a> StationGui<'a> {
             show_star_map: false,
             
             logout_button: TextButton::new("logout".to_string(), 24, [550.0, 100.0], [120.0, 40.0]),
+            
+            credits: None,
         }
     }
     
@@ -89,9 +94,23 @@ impl<'a> StationGui<'a> {
         
         if let Some(chat_action) = self.chat_gui.event(e, self.mouse_pos - self.chat_gui_pos) {
             match chat_action {
+                ChatGuiAction::SendMsg(ref msg) if msg.starts_with("/hangar") => {
+                    match HangarAction::parse(&msg[1..]) {
+                        Ok(hangar_action) => { return Some(StationAction::Hangar(hangar_action)); },
+                        Err(usage) => {
+                            self.chat_gui.add_message(ChatMsg {
+                                author_name: "Hangar".to_string(),
+                                content: usage,
+                            });
+                        },
+                    }
+                },
                 ChatGuiAction::SendMsg(msg) => {
                     return Some(StationAction::Chat(msg));
                 },
+                ChatGuiAction::CareerStats => {
+                    return Some(StationAction::CareerStats);
+                },
             }
         }
         
@@ -158,6 +177,8 @@ impl<'a> StationGui<'a> {
         self.star_map_button.draw(context, gl, glyph_cache);
         self.logout_button.draw(context, gl, glyph_cache);
         
+        draw_credits(self.credits, context, gl, glyph_cache);
+        
         self.chat_gui.draw(&context.trans(self.chat_gui_pos.x, self.chat_gui_pos.y), gl, glyph_cache);
         
         if self.show_star_map {
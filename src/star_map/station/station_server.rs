@@ -3,52 +3,83 @@ use std::sync::Arc;
 use std::sync::mpsc::{Sender, Receiver};
 
 use chat::ChatMsg;
-use login::AccountBox;
+use event_mux::EventMux;
+use login::{moderator_command, send_balance, AccountBox, ModRequest};
 use module::ModelStore;
 use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
+use packet_types::ClientStationPacket;
 use star_map::StarMapAction;
-use star_map::station::{ShipEditAction, StationAction};
+use star_map::station::{HangarAction, HangarShip, ShipEditAction, StationAction};
+
+const MAX_SHIP_NAME_LENGTH: usize = 24;
+
+// How often everyone's account is sent to the login server to save
+const SNAPSHOT_INTERVAL_MS: u32 = 60000;
+
+// Everything a station waits on
+enum StationEvent {
+    Slot(SlotInMsg),
+    Chat(ChatMsg),
+    FromMap(AccountBox),
+    SaveAccounts,
+}
 
 pub struct StationServer {
     slot: ServerSlot,
     star_map_slot_id: ServerSlotId,
     chat_sender: Sender<ChatMsg>,
-    chat_receiver: Receiver<ChatMsg>,
+    mod_sender: Sender<ModRequest>,
     to_map_sender: Sender<(AccountBox, StarMapAction)>,
-    from_map_receiver: Receiver<AccountBox>,
+    save_sender: Sender<AccountBox>,
+    
+    // Slot messages, chat, accounts arriving from the star map and the snapshot timer
+    events: EventMux<StationEvent>,
     
     model_store: Arc<ModelStore>,
 
     // All the clients' accounts
     accounts: HashMap<ClientId, AccountBox>,
+    
+    // Set once the server starts shutting down. Accounts are sent straight back to the star map.
+    shutting_down: bool,
 }
 
 impl StationServer {
-    pub fn new(slot: ServerSlot,
+    pub fn new(mut slot: ServerSlot,
                star_map_slot_id: ServerSlotId,
                chat_sender: Sender<ChatMsg>,
                chat_receiver: Receiver<ChatMsg>,
+               mod_sender: Sender<ModRequest>,
                to_map_sender: Sender<(AccountBox, StarMapAction)>,
                from_map_receiver: Receiver<AccountBox>,
+               save_sender: Sender<AccountBox>,
                model_store: Arc<ModelStore>) -> StationServer {
+        let events = EventMux::new();
+        slot.forward_incoming(&events, StationEvent::Slot);
+        events.forward(chat_receiver, StationEvent::Chat);
+        events.forward(from_map_receiver, StationEvent::FromMap);
+        events.schedule(SNAPSHOT_INTERVAL_MS, StationEvent::SaveAccounts);
+        
         StationServer {
             slot: slot,
             star_map_slot_id: star_map_slot_id,
             chat_sender: chat_sender,
-            chat_receiver: chat_receiver,
+            mod_sender: mod_sender,
             to_map_sender: to_map_sender,
-            from_map_receiver: from_map_receiver,
+            save_sender: save_sender,
+            events: events,
             model_store: model_store,
             accounts: HashMap::new(),
+            shutting_down: false,
         }
     }
     
     pub fn run(&mut self, ack: Sender<()>) {    
         loop {
-            ///////////////////////////////////////////////////////////
-            // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
-                match msg {
+            match self.events.recv() {
+                ///////////////////////////////////////////////////////////
+                // Receiver ServerSlot messages
+                StationEvent::Slot(msg) => match msg {
                     SlotInMsg::Joined(client_id) => {
                         println!("Client {} joined station {}", client_id, self.slot.get_id());
                     },
@@ -61,31 +92,69 @@ impl StationServer {
                     SlotInMsg::ReceivedPacket(client_id, mut packet) => {
                         self.handle_packet(client_id, &mut packet);
                     },
-                }
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive messages from chat server
-            if let Ok(msg) = self.chat_receiver.try_recv() {
-                let mut msg_packet = OutPacket::new();
-                msg_packet.write(&msg).unwrap();
-                self.slot.broadcast(msg_packet);
-            }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive new clients
-            if let Ok(mut account) = self.from_map_receiver.try_recv() {
-                let client_id = account.client_id.expect("This must have a client ID");
+                    SlotInMsg::Latency(_, _) => { },
+                    SlotInMsg::SlotDestroyed(_) => { },
+                    SlotInMsg::NetStats(_) => { },
+                    SlotInMsg::RateLimited(client_id, penalty) => {
+                        if let Some(account) = self.accounts.get(&client_id) {
+                            println!("Client {} ({}) flooding station {}: {:?}", client_id, account.username, self.slot.get_id(), penalty);
+                        }
+                    },
+                    SlotInMsg::Resumed(client_id) => {
+                        // Station state lives on the client, so there's nothing to catch up on
+                        println!("Client {} reconnected at station {}", client_id, self.slot.get_id());
+                    },
+                    SlotInMsg::ShuttingDown => {
+                        self.shutting_down = true;
+                        
+                        let client_ids: Vec<ClientId> = self.accounts.keys().map(|id| *id).collect();
+                        for client_id in client_ids {
+                            self.log_out(client_id);
+                        }
+                        
+                        self.slot.shutdown_complete();
+                    },
+                },
                 
-                // Send initial join packet
-                let mut packet = OutPacket::new();
-                packet.write(&account.ship).unwrap();
-                self.slot.send(client_id, packet);
+                ///////////////////////////////////////////////////////////
+                // Receive messages from chat server
+                StationEvent::Chat(msg) => {
+                    let mut msg_packet = OutPacket::new();
+                    msg_packet.write(&ClientStationPacket::Chat(msg)).unwrap();
+                    self.slot.broadcast(msg_packet);
+                },
                 
-                // Add the player's account
-                self.accounts.insert(client_id, account);
+                ///////////////////////////////////////////////////////////
+                // Receive new clients
+                StationEvent::FromMap(mut account) => {
+                    let client_id = account.client_id.expect("This must have a client ID");
+                    
+                    if self.shutting_down {
+                        // Sent before the star map heard about the shutdown, send it right back
+                        self.to_map_sender.send((account, StarMapAction::Logout));
+                        ack.send(());
+                        continue;
+                    }
+                    
+                    // Send initial join packet
+                    let mut packet = OutPacket::new();
+                    packet.write(&account.ship).unwrap();
+                    self.slot.send(client_id, packet);
+                    
+                    // Add the player's account
+                    self.accounts.insert(client_id, account);
+                    send_balance(&self.slot, client_id, &self.accounts[&client_id].wallet, ClientStationPacket::Credits);
+                    self.send_hangar(client_id);
+                    
+                    ack.send(());
+                },
                 
-                ack.send(());
+                StationEvent::SaveAccounts => {
+                    for account in self.accounts.values() {
+                        self.save_sender.send(account.snapshot(None));
+                    }
+                    self.events.schedule(SNAPSHOT_INTERVAL_MS, StationEvent::SaveAccounts);
+                },
             }
         }
     }
@@ -124,6 +193,22 @@ impl StationServer {
             },
             StationAction::Chat(msg) => {
                 let ref account = self.accounts[&client_id];
+                
+                // Moderator commands go to the login server instead of chat
+                match moderator_command(account, &msg) {
+                    Some(Ok(request)) => {
+                        println!("Moderator command from {}", request);
+                        self.mod_sender.send(request);
+                        return;
+                    },
+                    Some(Err(reply)) => {
+                        let mut packet = OutPacket::new();
+                        packet.write(&ClientStationPacket::Chat(reply)).unwrap();
+                        self.slot.send(client_id, packet);
+                        return;
+                    },
+                    None => { },
+                }
             
                 let msg = ChatMsg {
                     author_name: account.username.clone(),
@@ -133,12 +218,93 @@ impl StationServer {
                 self.chat_sender.send(msg);
             },
             StationAction::Logout => {
-                let account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
+                self.log_out(client_id);
+            },
+            StationAction::CareerStats => {
+                let mut packet = OutPacket::new();
+                packet.write(&ClientStationPacket::CareerStats(self.accounts[&client_id].career.clone())).unwrap();
+                self.slot.send(client_id, packet);
+            },
+            StationAction::Hangar(hangar_action) => {
+                match self.handle_hangar(client_id, hangar_action) {
+                    Ok(active_changed) => {
+                        if active_changed {
+                            let mut packet = OutPacket::new();
+                            packet.write(&ClientStationPacket::ActiveShip).unwrap();
+                            packet.write(&self.accounts[&client_id].ship).unwrap();
+                            self.slot.send(client_id, packet);
+                        }
+                        self.send_hangar(client_id);
+                    },
+                    Err(e) => {
+                        let mut packet = OutPacket::new();
+                        packet.write(&ClientStationPacket::HangarError(e)).unwrap();
+                        self.slot.send(client_id, packet);
+                    },
+                }
+            },
+        }
+    }
+    
+    // Carries out a hangar action. Returns whether the active ship changed, or why nothing did.
+    fn handle_hangar(&mut self, client_id: ClientId, action: HangarAction) -> Result<bool, String> {
+        let account = &mut **self.accounts.get_mut(&client_id).expect("Client's account must exist here.");
+        
+        match action {
+            HangarAction::List => Ok(false),
+            HangarAction::Switch(id) => {
+                let index = try!(account.hangar.iter().position(|ship| ship.id == id).ok_or(format!("No ship {} in the hangar", id)));
                 
-                self.slot.transfer_client(client_id, self.star_map_slot_id);
+                // The old active ship takes the new one's place in the hangar
+                let ship = account.hangar.remove(index);
+                if let Some(old_ship) = account.ship.take() {
+                    account.hangar.insert(index, old_ship);
+                }
+                account.ship = Some(ship);
+                Ok(true)
+            },
+            HangarAction::Rename(id, name) => {
+                let name = name.trim().to_string();
+                if name.is_empty() || name.chars().count() > MAX_SHIP_NAME_LENGTH {
+                    return Err(format!("Ship names are 1 to {} characters", MAX_SHIP_NAME_LENGTH));
+                }
+                
+                let active = account.ship.as_ref().map(|ship| ship.id == id).unwrap_or(false);
+                match account.ship.iter_mut().chain(account.hangar.iter_mut()).find(|ship| ship.id == id) {
+                    Some(ship) => { ship.name = name; },
+                    None => { return Err(format!("No ship {}", id)); },
+                }
+                Ok(active)
+            },
+            HangarAction::Retire(id) => {
+                if account.ship.as_ref().map(|ship| ship.id == id).unwrap_or(false) {
+                    return Err("Switch to another ship before retiring this one".to_string());
+                }
                 
-                self.to_map_sender.send((account, StarMapAction::Logout));
+                let index = try!(account.hangar.iter().position(|ship| ship.id == id).ok_or(format!("No ship {} in the hangar", id)));
+                account.hangar.remove(index);
+                Ok(false)
             },
         }
     }
+    
+    // Tells a client which ships it owns
+    fn send_hangar(&self, client_id: ClientId) {
+        let ref account = self.accounts[&client_id];
+        
+        let mut ships: Vec<HangarShip> = account.ship.iter().map(|ship| HangarShip::new(ship, true)).collect();
+        ships.extend(account.hangar.iter().map(|ship| HangarShip::new(ship, false)));
+        
+        let mut packet = OutPacket::new();
+        packet.write(&ClientStationPacket::Hangar(ships)).unwrap();
+        self.slot.send(client_id, packet);
+    }
+    
+    fn log_out(&mut self, client_id: ClientId) {
+        let account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
+        
+        self.slot.transfer_client(client_id, self.star_map_slot_id);
+        
+        self.to_map_sender.send((account, StarMapAction::Logout));
+    }
 }